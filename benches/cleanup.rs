@@ -0,0 +1,67 @@
+// Copyright 2015 The Gfx-rs Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Teardown of many resources, deleted in bulk by a single `cleanup`
+//! against one `cleanup` per resource, which is what deleting them one at
+//! a time costs. Needs a headless GL context.
+
+#![feature(test)]
+
+extern crate test;
+extern crate gfx;
+extern crate gfx_device_gl;
+extern crate glutin;
+extern crate libc;
+
+use gfx::device as d;
+use test::Bencher;
+
+const COUNT: usize = 1024;
+
+fn make_factory() -> (glutin::HeadlessContext, gfx_device_gl::Factory) {
+    let context = glutin::HeadlessRendererBuilder::new(64, 64).build().unwrap();
+    unsafe { context.make_current() };
+    let (_, factory) = gfx_device_gl::create(|s|
+        context.get_proc_address(s) as *const libc::c_void).unwrap();
+    (context, factory)
+}
+
+fn make_buffers(factory: &mut gfx_device_gl::Factory)
+                -> Vec<d::handle::RawBuffer<gfx_device_gl::Resources>> {
+    (0 .. COUNT).map(|_|
+        d::Factory::create_buffer_raw(factory, 64, d::BufferUsage::Static)
+    ).collect()
+}
+
+#[bench]
+fn bench_cleanup_bulk(b: &mut Bencher) {
+    let (_context, mut factory) = make_factory();
+    b.iter(|| {
+        let buffers = make_buffers(&mut factory);
+        drop(buffers);
+        d::Factory::cleanup(&mut factory);
+    });
+}
+
+#[bench]
+fn bench_cleanup_one_by_one(b: &mut Bencher) {
+    let (_context, mut factory) = make_factory();
+    b.iter(|| {
+        let buffers = make_buffers(&mut factory);
+        for buffer in buffers.into_iter() {
+            drop(buffer);
+            d::Factory::cleanup(&mut factory);
+        }
+    });
+}
@@ -0,0 +1,185 @@
+// Copyright 2015 The Gfx-rs Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use gfx::device as d;
+use gfx::device::handle;
+use gfx::device::target::Rect;
+use Factory;
+use Resources as R;
+
+
+/// A row of the atlas, filled from left to right.
+struct Shelf {
+    y: u16,
+    height: u16,
+    used: u16,
+}
+
+/// Shelf packing: images go to the shortest shelf they fit in, and a new
+/// shelf is opened below the last one when none does.
+struct ShelfPacker {
+    width: u16,
+    height: u16,
+    shelves: Vec<Shelf>,
+}
+
+impl ShelfPacker {
+    fn new(width: u16, height: u16) -> ShelfPacker {
+        ShelfPacker {
+            width: width,
+            height: height,
+            shelves: Vec::new(),
+        }
+    }
+
+    fn allocate(&mut self, w: u16, h: u16) -> Option<Rect> {
+        if w == 0 || h == 0 || w > self.width {
+            return None
+        }
+        let width = self.width;
+        let best = self.shelves.iter_mut()
+            .filter(|s| s.height >= h && width - s.used >= w)
+            .min_by(|s| s.height);
+        if let Some(shelf) = best {
+            let rect = Rect { x: shelf.used, y: shelf.y, w: w, h: h };
+            shelf.used += w;
+            return Some(rect)
+        }
+        let y = self.shelves.last().map(|s| s.y + s.height).unwrap_or(0);
+        if self.height - y < h {
+            return None
+        }
+        self.shelves.push(Shelf { y: y, height: h, used: w });
+        Some(Rect { x: 0, y: y, w: w, h: h })
+    }
+
+    /// Give back the space of the last rectangle allocated.
+    fn release(&mut self, rect: Rect) {
+        match self.shelves.iter().position(|s| s.y == rect.y) {
+            Some(i) if self.shelves[i].used == rect.x + rect.w => {
+                self.shelves[i].used = rect.x;
+                if i + 1 == self.shelves.len() && rect.x == 0 {
+                    self.shelves.pop();
+                }
+            },
+            _ => error!("Rectangle {:?} is not the last one allocated", rect),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.shelves.clear();
+    }
+}
+
+/// A 2D texture that small images are packed into, as used for glyph
+/// caches and sprite sheets.
+pub struct AtlasTexture {
+    texture: handle::Texture<R>,
+    packer: ShelfPacker,
+}
+
+impl AtlasTexture {
+    /// Create an empty atlas of the given size and format.
+    pub fn new(factory: &mut Factory, width: u16, height: u16,
+               format: d::tex::Format) -> Result<AtlasTexture, d::tex::TextureError> {
+        let info = d::tex::TextureInfo {
+            width: width,
+            height: height,
+            depth: 1,
+            levels: 1,
+            kind: d::tex::TextureKind::Texture2D,
+            format: format,
+        };
+        let texture = try!(d::Factory::create_texture(factory, info));
+        Ok(AtlasTexture {
+            texture: texture,
+            packer: ShelfPacker::new(width, height),
+        })
+    }
+
+    /// Get the underlying texture.
+    pub fn get_texture(&self) -> &handle::Texture<R> {
+        &self.texture
+    }
+
+    /// Pack an image into the atlas and upload its data, returning where it
+    /// landed, or `None` if the atlas is full. If the upload fails, its
+    /// space is given back to the atlas.
+    pub fn insert(&mut self, factory: &mut Factory, width: u16, height: u16,
+                  data: &[u8]) -> Result<Option<Rect>, d::tex::TextureError> {
+        let rect = match self.packer.allocate(width, height) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let img = d::tex::ImageInfo {
+            xoffset: rect.x,
+            yoffset: rect.y,
+            zoffset: 0,
+            width: rect.w,
+            height: rect.h,
+            depth: 1,
+            format: self.texture.get_info().format,
+            mipmap: 0,
+        };
+        match d::Factory::update_texture_raw(factory, &self.texture, &img, data, None) {
+            Ok(()) => Ok(Some(rect)),
+            Err(e) => {
+                error!("Unable to upload a {}x{} image to the atlas: {:?}", width, height, e);
+                self.packer.release(rect);
+                Err(e)
+            },
+        }
+    }
+
+    /// Forget all the packed images, so that their space gets reused. The
+    /// texture contents are left as is.
+    pub fn clear(&mut self) {
+        self.packer.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShelfPacker;
+    use gfx::device::target::Rect;
+
+    #[test]
+    fn test_shelf_packer() {
+        let mut packer = ShelfPacker::new(16, 16);
+        assert_eq!(packer.allocate(8, 4), Some(Rect { x: 0, y: 0, w: 8, h: 4 }));
+        assert_eq!(packer.allocate(8, 8), Some(Rect { x: 0, y: 4, w: 8, h: 8 }));
+        // the shortest shelf that fits is reused
+        assert_eq!(packer.allocate(4, 3), Some(Rect { x: 8, y: 0, w: 4, h: 3 }));
+        assert_eq!(packer.allocate(8, 6), Some(Rect { x: 8, y: 4, w: 8, h: 6 }));
+        assert_eq!(packer.allocate(16, 5), None);
+        assert_eq!(packer.allocate(17, 1), None);
+        assert_eq!(packer.allocate(16, 4), Some(Rect { x: 0, y: 12, w: 16, h: 4 }));
+        packer.clear();
+        assert_eq!(packer.allocate(16, 16), Some(Rect { x: 0, y: 0, w: 16, h: 16 }));
+    }
+
+    #[test]
+    fn test_shelf_release() {
+        let mut packer = ShelfPacker::new(16, 16);
+        let first = packer.allocate(8, 4).unwrap();
+        let second = packer.allocate(4, 4).unwrap();
+        packer.release(second);
+        assert_eq!(packer.allocate(8, 4), Some(Rect { x: 8, y: 0, w: 8, h: 4 }));
+        let third = packer.allocate(8, 8).unwrap();
+        packer.release(third);
+        // the emptied shelf is closed, so a taller image can start there
+        assert_eq!(packer.allocate(16, 12), Some(Rect { x: 0, y: 4, w: 16, h: 12 }));
+        assert_eq!(first, Rect { x: 0, y: 0, w: 8, h: 4 });
+    }
+}
@@ -65,6 +65,58 @@ impl CommandBuffer {
     }
 }
 
+/// A sequence of commands recorded once and replayed any number of times
+/// with `Device::replay`, along with the data they upload.
+/// The list doesn't keep the resources it refers to alive.
+pub struct CommandList {
+    buf: CommandBuffer,
+    data: d::draw::DataBuffer,
+}
+
+impl CommandList {
+    pub fn new() -> CommandList {
+        use gfx::device::draw::CommandBuffer as CommandBufferTrait;
+        CommandList {
+            buf: CommandBufferTrait::new(),
+            data: d::draw::DataBuffer::new(),
+        }
+    }
+
+    /// Get the command buffer to record into.
+    pub fn get_buffer(&mut self) -> &mut CommandBuffer {
+        &mut self.buf
+    }
+
+    /// Store the data of a buffer or texture update, to be referenced by
+    /// the recorded update command.
+    pub fn add_data<T: Copy>(&mut self, data: &[T]) -> d::draw::DataPointer {
+        self.data.add_vec(data)
+    }
+
+    /// Record a single command.
+    pub fn push(&mut self, cmd: Command) {
+        self.buf.buf.push(cmd);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.buf.len()
+    }
+
+    pub fn iter<'a>(&'a self) -> slice::Iter<'a, Command> {
+        self.buf.iter()
+    }
+
+    pub fn get_data(&self) -> &d::draw::DataBuffer {
+        &self.data
+    }
+
+    /// Drop all the recorded commands and data.
+    pub fn clear(&mut self) {
+        self.buf.buf.clear();
+        self.data.clear();
+    }
+}
+
 impl d::draw::CommandBuffer<Resources> for CommandBuffer {
     fn new() -> CommandBuffer {
         CommandBuffer {
@@ -14,10 +14,16 @@
 
 use libc;
 use log::LogLevel;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::mem;
+use std::ptr;
 use std::rc::Rc;
 use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use {gl, tex};
+use {fence, gl, state, tex};
 use gfx;
 use gfx::device as d;
 use gfx::device::handle;
@@ -26,15 +32,39 @@ use gfx::device::mapping::Builder;
 use gfx::tex::Size;
 
 use Buffer;
+use info::{Info, MemoryInfoExtension, PrivateCaps};
+use shade::{BinaryMetadata, BinaryStale, BlockLayout, CreateProgramError, ProgramPipeline,
+            SubroutineInterface, TessStage};
 use Resources as R;
 
 
-pub fn update_sub_buffer(gl: &gl::Gl, buffer: Buffer, address: *const u8,
-                         size: usize, offset: usize, role: d::BufferRole) {
-    let target = match role {
+/// Log a factory operation with its arguments, if tracing is enabled.
+macro_rules! trace_call {
+    ($factory:expr, $($arg:tt)*) => (
+        if $factory.trace {
+            trace!($($arg)*);
+        }
+    )
+}
+
+fn role_to_gl(role: d::BufferRole) -> gl::types::GLenum {
+    match role {
         d::BufferRole::Vertex => gl::ARRAY_BUFFER,
         d::BufferRole::Index  => gl::ELEMENT_ARRAY_BUFFER,
-    };
+    }
+}
+
+fn usage_to_gl(usage: d::BufferUsage) -> gl::types::GLenum {
+    match usage {
+        d::BufferUsage::Static  => gl::STATIC_DRAW,
+        d::BufferUsage::Dynamic => gl::DYNAMIC_DRAW,
+        d::BufferUsage::Stream  => gl::STREAM_DRAW,
+    }
+}
+
+pub fn update_sub_buffer(gl: &gl::Gl, buffer: Buffer, address: *const u8,
+                         size: usize, offset: usize, role: d::BufferRole) {
+    let target = role_to_gl(role);
     unsafe { gl.BindBuffer(target, buffer) };
     unsafe {
         gl.BufferSubData(target,
@@ -43,91 +73,2952 @@ pub fn update_sub_buffer(gl: &gl::Gl, buffer: Buffer, address: *const u8,
             address as *const gl::types::GLvoid
         );
     }
-}
+}
+
+/// An error in a buffer operation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BufferError {
+    /// The buffer has immutable storage that can't be updated
+    NotUpdatable,
+    /// The offset is not a multiple of the required alignment
+    UnalignedOffset(usize),
+    /// The range doesn't fit into the buffer
+    OutOfBounds,
+    /// The operation is not supported by the implementation
+    Unsupported,
+    /// The buffer is mapped already
+    AlreadyMapped,
+}
+
+/// An error in resolving a shader block of a program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockError {
+    /// The block kind is not supported by the implementation
+    Unsupported,
+    /// The program has no active block of that name
+    NotFound(String),
+    /// The program has no active block of that index
+    InvalidIndex(u32),
+}
+
+/// An error in attaching a texture to a frame buffer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AttachmentError {
+    /// Frame buffer objects are not supported
+    Unsupported,
+    /// The texture doesn't have this mip level
+    InvalidLevel(u8),
+    /// The texture format can't be rendered to
+    UnsupportedFormat,
+}
+
+/// An error in setting the number of vertices of a patch.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PatchError {
+    /// Tessellation is not supported by the implementation
+    Unsupported,
+    /// The count is zero or above `max_patch_vertices`
+    InvalidCount(usize),
+}
+
+/// An error in running a compute program.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DispatchError {
+    /// Compute shaders are not supported by the implementation
+    Unsupported,
+}
+
+/// An error in using fences.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FenceError {
+    /// Sync objects are not supported, `ARB_sync` is required
+    Unsupported,
+}
+
+/// How `blit_frame_buffer` samples the source when the rectangles differ
+/// in size.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlitFilter {
+    Nearest,
+    Linear,
+}
+
+/// An error in blitting between frame buffers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlitError {
+    /// Frame buffer objects are not supported
+    Unsupported,
+    /// Linear filtering was requested for depth or stencil
+    InvalidFilter,
+    /// The source frame buffer is incomplete, with the GL status
+    IncompleteSource(gl::types::GLenum),
+    /// The destination frame buffer is incomplete, with the GL status
+    IncompleteDestination(gl::types::GLenum),
+}
+
+/// The kind of shader block a buffer range is bound to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlockTarget {
+    Uniform,
+    ShaderStorage,
+}
+
+/// The kinds of accesses a memory barrier orders with respect to the
+/// incoherent writes of shaders issued before it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MemoryBarrier {
+    /// Vertex fetches from buffers
+    VertexAttribArray,
+    /// Index fetches from buffers
+    ElementArray,
+    /// Uniform block reads
+    Uniform,
+    /// Texture fetches through samplers
+    TextureFetch,
+    /// Image loads and stores
+    ShaderImageAccess,
+    /// Indirect draw and dispatch commands
+    Command,
+    /// Buffer updates, copies and mappings
+    BufferUpdate,
+    /// Texture updates and readbacks
+    TextureUpdate,
+    /// Rendering to frame buffer attachments
+    FrameBuffer,
+    /// Shader storage block reads and writes
+    ShaderStorage,
+    /// Every kind of access
+    All,
+}
+
+impl MemoryBarrier {
+    fn to_gl(&self) -> gl::types::GLbitfield {
+        match *self {
+            MemoryBarrier::VertexAttribArray => gl::VERTEX_ATTRIB_ARRAY_BARRIER_BIT,
+            MemoryBarrier::ElementArray => gl::ELEMENT_ARRAY_BARRIER_BIT,
+            MemoryBarrier::Uniform => gl::UNIFORM_BARRIER_BIT,
+            MemoryBarrier::TextureFetch => gl::TEXTURE_FETCH_BARRIER_BIT,
+            MemoryBarrier::ShaderImageAccess => gl::SHADER_IMAGE_ACCESS_BARRIER_BIT,
+            MemoryBarrier::Command => gl::COMMAND_BARRIER_BIT,
+            MemoryBarrier::BufferUpdate => gl::BUFFER_UPDATE_BARRIER_BIT,
+            MemoryBarrier::TextureUpdate => gl::TEXTURE_UPDATE_BARRIER_BIT,
+            MemoryBarrier::FrameBuffer => gl::FRAMEBUFFER_BARRIER_BIT,
+            MemoryBarrier::ShaderStorage => gl::SHADER_STORAGE_BARRIER_BIT,
+            MemoryBarrier::All => gl::ALL_BARRIER_BITS,
+        }
+    }
+}
+
+/// Video memory reported by the driver, in kilobytes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GpuMemoryInfo {
+    /// Total dedicated video memory, if reported
+    pub total_kb: Option<usize>,
+    /// Video memory currently available
+    pub available_kb: usize,
+}
+
+// not part of the bindings, as vendor extensions
+const GPU_MEMORY_INFO_DEDICATED_VIDMEM_NVX: gl::types::GLenum = 0x9047;
+const GPU_MEMORY_INFO_CURRENT_AVAILABLE_VIDMEM_NVX: gl::types::GLenum = 0x9049;
+const TEXTURE_FREE_MEMORY_ATI: gl::types::GLenum = 0x87FC;
+
+/// Check that a range of a buffer can be bound to a block.
+fn check_buffer_range(offset: usize, size: usize, alignment: usize,
+                      buffer_size: usize) -> Result<(), BufferError> {
+    if alignment != 0 && offset % alignment != 0 {
+        Err(BufferError::UnalignedOffset(alignment))
+    } else if size == 0 || offset + size > buffer_size {
+        Err(BufferError::OutOfBounds)
+    } else {
+        Ok(())
+    }
+}
+
+/// How a range of a buffer gets updated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RangeUpdate {
+    /// `BufferSubData`, or `BufferData` when the buffer is promoted
+    Plain,
+    /// Mapping the range with `MAP_INVALIDATE_RANGE_BIT`
+    Orphan,
+}
+
+/// Choose how to update a range of a buffer. A promoted buffer gets
+/// re-allocated as a whole, which already orphans its old storage.
+fn choose_range_update(orphan_range: bool, map_supported: bool,
+                       promoted: bool) -> RangeUpdate {
+    if orphan_range && map_supported && !promoted {
+        RangeUpdate::Orphan
+    } else {
+        RangeUpdate::Plain
+    }
+}
+
+/// A placeholder for a real `Output` implemented by your window.
+pub struct Output {
+    width: Size,
+    height: Size,
+    handle: handle::FrameBuffer<R>,
+}
+
+impl gfx::Output<R> for Output {
+    fn get_handle(&self) -> Option<&handle::FrameBuffer<R>> {
+        Some(&self.handle)
+    }
+
+    fn get_size(&self) -> (Size, Size) {
+        (self.width, self.height)
+    }
+
+    fn get_mask(&self) -> gfx::Mask {
+        gfx::COLOR | gfx::DEPTH | gfx::STENCIL
+    }
+}
+
+/// Heuristics detecting buffers created with an unfitting usage hint.
+#[derive(Copy, Clone, Debug)]
+pub struct UsageHeuristics {
+    /// Number of updates after which a static buffer is reported, if any
+    pub static_update_limit: Option<usize>,
+    /// Re-allocate reported buffers with a dynamic usage, when the update
+    /// covers the whole buffer so that no content is lost
+    pub promote: bool,
+}
+
+/// One of the two ping-pong sets of dual depth peeling.
+#[derive(Clone, Debug)]
+pub struct DepthPeelSet {
+    /// Frame buffer with `depth`, `front` and `back` attached to the color
+    /// targets 0, 1 and 2
+    pub frame_buffer: handle::FrameBuffer<R>,
+    /// Negated nearest and farthest depths, written with `MAX` blending
+    pub depth: handle::Texture<R>,
+    /// Front-to-back accumulated color
+    pub front: handle::Texture<R>,
+    /// Color of the farthest layer peeled by the pass
+    pub back: handle::Texture<R>,
+}
+
+/// The render targets of dual depth peeling, which peels the nearest and
+/// the farthest layer of the transparent geometry at every pass.
+#[derive(Clone, Debug)]
+pub struct DepthPeelTargets {
+    /// Sets written by alternate passes, each reading the depths of the other
+    pub sets: [DepthPeelSet; 2],
+    /// Frame buffer with `back_blend` attached to the color target 0
+    pub back_blend_buffer: handle::FrameBuffer<R>,
+    /// Back-to-front accumulated color
+    pub back_blend: handle::Texture<R>,
+    /// Number of geometry passes needed to peel the requested layers
+    pub passes: u32,
+}
+
+/// Update statistics of a static buffer.
+#[derive(Copy, Clone)]
+struct UpdateCount {
+    count: usize,
+    promoted: bool,
+}
+
+/// What to do when a factory operation raises a GL error.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorCheck {
+    /// Don't query the errors
+    Off,
+    /// Log them, with the name of the operation
+    Log,
+    /// Panic on the first one
+    Panic,
+}
+
+/// Describe the error raised by an operation, if any.
+fn error_report(operation: &str, code: gl::types::GLenum) -> Option<String> {
+    match ::Error::from_error_code(code) {
+        ::Error::NoError => None,
+        err => Some(format!("Error after {}: {:?}", operation, err)),
+    }
+}
+
+/// Describe an object in log messages: its name, followed by its label if
+/// it has one.
+fn describe(labels: &HashMap<(gl::types::GLenum, gl::types::GLuint), String>,
+            identifier: gl::types::GLenum, name: gl::types::GLuint) -> String {
+    match labels.get(&(identifier, name)) {
+        Some(label) => format!("{} ({})", name, label),
+        None => format!("{}", name),
+    }
+}
+
+/// Forget a reference to a sampler object shared by identical samplers,
+/// returning true if it was the last one and the object has to be deleted.
+fn release_sampler(refs: &mut HashMap<::Sampler, usize>,
+                   cache: &mut Vec<(d::tex::SamplerInfo, ::Sampler)>,
+                   name: ::Sampler) -> bool {
+    let last = match refs.get_mut(&name) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            false
+        },
+        _ => true,
+    };
+    if last {
+        refs.remove(&name);
+        cache.retain(|&(_, s)| s != name);
+    }
+    last
+}
+
+/// Check if objects of the given identifier are containers, which are not
+/// shared between the contexts of a share group.
+fn is_container(identifier: gl::types::GLenum) -> bool {
+    match identifier {
+        gl::FRAMEBUFFER | gl::VERTEX_ARRAY | gl::PROGRAM_PIPELINE => true,
+        _ => false,
+    }
+}
+
+/// The handles of the objects shared between the contexts of a share group,
+/// along with the state the factories track for these objects. Keeping the
+/// state here lets whichever factory deletes an object forget it for all of
+/// them, before GL recycles its name.
+pub struct SharedState {
+    manager: handle::Manager<R>,
+    /// Storage flags of the buffers allocated with `BufferStorage`
+    immutable_buffers: HashMap<Buffer, gl::types::GLbitfield>,
+    static_updates: HashMap<Buffer, UpdateCount>,
+    /// Sizes of the buffers reallocated by `resize_buffer_raw`
+    buffer_sizes: HashMap<Buffer, usize>,
+    /// Buffers currently mapped, persistently or not
+    mapped_buffers: HashSet<Buffer>,
+    /// Which layers of the array textures have received data
+    uploaded_layers: HashMap<::Texture, Vec<bool>>,
+    /// Uniform block layouts introspected at program creation
+    block_layouts: HashMap<::Program, Vec<BlockLayout>>,
+    /// Actual stages of the shaders made by `create_tess_shader`
+    tess_shaders: HashMap<::Shader, TessStage>,
+    /// Sampler objects by the info they were made for, shared by the
+    /// samplers created with identical infos
+    sampler_cache: Vec<(d::tex::SamplerInfo, ::Sampler)>,
+    /// Number of handles sharing each sampler object
+    sampler_refs: HashMap<::Sampler, usize>,
+    /// Labels of the objects by identifier and name, shown in log messages
+    labels: HashMap<(gl::types::GLenum, gl::types::GLuint), String>,
+}
+
+impl SharedState {
+    /// Create an empty state, for the first factory of a share group.
+    pub fn new() -> SharedState {
+        SharedState {
+            manager: handle::Manager::new(),
+            immutable_buffers: HashMap::new(),
+            static_updates: HashMap::new(),
+            buffer_sizes: HashMap::new(),
+            mapped_buffers: HashSet::new(),
+            uploaded_layers: HashMap::new(),
+            block_layouts: HashMap::new(),
+            tess_shaders: HashMap::new(),
+            sampler_cache: Vec::new(),
+            sampler_refs: HashMap::new(),
+            labels: HashMap::new(),
+        }
+    }
+
+    /// Get the manager of the handles.
+    pub fn manager(&mut self) -> &mut handle::Manager<R> {
+        &mut self.manager
+    }
+}
+
+/// The state shared between factories of different contexts.
+pub type SharedHandles = Arc<Mutex<SharedState>>;
+
+/// GL resource factory.
+///
+/// A factory is bound to the context it was created for, and has to be used
+/// on the thread this context is current on. Factories of several contexts
+/// belonging to the same share group can register their resources into one
+/// manager, see `create_shared`. Container objects, frame buffers and array
+/// buffers, are kept in a manager of their own factory instead, and are only
+/// deleted by its `cleanup`.
+pub struct Factory {
+    caps: d::Capabilities,
+    private_caps: PrivateCaps,
+    binary_metadata: BinaryMetadata,
+    gl: Rc<gl::Gl>,
+    main_fbo: handle::FrameBuffer<R>,
+    handles: SharedHandles,
+    /// Handles of the container objects, which belong to this context
+    containers: handle::Manager<R>,
+    frame_handles: handle::Manager<R>,
+    barrier_after_update: bool,
+    auto_mipmap: bool,
+    trace: bool,
+    msaa_fallback: bool,
+    error_check: ErrorCheck,
+    cache_block_layouts: bool,
+    /// Blend toggles of the color attachments, `None` if unknown
+    blend_enabled: Vec<Option<bool>>,
+    /// Depth clamp toggle, `None` if unknown
+    depth_clamp: Option<bool>,
+    /// Pipeline state last applied with `apply_pipeline_state`
+    pipeline_state: Option<state::PipelineState>,
+    /// Texture units left to external code, shared with the device
+    reserved_units: Rc<RefCell<Vec<u32>>>,
+    /// Pixel store preset last applied, if any
+    pixel_store: Option<tex::PixelStorePreset>,
+    /// Set by the device when it changes the state of the context, making
+    /// the cached state above stale
+    state_changed: Rc<Cell<bool>>,
+    default_textures: [Option<handle::Texture<R>>; 3],
+    usage_heuristics: UsageHeuristics,
+    /// Compressed formats supported by the driver
+    compressed_formats: Vec<gl::types::GLenum>,
+    /// Program pipelines dropped since the last cleanup
+    dropped_pipelines: Rc<RefCell<Vec<gl::types::GLuint>>>,
+    /// Label of the next object created, see `set_next_label`
+    next_label: Option<String>,
+    /// Labels of the container objects, the other ones being shared
+    container_labels: HashMap<(gl::types::GLenum, gl::types::GLuint), String>,
+}
+
+/// Context of `cleanup`: names to delete, and the per-resource state that
+/// has to be forgotten along with them. The names of the object types that
+/// can be deleted in bulk are collected, to delete them with a single call
+/// per type.
+struct Cleanup<'a> {
+    gl: &'a gl::Gl,
+    buffers: Vec<Buffer>,
+    array_buffers: Vec<::ArrayBuffer>,
+    frame_buffers: Vec<::FrameBuffer>,
+    surfaces: Vec<::Surface>,
+    textures: Vec<::Texture>,
+    samplers: Vec<::Sampler>,
+    immutable_buffers: &'a mut HashMap<Buffer, gl::types::GLbitfield>,
+    static_updates: &'a mut HashMap<Buffer, UpdateCount>,
+    buffer_sizes: &'a mut HashMap<Buffer, usize>,
+    mapped_buffers: &'a mut HashSet<Buffer>,
+    uploaded_layers: &'a mut HashMap<::Texture, Vec<bool>>,
+    block_layouts: &'a mut HashMap<::Program, Vec<BlockLayout>>,
+    tess_shaders: &'a mut HashMap<::Shader, TessStage>,
+    sampler_cache: &'a mut Vec<(d::tex::SamplerInfo, ::Sampler)>,
+    sampler_refs: &'a mut HashMap<::Sampler, usize>,
+    labels: &'a mut HashMap<(gl::types::GLenum, gl::types::GLuint), String>,
+    container_labels: &'a mut HashMap<(gl::types::GLenum, gl::types::GLuint), String>,
+}
+
+/// Get the error checking of a new factory: debug contexts are meant for
+/// catching errors, so they are reported by default.
+fn default_error_check(private_caps: &PrivateCaps) -> ErrorCheck {
+    if private_caps.debug_context {
+        ErrorCheck::Log
+    } else {
+        ErrorCheck::Off
+    }
+}
+
+/// Create a new `Factory`.
+pub fn create(caps: d::Capabilities, private_caps: PrivateCaps, info: &Info,
+              gl: Rc<gl::Gl>, handles: SharedHandles,
+              reserved_units: Rc<RefCell<Vec<u32>>>,
+              state_changed: Rc<Cell<bool>>) -> Factory {
+    let mut containers = handle::Manager::new();
+    let main_fbo = containers.make_frame_buffer(0);
+    let blend_enabled = (0 .. caps.max_draw_buffers).map(|_| None).collect();
+    let compressed_formats = tex::get_compressed_formats(&gl);
+    let error_check = default_error_check(&private_caps);
+    if error_check == ErrorCheck::Log {
+        info!("\tDebug context, logging the errors of the factory");
+    }
+
+    Factory {
+        caps: caps,
+        private_caps: private_caps,
+        binary_metadata: BinaryMetadata::new(info),
+        gl: gl,
+        main_fbo: main_fbo,
+        handles: handles,
+        containers: containers,
+        frame_handles: handle::Manager::new(),
+        barrier_after_update: false,
+        auto_mipmap: false,
+        trace: false,
+        msaa_fallback: false,
+        error_check: error_check,
+        cache_block_layouts: false,
+        blend_enabled: blend_enabled,
+        depth_clamp: None,
+        pipeline_state: None,
+        reserved_units: reserved_units,
+        pixel_store: None,
+        state_changed: state_changed,
+        default_textures: [None, None, None],
+        usage_heuristics: UsageHeuristics {
+            static_update_limit: Some(16),
+            promote: false,
+        },
+        compressed_formats: compressed_formats,
+        dropped_pipelines: Rc::new(RefCell::new(Vec::new())),
+        next_label: None,
+        container_labels: HashMap::new(),
+    }
+}
+
+impl Factory {
+    /// Lock the state shared with the factories of the other contexts. The
+    /// guard must not be held while calling another method locking it.
+    fn shared(&self) -> MutexGuard<SharedState> {
+        self.handles.lock().unwrap()
+    }
+
+    /// Describe an object in log messages, see `describe`.
+    fn describe_object(&self, identifier: gl::types::GLenum, name: gl::types::GLuint) -> String {
+        if is_container(identifier) {
+            describe(&self.container_labels, identifier, name)
+        } else {
+            describe(&self.shared().labels, identifier, name)
+        }
+    }
+
+    /// Make a new buffer, bound to the given target.
+    fn create_buffer_internal(&mut self, target: gl::types::GLenum,
+                              label: Option<String>) -> Buffer {
+        let mut name = 0 as Buffer;
+        unsafe {
+            self.gl.GenBuffers(1, &mut name);
+            // the name only becomes an object, which can be labeled, once bound
+            self.gl.BindBuffer(target, name);
+        }
+        self.apply_label(gl::BUFFER, name, label);
+        info!("\tCreated buffer {}", self.describe_object(gl::BUFFER, name));
+        name
+    }
+
+    fn init_buffer(&mut self, buffer: Buffer, info: &d::BufferInfo) {
+        let target = role_to_gl(info.role);
+        unsafe { self.gl.BindBuffer(target, buffer) };
+        unsafe {
+            self.gl.BufferData(target,
+                info.size as gl::types::GLsizeiptr,
+                0 as *const gl::types::GLvoid,
+                usage_to_gl(info.usage)
+            );
+        }
+    }
+
+    /// Create an uninitialized buffer for the given role. The role decides
+    /// the binding point used by every later update and mapping, so index
+    /// buffers have to be created through this rather than
+    /// `create_buffer_raw`, which assumes vertex data.
+    pub fn create_buffer_raw_with_role(&mut self, size: usize, usage: d::BufferUsage,
+                                       role: d::BufferRole) -> handle::RawBuffer<R> {
+        trace_call!(self, "create_buffer_raw({}, {:?}, {:?})", size, usage, role);
+        let label = self.next_label.take();
+        let name = self.create_buffer_internal(role_to_gl(role), label);
+        let info = d::BufferInfo {
+            role: role,
+            usage: usage,
+            size: size,
+        };
+        self.init_buffer(name, &info);
+        self.check_errors("create_buffer_raw");
+        self.shared().manager.make_buffer(name, info)
+    }
+
+    /// Map a whole buffer, failing instead of returning a null mapping if
+    /// it is mapped already.
+    pub fn try_map_buffer_raw(&mut self, buf: &handle::RawBuffer<R>, access: d::MapAccess)
+                              -> Result<RawMapping, BufferError> {
+        let name = self.frame_handles.ref_buffer(buf);
+        if self.shared().mapped_buffers.contains(&name) {
+            return Err(BufferError::AlreadyMapped)
+        }
+        Ok(d::Factory::map_buffer_raw(self, buf, access))
+    }
+
+    /// Map a range of a buffer instead of all of it. The mapping points to
+    /// the start of the range. Falls back to mapping the whole buffer
+    /// without `ARB_map_buffer_range`.
+    pub fn map_buffer_range_raw(&mut self, buf: &handle::RawBuffer<R>, offset: usize,
+                                size: usize, access: d::MapAccess)
+                                -> Result<RawMapping, BufferError> {
+        trace_call!(self, "map_buffer_range_raw({:?}, {}, {}, {:?})", buf.get_info(), offset, size, access);
+        let name = self.frame_handles.ref_buffer(buf);
+        if self.shared().mapped_buffers.contains(&name) {
+            return Err(BufferError::AlreadyMapped)
+        }
+        let buffer_size = self.buffer_size(name, buf.get_info());
+        try!(check_buffer_range(offset, size, 1, buffer_size));
+        if !self.private_caps.map_buffer_range_supported {
+            let mut map = d::Factory::map_buffer_raw(self, buf, access);
+            map.pointer = unsafe { (map.pointer as *mut u8).offset(offset as isize) }
+                          as *mut libc::c_void;
+            map.offset = offset;
+            return Ok(map)
+        }
+        let target = role_to_gl(buf.get_info().role);
+        let flags = match access {
+            d::MapAccess::Readable => gl::MAP_READ_BIT,
+            d::MapAccess::Writable => gl::MAP_WRITE_BIT,
+            d::MapAccess::RW => gl::MAP_READ_BIT | gl::MAP_WRITE_BIT,
+        };
+        let ptr = unsafe {
+            self.gl.BindBuffer(target, name);
+            self.gl.MapBufferRange(target, offset as gl::types::GLintptr,
+                                   size as gl::types::GLsizeiptr, flags)
+        } as *mut libc::c_void;
+        self.check_errors("map_buffer_range_raw");
+        self.shared().mapped_buffers.insert(name);
+        Ok(RawMapping {
+            pointer: ptr,
+            buffer: name,
+            target: target,
+            access: access,
+            offset: offset,
+            persistent: false,
+        })
+    }
+
+    /// Create a vertex buffer that stays mapped for its whole lifetime, so it
+    /// can be written to across frames without map/unmap churn. The mapping
+    /// is released along with the buffer, and must not be unmapped. Without
+    /// `coherent`, writes have to be made visible with `flush_mapped_range`.
+    ///
+    /// Without `ARB_buffer_storage`, a dynamic buffer is mapped classically
+    /// instead: `RawMapping::is_persistent` is then `false`, and the buffer
+    /// has to be unmapped before it is used for drawing.
+    pub fn create_persistent_buffer_raw(&mut self, size: usize, access: d::MapAccess,
+                                        coherent: bool)
+                                        -> (handle::RawBuffer<R>, RawMapping) {
+        if !self.private_caps.buffer_storage_supported {
+            warn!("Persistent mapping is not supported, falling back to a classic mapping");
+            let buf = self.create_buffer_raw_with_role(size, d::BufferUsage::Dynamic,
+                                                       d::BufferRole::Vertex);
+            let map = d::Factory::map_buffer_raw(self, &buf, access);
+            return (buf, map)
+        }
+        let mut flags = gl::MAP_PERSISTENT_BIT | match access {
+            d::MapAccess::Readable => gl::MAP_READ_BIT,
+            d::MapAccess::Writable => gl::MAP_WRITE_BIT,
+            d::MapAccess::RW => gl::MAP_READ_BIT | gl::MAP_WRITE_BIT,
+        };
+        if coherent {
+            flags |= gl::MAP_COHERENT_BIT;
+        }
+        let map_flags = match access {
+            d::MapAccess::Readable => flags,
+            _ if coherent => flags,
+            _ => flags | gl::MAP_FLUSH_EXPLICIT_BIT,
+        };
+        let target = gl::ARRAY_BUFFER;
+        let label = self.next_label.take();
+        let name = self.create_buffer_internal(target, label);
+        let ptr = unsafe {
+            self.gl.BindBuffer(target, name);
+            self.gl.BufferStorage(target, size as gl::types::GLsizeiptr,
+                                  0 as *const gl::types::GLvoid, flags);
+            self.gl.MapBufferRange(target, 0, size as gl::types::GLsizeiptr, map_flags)
+        } as *mut libc::c_void;
+        info!("\tMapped buffer {} persistently", self.describe_object(gl::BUFFER, name));
+        self.check_errors("create_persistent_buffer_raw");
+        self.shared().immutable_buffers.insert(name, flags);
+        let info = d::BufferInfo {
+            role: d::BufferRole::Vertex,
+            usage: d::BufferUsage::Dynamic,
+            size: size,
+        };
+        self.shared().mapped_buffers.insert(name);
+        let map = RawMapping {
+            pointer: ptr,
+            buffer: name,
+            target: target,
+            access: access,
+            offset: 0,
+            persistent: true,
+        };
+        (self.shared().manager.make_buffer(name, info), map)
+    }
+
+    /// Make the writes to a range of a persistently mapped buffer visible to
+    /// the GPU. Does nothing for coherent and classic mappings.
+    pub fn flush_mapped_range(&mut self, buf: &handle::RawBuffer<R>, offset: usize,
+                              size: usize) -> Result<(), BufferError> {
+        let name = self.frame_handles.ref_buffer(buf);
+        let buffer_size = self.buffer_size(name, buf.get_info());
+        try!(check_buffer_range(offset, size, 1, buffer_size));
+        match self.shared().immutable_buffers.get(&name) {
+            Some(flags) if flags & gl::MAP_PERSISTENT_BIT != 0 &&
+                           flags & gl::MAP_COHERENT_BIT == 0 &&
+                           flags & gl::MAP_WRITE_BIT != 0 => (),
+            _ => return Ok(()),
+        }
+        let target = role_to_gl(buf.get_info().role);
+        unsafe {
+            self.gl.BindBuffer(target, name);
+            self.gl.FlushMappedBufferRange(target, offset as gl::types::GLintptr,
+                                           size as gl::types::GLsizeiptr);
+        }
+        self.check_errors("flush_mapped_range");
+        Ok(())
+    }
+
+    /// Get the current size of a buffer, which differs from the one it was
+    /// created with after a `resize_buffer_raw`.
+    pub fn get_buffer_size(&mut self, buffer: &handle::RawBuffer<R>) -> usize {
+        let name = self.frame_handles.ref_buffer(buffer);
+        self.buffer_size(name, buffer.get_info())
+    }
+
+    fn buffer_size(&self, name: Buffer, info: &d::BufferInfo) -> usize {
+        self.shared().buffer_sizes.get(&name).cloned().unwrap_or(info.size)
+    }
+
+    /// Get the number of elements of a typed buffer that fit into its
+    /// current storage, which is what a mapping of it can cover. The length
+    /// of the handle is stale after a `resize_buffer_raw`.
+    fn mapped_len<T>(&mut self, buf: &handle::Buffer<R, T>) -> usize {
+        let name = self.frame_handles.ref_buffer(buf.raw());
+        self.buffer_size(name, buf.raw().get_info()) / mem::size_of::<T>()
+    }
+
+    /// Reallocate the storage of a buffer, keeping its handle. With
+    /// `preserve`, the contents that fit into the new size are kept, otherwise
+    /// they are undefined. Buffers with immutable storage can't be resized.
+    ///
+    /// The info of the handle keeps the size the buffer was created with:
+    /// `get_buffer_size` gives the current one, which the mappings and range
+    /// checks of the factory use.
+    pub fn resize_buffer_raw(&mut self, buffer: &handle::RawBuffer<R>,
+                             new_size: usize, preserve: bool) -> Result<(), BufferError> {
+        let name = self.frame_handles.ref_buffer(buffer);
+        if self.shared().immutable_buffers.contains_key(&name) {
+            return Err(BufferError::NotUpdatable)
+        }
+        let info = buffer.get_info();
+        let old_size = self.buffer_size(name, info);
+        let kept = if preserve { ::std::cmp::min(old_size, new_size) } else { 0 };
+        let usage = match self.shared().static_updates.get(&name) {
+            Some(stats) if stats.promoted => gl::DYNAMIC_DRAW,
+            _ => usage_to_gl(info.usage),
+        };
+        let target = role_to_gl(info.role);
+        if kept == 0 {
+            unsafe {
+                self.gl.BindBuffer(target, name);
+                self.gl.BufferData(target, new_size as gl::types::GLsizeiptr,
+                                   0 as *const gl::types::GLvoid, usage);
+            }
+        } else if self.private_caps.copy_buffer_supported {
+            // round-trip the contents through a temporary buffer
+            let temp = self.create_buffer_internal(gl::COPY_WRITE_BUFFER, None);
+            unsafe {
+                self.gl.BindBuffer(gl::COPY_READ_BUFFER, name);
+                self.gl.BindBuffer(gl::COPY_WRITE_BUFFER, temp);
+                self.gl.BufferData(gl::COPY_WRITE_BUFFER, kept as gl::types::GLsizeiptr,
+                                   0 as *const gl::types::GLvoid, gl::STREAM_COPY);
+                self.gl.CopyBufferSubData(gl::COPY_READ_BUFFER, gl::COPY_WRITE_BUFFER,
+                                          0, 0, kept as gl::types::GLsizeiptr);
+                self.gl.BufferData(gl::COPY_READ_BUFFER, new_size as gl::types::GLsizeiptr,
+                                   0 as *const gl::types::GLvoid, usage);
+                self.gl.CopyBufferSubData(gl::COPY_WRITE_BUFFER, gl::COPY_READ_BUFFER,
+                                          0, 0, kept as gl::types::GLsizeiptr);
+                self.gl.DeleteBuffers(1, &temp);
+            }
+        } else {
+            let mut data: Vec<u8> = (0 .. kept).map(|_| 0).collect();
+            unsafe {
+                self.gl.BindBuffer(target, name);
+                self.gl.GetBufferSubData(target, 0, kept as gl::types::GLsizeiptr,
+                                         data.as_mut_ptr() as *mut gl::types::GLvoid);
+                self.gl.BufferData(target, new_size as gl::types::GLsizeiptr,
+                                   0 as *const gl::types::GLvoid, usage);
+                self.gl.BufferSubData(target, 0, kept as gl::types::GLsizeiptr,
+                                      data.as_ptr() as *const gl::types::GLvoid);
+            }
+        }
+        self.check_errors("resize_buffer_raw");
+        info!("\tResized buffer {} from {} to {} bytes",
+              self.describe_object(gl::BUFFER, name), old_size, new_size);
+        self.shared().buffer_sizes.insert(name, new_size);
+        Ok(())
+    }
+
+    /// Check that `BufferSubData` can be issued on a buffer: either it has
+    /// mutable storage, or it was created with the dynamic storage flag.
+    fn check_buffer_updatable(&self, buffer: Buffer) -> Result<(), BufferError> {
+        match self.shared().immutable_buffers.get(&buffer) {
+            Some(flags) if flags & gl::DYNAMIC_STORAGE_BIT == 0 =>
+                Err(BufferError::NotUpdatable),
+            _ => Ok(()),
+        }
+    }
+
+    /// Update a buffer, failing if its storage is not updatable.
+    pub fn try_update_buffer_raw(&mut self, buffer: &handle::RawBuffer<R>,
+                                 data: &[u8], offset_bytes: usize)
+                                 -> Result<(), BufferError> {
+        trace_call!(self, "update_buffer_raw({:?}, {} bytes at {})", buffer.get_info(), data.len(), offset_bytes);
+        let raw_handle = self.frame_handles.ref_buffer(buffer);
+        let size = self.buffer_size(raw_handle, buffer.get_info());
+        debug_assert!(offset_bytes + data.len() <= size);
+        try!(self.check_buffer_updatable(raw_handle));
+        let whole = offset_bytes == 0 && data.len() == size;
+        let promoted = self.count_static_update(raw_handle, buffer.get_info(), whole);
+        self.write_buffer(raw_handle, buffer.get_info().role, data, offset_bytes, promoted);
+        self.check_errors("update_buffer_raw");
+        Ok(())
+    }
+
+    /// Write data into a buffer, re-allocating it with a dynamic usage if it
+    /// has just been promoted.
+    fn write_buffer(&mut self, name: Buffer, role: d::BufferRole, data: &[u8],
+                    offset: usize, promoted: bool) {
+        if promoted {
+            let target = role_to_gl(role);
+            unsafe {
+                self.gl.BindBuffer(target, name);
+                self.gl.BufferData(target,
+                    data.len() as gl::types::GLsizeiptr,
+                    data.as_ptr() as *const gl::types::GLvoid,
+                    gl::DYNAMIC_DRAW
+                );
+            }
+        } else {
+            update_sub_buffer(&self.gl, name, data.as_ptr(), data.len(), offset, role);
+        }
+    }
+
+    /// Update a range of a buffer. With `orphan_range` the old contents of
+    /// just that range are invalidated, so that the driver doesn't have to
+    /// wait for the draws still reading them, while the rest of the buffer
+    /// is kept. Falls back to a plain update if `MapBufferRange` is not
+    /// supported or fails. Updates are counted against the usage heuristics
+    /// like those of `try_update_buffer_raw`, and an empty update does
+    /// nothing.
+    pub fn update_buffer_range_raw(&mut self, buffer: &handle::RawBuffer<R>,
+                                   data: &[u8], offset: usize, orphan_range: bool)
+                                   -> Result<(), BufferError> {
+        trace_call!(self, "update_buffer_range_raw({:?}, {} bytes at {}, {})", buffer.get_info(), data.len(), offset, orphan_range);
+        if data.is_empty() {
+            return Ok(())
+        }
+        let name = self.frame_handles.ref_buffer(buffer);
+        if self.shared().mapped_buffers.contains(&name) {
+            return Err(BufferError::AlreadyMapped)
+        }
+        let buffer_size = self.buffer_size(name, buffer.get_info());
+        try!(check_buffer_range(offset, data.len(), 1, buffer_size));
+        try!(self.check_buffer_updatable(name));
+        let role = buffer.get_info().role;
+        let whole = offset == 0 && data.len() == buffer_size;
+        let promoted = self.count_static_update(name, buffer.get_info(), whole);
+        let method = choose_range_update(orphan_range,
+                                         self.private_caps.map_buffer_range_supported,
+                                         promoted);
+        if method == RangeUpdate::Plain {
+            self.write_buffer(name, role, data, offset, promoted);
+            self.check_errors("update_buffer_range_raw");
+            return Ok(())
+        }
+        let target = role_to_gl(role);
+        let mapped = unsafe {
+            self.gl.BindBuffer(target, name);
+            let ptr = self.gl.MapBufferRange(target, offset as gl::types::GLintptr,
+                                             data.len() as gl::types::GLsizeiptr,
+                                             gl::MAP_WRITE_BIT | gl::MAP_INVALIDATE_RANGE_BIT);
+            if !ptr.is_null() {
+                ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+                self.gl.UnmapBuffer(target);
+            }
+            !ptr.is_null()
+        };
+        self.check_errors("update_buffer_range_raw");
+        if !mapped {
+            warn!("Unable to map {} bytes of buffer {}, updating it without orphaning",
+                  data.len(), self.describe_object(gl::BUFFER, name));
+            self.write_buffer(name, role, data, offset, false);
+            self.check_errors("update_buffer_range_raw");
+        }
+        Ok(())
+    }
+
+    /// Count an update of a static buffer against the usage heuristics.
+    /// Returns `true` if the buffer has to be promoted to a dynamic usage.
+    fn count_static_update(&mut self, buffer: Buffer, info: &d::BufferInfo,
+                           whole: bool) -> bool {
+        let limit = match (info.usage, self.usage_heuristics.static_update_limit) {
+            (d::BufferUsage::Static, Some(limit)) => limit,
+            _ => return false,
+        };
+        let promote = self.usage_heuristics.promote;
+        let (count, promoted) = {
+            let mut shared = self.shared();
+            let stats = shared.static_updates.entry(buffer).or_insert(UpdateCount {
+                count: 0,
+                promoted: false,
+            });
+            stats.count += 1;
+            let promoted = stats.count > limit && !stats.promoted && whole && promote;
+            if promoted {
+                stats.promoted = true;
+            }
+            (stats.count, promoted)
+        };
+        if count == limit + 1 {
+            warn!("Static buffer {} of {} bytes has been updated {} times, consider using a dynamic usage",
+                  self.describe_object(gl::BUFFER, buffer), info.size, count);
+        }
+        if promoted {
+            info!("\tPromoting buffer {} to a dynamic usage", buffer);
+        }
+        promoted
+    }
+
+    /// Configure the detection of static buffers that are frequently updated.
+    pub fn set_usage_heuristics(&mut self, heuristics: UsageHeuristics) {
+        self.usage_heuristics = heuristics;
+    }
+
+    /// Bind a range of a buffer to the given uniform or shader storage
+    /// block binding point.
+    pub fn bind_buffer_range(&mut self, target: BlockTarget,
+                             buffer: &handle::RawBuffer<R>, binding: u32,
+                             offset: usize, size: usize) -> Result<(), BufferError> {
+        let (gl_target, alignment) = match target {
+            BlockTarget::Uniform if self.caps.uniform_block_supported =>
+                (gl::UNIFORM_BUFFER, self.private_caps.uniform_buffer_alignment),
+            BlockTarget::ShaderStorage if self.private_caps.shader_storage_supported =>
+                (gl::SHADER_STORAGE_BUFFER, self.private_caps.storage_buffer_alignment),
+            _ => return Err(BufferError::Unsupported),
+        };
+        let name = self.frame_handles.ref_buffer(buffer);
+        let buffer_size = self.buffer_size(name, buffer.get_info());
+        try!(check_buffer_range(offset, size, alignment, buffer_size));
+        unsafe {
+            self.gl.BindBufferRange(gl_target, binding as gl::types::GLuint, name,
+                offset as gl::types::GLintptr, size as gl::types::GLsizeiptr);
+        }
+        self.check_errors("bind_buffer_range");
+        Ok(())
+    }
+
+    /// Bind a whole buffer to a shader storage block binding point, for
+    /// shaders to read and write it. Buffers of any role can be bound.
+    pub fn bind_shader_storage_buffer(&mut self, buffer: &handle::RawBuffer<R>,
+                                      binding: u32) -> Result<(), BufferError> {
+        if !self.private_caps.shader_storage_supported {
+            return Err(BufferError::Unsupported)
+        }
+        let name = self.frame_handles.ref_buffer(buffer);
+        unsafe {
+            self.gl.BindBufferBase(gl::SHADER_STORAGE_BUFFER, binding as gl::types::GLuint, name);
+        }
+        self.check_errors("bind_shader_storage_buffer");
+        Ok(())
+    }
+
+    /// Route a fragment shader output to another color attachment by
+    /// relinking the program in place, without recompiling its shaders.
+    /// Fails if the relink moved any attribute or uniform, as the locations
+    /// recorded in the program handle would then be stale, in which case
+    /// the output is moved back to its old location.
+    ///
+    /// Relinking resets the uniforms outside of blocks to their initial
+    /// values, while the uniform block bindings are kept. The cached block
+    /// layouts of the program are refreshed.
+    pub fn remap_frag_data(&mut self, program: &handle::Program<R>, name: &str,
+                           location: u32) -> Result<(), ::shade::RelinkError> {
+        if location as usize >= self.caps.max_draw_buffers {
+            return Err(::shade::RelinkError::InvalidLocation(location))
+        }
+        let prog = self.frame_handles.ref_program(program);
+        let result = ::shade::remap_frag_data(&self.gl, &self.caps, prog, program.get_info(),
+                                              name, location);
+        let cached = self.shared().block_layouts.contains_key(&prog);
+        if cached {
+            let layouts = ::shade::query_block_layouts(&self.gl, &self.caps, prog);
+            self.shared().block_layouts.insert(prog, layouts);
+        }
+        self.check_errors("remap_frag_data");
+        result
+    }
+
+    /// Get the name, array size and GL type of the varyings captured by
+    /// transform feedback, to check them against the capture buffer layout.
+    pub fn get_transform_feedback_varyings(&mut self, program: &handle::Program<R>)
+                                           -> Vec<(String, u32, gl::types::GLenum)> {
+        let prog = self.frame_handles.ref_program(program);
+        let varyings = ::shade::query_transform_feedback_varyings(&self.gl, prog);
+        self.check_errors("get_transform_feedback_varyings");
+        varyings
+    }
+
+    /// Get the location of a named uniform, vertex input or fragment output
+    /// of a program, if it's active.
+    pub fn get_resource_location(&mut self, program: &handle::Program<R>,
+                                 interface: ::shade::ProgramInterface, name: &str)
+                                 -> Option<i32> {
+        let prog = self.frame_handles.ref_program(program);
+        let location = ::shade::get_resource_location(&self.gl, prog, interface, name,
+                                       self.private_caps.program_interface_supported);
+        self.check_errors("get_resource_location");
+        location
+    }
+
+    /// Warn when the loose uniforms of a program come close to the limits,
+    /// which usually means the next addition will fail to link with an
+    /// obscure driver message.
+    fn check_uniform_limits(&self, program: ::Program, info: &d::shade::ProgramInfo) {
+        let (locations, components) = ::shade::count_uniform_usage(info);
+        let caps = &self.private_caps;
+        let near = |used: usize, max: usize| max != 0 && used * 10 >= max * 9;
+        if near(locations, caps.max_uniform_locations) {
+            warn!("Program {} uses {} of the {} uniform locations",
+                  self.describe_object(gl::PROGRAM, program), locations,
+                  caps.max_uniform_locations);
+        }
+        let max_components = ::std::cmp::min(caps.max_vertex_uniform_components,
+                                             caps.max_fragment_uniform_components);
+        if near(components, max_components) {
+            warn!("Program {} uses up to {} uniform components, the vertex limit is {} and the fragment one {}",
+                  self.describe_object(gl::PROGRAM, program), components,
+                  caps.max_vertex_uniform_components,
+                  caps.max_fragment_uniform_components);
+        }
+    }
+
+    /// Create a program like `create_program`, but report why it failed,
+    /// with the link log when linking did.
+    pub fn try_create_program(&mut self, shaders: &[handle::Shader<R>],
+                              targets: Option<&[&str]>)
+                              -> Result<handle::Program<R>, CreateProgramError> {
+        trace_call!(self, "create_program({} shaders, {:?})", shaders.len(), targets);
+        let label = self.next_label.take();
+        let objects: Vec<::Shader> = shaders.iter()
+            .map(|h| self.frame_handles.ref_shader(h))
+            .collect();
+        let mut stages = Vec::new();
+        let mut tess_stages = Vec::new();
+        for (h, name) in shaders.iter().zip(objects.iter()) {
+            match self.shared().tess_shaders.get(name) {
+                Some(&stage) => tess_stages.push(stage),
+                None => stages.push(*h.get_info()),
+            }
+        }
+        try!(::shade::check_stages(&stages));
+        try!(::shade::check_tess_stages(&tess_stages));
+        let (prog, log) = ::shade::create_program(&self.gl, &self.caps,
+                                                  objects.into_iter(), targets,
+                                                  self.private_caps.program_binary_supported);
+        if let Some(ref log) = log {
+            let level = if prog.is_err() { LogLevel::Error } else { LogLevel::Warn };
+            log!(level, "\tProgram link log: {}", log);
+        }
+        self.check_errors("create_program");
+        match prog {
+            Ok((name, info)) => {
+                self.apply_label(gl::PROGRAM, name, label);
+                self.check_uniform_limits(name, &info);
+                if self.cache_block_layouts && !info.blocks.is_empty() {
+                    let layouts = ::shade::query_block_layouts(&self.gl, &self.caps, name);
+                    self.shared().block_layouts.insert(name, layouts);
+                }
+                Ok(self.shared().manager.make_program(name, info))
+            },
+            Err(()) => Err(CreateProgramError::LinkFailed(log.unwrap_or(String::new()))),
+        }
+    }
+
+    /// Create a tessellation shader, to be linked by `create_program` with
+    /// the other stages. Fails with `ModelNotSupported` without
+    /// `tessellation_supported`.
+    ///
+    /// The handle reports the `Vertex` stage, as `Stage` has no tessellation
+    /// ones: the actual stage is given by `get_tess_stage`, which works with
+    /// every factory sharing the handles of this one.
+    pub fn create_tess_shader(&mut self, stage: TessStage, code: &[u8])
+                              -> Result<handle::Shader<R>, d::shade::CreateShaderError> {
+        trace_call!(self, "create_tess_shader({:?}, {} bytes)", stage, code.len());
+        let label = self.next_label.take();
+        if !self.private_caps.tessellation_supported {
+            error!("Tessellation shaders are not supported, ARB_tessellation_shader is required");
+            return Err(d::shade::CreateShaderError::ModelNotSupported)
+        }
+        let (name, info) = ::shade::create_tess_shader(&self.gl, stage, code);
+        info.map(|info| {
+            let level = if name.is_err() { LogLevel::Error } else { LogLevel::Warn };
+            log!(level, "\tShader compile log: {}", info);
+        });
+        self.check_errors("create_tess_shader");
+        name.map(|sh| {
+            self.apply_label(gl::SHADER, sh, label);
+            self.shared().tess_shaders.insert(sh, stage);
+            self.shared().manager.make_shader(sh, d::shade::Stage::Vertex)
+        })
+    }
+
+    /// Get the stage of a shader made by `create_tess_shader`, or `None`
+    /// for the shaders of the stages of its info.
+    pub fn get_tess_stage(&mut self, shader: &handle::Shader<R>) -> Option<TessStage> {
+        let name = self.frame_handles.ref_shader(shader);
+        self.shared().tess_shaders.get(&name).cloned()
+    }
+
+    /// Create a program made of a single compute shader, to be run with
+    /// `dispatch_compute`. Fails with `Unsupported` without
+    /// `compute_supported`.
+    pub fn create_compute_program(&mut self, code: &[u8])
+                                  -> Result<handle::Program<R>, CreateProgramError> {
+        trace_call!(self, "create_compute_program({} bytes)", code.len());
+        let label = self.next_label.take();
+        if !self.private_caps.compute_supported {
+            error!("Compute shaders are not supported, ARB_compute_shader is required");
+            return Err(CreateProgramError::Unsupported)
+        }
+        let (shader, log) = ::shade::create_compute_shader(&self.gl, code);
+        let shader = match shader {
+            Ok(sh) => sh,
+            Err(_) => {
+                let log = log.unwrap_or(String::new());
+                error!("\tShader compile log: {}", log);
+                return Err(CreateProgramError::CompileFailed(log))
+            },
+        };
+        let (prog, log) = ::shade::create_program(&self.gl, &self.caps,
+                                                  Some(shader).into_iter(), None,
+                                                  self.private_caps.program_binary_supported);
+        // the shader is only deleted once the program is
+        unsafe { self.gl.DeleteShader(shader) };
+        if let Some(ref log) = log {
+            let level = if prog.is_err() { LogLevel::Error } else { LogLevel::Warn };
+            log!(level, "\tProgram link log: {}", log);
+        }
+        self.check_errors("create_compute_program");
+        match prog {
+            Ok((name, info)) => {
+                self.apply_label(gl::PROGRAM, name, label);
+                Ok(self.shared().manager.make_program(name, info))
+            },
+            Err(()) => Err(CreateProgramError::LinkFailed(log.unwrap_or(String::new()))),
+        }
+    }
+
+    /// Create a program of a single stage, that can be combined with the
+    /// programs of other stages in a `ProgramPipeline` without relinking.
+    /// Fails with `Unsupported` without `separate_shaders_supported`.
+    pub fn create_separable_program(&mut self, stage: d::shade::Stage, code: &[u8])
+                                    -> Result<handle::Program<R>, CreateProgramError> {
+        trace_call!(self, "create_separable_program({:?}, {} bytes)", stage, code.len());
+        let label = self.next_label.take();
+        if !self.private_caps.separate_shaders_supported {
+            error!("Separable programs are not supported, ARB_separate_shader_objects is required");
+            return Err(CreateProgramError::Unsupported)
+        }
+        if stage == d::shade::Stage::Geometry && !self.private_caps.geometry_shader_supported {
+            error!("Geometry shaders are not supported");
+            return Err(CreateProgramError::IncompatibleStages)
+        }
+        let (shader, log) = ::shade::create_shader(&self.gl, stage, code);
+        let shader = match shader {
+            Ok(sh) => sh,
+            Err(_) => {
+                let log = log.unwrap_or(String::new());
+                error!("\tShader compile log: {}", log);
+                return Err(CreateProgramError::CompileFailed(log))
+            },
+        };
+        let retrievable = self.private_caps.program_binary_supported;
+        let (prog, log) = ::shade::create_separable_program(&self.gl, &self.caps, shader,
+                                                            retrievable);
+        unsafe { self.gl.DeleteShader(shader) };
+        if let Some(ref log) = log {
+            let level = if prog.is_err() { LogLevel::Error } else { LogLevel::Warn };
+            log!(level, "\tProgram link log: {}", log);
+        }
+        self.check_errors("create_separable_program");
+        match prog {
+            Ok((name, info)) => {
+                self.apply_label(gl::PROGRAM, name, label);
+                Ok(self.shared().manager.make_program(name, info))
+            },
+            Err(()) => Err(CreateProgramError::LinkFailed(log.unwrap_or(String::new()))),
+        }
+    }
+
+    /// Create an empty program pipeline, to be given separable programs with
+    /// `use_program_stages`. Fails with `Unsupported` without
+    /// `separate_shaders_supported`.
+    ///
+    /// Pipelines are container objects: they belong to the context of this
+    /// factory, and are deleted by its `cleanup` once dropped.
+    pub fn create_pipeline(&mut self) -> Result<ProgramPipeline, CreateProgramError> {
+        trace_call!(self, "create_pipeline()");
+        if !self.private_caps.separate_shaders_supported {
+            error!("Program pipelines are not supported, ARB_separate_shader_objects is required");
+            return Err(CreateProgramError::Unsupported)
+        }
+        let label = self.next_label.take();
+        let mut name = 0;
+        unsafe { self.gl.GenProgramPipelines(1, &mut name) };
+        self.apply_label(gl::PROGRAM_PIPELINE, name, label);
+        info!("\tCreated program pipeline {}", self.describe_object(gl::PROGRAM_PIPELINE, name));
+        self.check_errors("create_pipeline");
+        Ok(ProgramPipeline::new(name, self.dropped_pipelines.clone()))
+    }
+
+    /// Run the given stages of a pipeline with a separable program made by
+    /// `create_separable_program`. The program handle has to be kept alive
+    /// as long as the pipeline uses it.
+    pub fn use_program_stages(&mut self, pipeline: &ProgramPipeline,
+                              stages: &[d::shade::Stage], program: &handle::Program<R>) {
+        trace_call!(self, "use_program_stages({}, {:?})", pipeline.get_name(), stages);
+        let prog = self.frame_handles.ref_program(program);
+        unsafe {
+            self.gl.UseProgramStages(pipeline.get_name(), ::shade::stages_to_gl(stages), prog);
+        }
+        self.check_errors("use_program_stages");
+    }
+
+    /// Bind a pipeline for the following draw calls. Pipelines are only used
+    /// when no program is bound, so this unbinds the current program, and
+    /// binding a program afterwards overrides the pipeline.
+    ///
+    /// This only affects the context of the factory, for the draw calls
+    /// issued directly through GL, such as within `Device::with_gl` when the
+    /// factory shares the context of the device. Command buffers always bind
+    /// a program, so the draw calls they submit never use the pipeline.
+    pub fn bind_program_pipeline(&mut self, pipeline: &ProgramPipeline) {
+        trace_call!(self, "bind_program_pipeline({})", pipeline.get_name());
+        unsafe {
+            self.gl.UseProgram(0);
+            self.gl.BindProgramPipeline(pipeline.get_name());
+        }
+        self.check_errors("bind_program_pipeline");
+    }
+
+    /// Run a compute program over a grid of work groups. The program stays
+    /// bound afterwards. The writes of the shader are only visible to the
+    /// following commands after a `memory_barrier` covering them.
+    pub fn dispatch_compute(&mut self, program: &handle::Program<R>,
+                            x: u32, y: u32, z: u32) -> Result<(), DispatchError> {
+        trace_call!(self, "dispatch_compute({}, {}, {})", x, y, z);
+        if !self.private_caps.compute_supported {
+            return Err(DispatchError::Unsupported)
+        }
+        let name = self.frame_handles.ref_program(program);
+        unsafe {
+            self.gl.UseProgram(name);
+            self.gl.DispatchCompute(x, y, z);
+        }
+        self.check_errors("dispatch_compute");
+        Ok(())
+    }
+
+    /// Order the given kinds of accesses after the incoherent writes issued
+    /// so far by shaders, such as the ones of compute shaders to storage
+    /// buffers and images.
+    pub fn memory_barrier(&mut self, barriers: &[MemoryBarrier]) {
+        trace_call!(self, "memory_barrier({:?})", barriers);
+        if !self.private_caps.memory_barrier_supported {
+            warn!("Memory barriers are not supported, ignored");
+            return
+        }
+        let bits = barriers.iter().fold(0, |bits, b| bits | b.to_gl());
+        if bits != 0 {
+            unsafe { self.gl.MemoryBarrier(bits) };
+        }
+        self.check_errors("memory_barrier");
+    }
+
+    /// Set the number of vertices making a patch, when drawing for a
+    /// program with tessellation. The default is 3.
+    pub fn set_patch_vertices(&mut self, count: usize) -> Result<(), PatchError> {
+        trace_call!(self, "set_patch_vertices({})", count);
+        if !self.private_caps.tessellation_supported {
+            return Err(PatchError::Unsupported)
+        }
+        if count == 0 || count > self.private_caps.max_patch_vertices {
+            return Err(PatchError::InvalidCount(count))
+        }
+        unsafe {
+            self.gl.PatchParameteri(gl::PATCH_VERTICES, count as gl::types::GLint);
+        }
+        self.check_errors("set_patch_vertices");
+        Ok(())
+    }
+
+    /// Make `create_program` introspect the member layout of the uniform
+    /// blocks of each new program, so it can be looked up later with
+    /// `get_uniform_block_layout` without querying GL. Off by default.
+    pub fn set_cache_block_layouts(&mut self, enabled: bool) {
+        self.cache_block_layouts = enabled;
+    }
+
+    /// Get the cached layout of a uniform block. Returns `None` if the
+    /// program has no such block, or was created while caching was off.
+    pub fn get_uniform_block_layout(&mut self, program: &handle::Program<R>, name: &str)
+                                    -> Option<BlockLayout> {
+        let prog = self.frame_handles.ref_program(program);
+        self.shared().block_layouts.get(&prog)
+            .and_then(|layouts| layouts.iter().find(|l| l.name == name).cloned())
+    }
+
+    /// Get the layouts of all the uniform blocks of a program, in the order
+    /// of their indices. Uses the cached layouts if `set_cache_block_layouts`
+    /// was on when the program was created, otherwise queries GL. Empty if
+    /// the program has no blocks or uniform blocks are not supported.
+    pub fn get_uniform_blocks(&mut self, program: &handle::Program<R>) -> Vec<BlockLayout> {
+        if !self.caps.uniform_block_supported || program.get_info().blocks.is_empty() {
+            return Vec::new()
+        }
+        let prog = self.frame_handles.ref_program(program);
+        let cached = self.shared().block_layouts.get(&prog).cloned();
+        match cached {
+            Some(layouts) => layouts,
+            None => {
+                let layouts = ::shade::query_block_layouts(&self.gl, &self.caps, prog);
+                self.check_errors("get_uniform_blocks");
+                layouts
+            },
+        }
+    }
+
+    /// Assign a binding point to a uniform block of a program, by its index
+    /// as reported by `get_uniform_blocks`. A buffer is then bound to the
+    /// binding point with `bind_buffer_range`.
+    pub fn bind_uniform_block(&mut self, program: &handle::Program<R>,
+                              block_index: u32, binding: u32)
+                              -> Result<(), BlockError> {
+        if !self.caps.uniform_block_supported {
+            return Err(BlockError::Unsupported)
+        }
+        if block_index as usize >= program.get_info().blocks.len() {
+            return Err(BlockError::InvalidIndex(block_index))
+        }
+        let name = self.frame_handles.ref_program(program);
+        unsafe { self.gl.UniformBlockBinding(name, block_index, binding) };
+        self.check_errors("bind_uniform_block");
+        Ok(())
+    }
+
+    /// Get the subroutines of a stage of a program, and the uniforms
+    /// selecting among them. Empty without `ARB_shader_subroutine`.
+    pub fn get_subroutines(&mut self, program: &handle::Program<R>, stage: d::shade::Stage)
+                           -> SubroutineInterface {
+        if !self.private_caps.subroutine_supported {
+            return SubroutineInterface {
+                subroutines: Vec::new(),
+                uniforms: Vec::new(),
+                location_count: 0,
+            }
+        }
+        let name = self.frame_handles.ref_program(program);
+        let interface = ::shade::query_subroutines(&self.gl, name, stage);
+        self.check_errors("get_subroutines");
+        interface
+    }
+
+    /// Select the subroutine of every subroutine uniform location of a
+    /// stage of the current program, giving the index of a subroutine for
+    /// each location. The selection is not part of the program state: it
+    /// is lost by every `UseProgram`, including the ones of submitted
+    /// command buffers, so it has to be set again after binding the program.
+    pub fn set_subroutines(&mut self, stage: d::shade::Stage, indices: &[u32]) {
+        trace_call!(self, "set_subroutines({:?}, {:?})", stage, indices);
+        if !self.private_caps.subroutine_supported {
+            error!("Shader subroutines are not supported, ignored");
+            return
+        }
+        unsafe {
+            self.gl.UniformSubroutinesuiv(::shade::stage_to_gl(stage),
+                                          indices.len() as gl::types::GLsizei,
+                                          indices.as_ptr());
+        }
+        self.check_errors("set_subroutines");
+    }
+
+    /// Assign a binding point to the shader storage block of the given name,
+    /// for blocks that don't specify it in the shader source.
+    pub fn bind_storage_block(&mut self, program: &handle::Program<R>,
+                              block_name: &str, binding: u32)
+                              -> Result<(), BlockError> {
+        if !self.private_caps.shader_storage_supported {
+            return Err(BlockError::Unsupported)
+        }
+        let name = self.frame_handles.ref_program(program);
+        let c_name = match CString::new(block_name) {
+            Ok(c) => c,
+            Err(_) => return Err(BlockError::NotFound(block_name.to_string())),
+        };
+        let index = unsafe {
+            self.gl.GetProgramResourceIndex(name, gl::SHADER_STORAGE_BLOCK,
+                c_name.as_bytes_with_nul().as_ptr() as *const gl::types::GLchar)
+        };
+        if index == gl::INVALID_INDEX {
+            return Err(BlockError::NotFound(block_name.to_string()))
+        }
+        unsafe { self.gl.ShaderStorageBlockBinding(name, index, binding) };
+        self.check_errors("bind_storage_block");
+        Ok(())
+    }
+
+    /// Get the handles and object state of this factory, to be shared with
+    /// a factory of another context.
+    pub fn share_handles(&self) -> SharedHandles {
+        self.handles.clone()
+    }
+
+    /// Bind the frame buffer of an output for reading from one of its
+    /// color attachments.
+    fn bind_read_target<O: gfx::Output<R>>(&mut self, output: &O, attachment: u32) {
+        let name = match output.get_handle() {
+            Some(handle) => self.frame_handles.ref_frame_buffer(handle),
+            None => 0,
+        };
+        self.bind_read_frame_buffer(name, attachment);
+    }
+
+    fn bind_read_frame_buffer(&self, name: ::FrameBuffer, attachment: u32) {
+        unsafe {
+            self.gl.BindFramebuffer(gl::READ_FRAMEBUFFER, name);
+            if name == 0 {
+                self.gl.ReadBuffer(gl::BACK);
+            } else {
+                self.gl.ReadBuffer(gl::COLOR_ATTACHMENT0 + attachment);
+            }
+        }
+    }
+
+    /// Copy a rectangle of a frame buffer to another, scaling it if the
+    /// rectangles differ in size. Blitting a multi-sampled frame buffer
+    /// into a single-sampled one resolves it. Colors are read from the first
+    /// color attachment of the source.
+    pub fn blit_frame_buffer(&mut self, src: &handle::FrameBuffer<R>,
+                             dst: &handle::FrameBuffer<R>, src_rect: d::target::Rect,
+                             dst_rect: d::target::Rect, mask: d::target::Mask,
+                             filter: BlitFilter) -> Result<(), BlitError> {
+        if !self.caps.render_targets_supported {
+            return Err(BlitError::Unsupported)
+        }
+        if filter == BlitFilter::Linear &&
+           mask.intersects(d::target::DEPTH | d::target::STENCIL) {
+            return Err(BlitError::InvalidFilter)
+        }
+        let src_name = self.frame_handles.ref_frame_buffer(src);
+        let dst_name = self.frame_handles.ref_frame_buffer(dst);
+        self.bind_read_frame_buffer(src_name, 0);
+        let (read_status, draw_status) = unsafe {
+            self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, dst_name);
+            (self.gl.CheckFramebufferStatus(gl::READ_FRAMEBUFFER),
+             self.gl.CheckFramebufferStatus(gl::DRAW_FRAMEBUFFER))
+        };
+        if read_status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(BlitError::IncompleteSource(read_status))
+        }
+        if draw_status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(BlitError::IncompleteDestination(draw_status))
+        }
+        type GLint = gl::types::GLint;
+        unsafe {
+            self.gl.BlitFramebuffer(
+                src_rect.x as GLint,
+                src_rect.y as GLint,
+                (src_rect.x + src_rect.w) as GLint,
+                (src_rect.y + src_rect.h) as GLint,
+                dst_rect.x as GLint,
+                dst_rect.y as GLint,
+                (dst_rect.x + dst_rect.w) as GLint,
+                (dst_rect.y + dst_rect.h) as GLint,
+                ::mask_to_gl(mask),
+                match filter {
+                    BlitFilter::Nearest => gl::NEAREST,
+                    BlitFilter::Linear => gl::LINEAR,
+                }
+            );
+        }
+        self.check_errors("blit_frame_buffer");
+        Ok(())
+    }
+
+    /// Create a texture holding a copy of a rectangle of the first color
+    /// attachment of a frame buffer, or of the back buffer for the main one.
+    pub fn create_texture_from_framebuffer(&mut self, src: &handle::FrameBuffer<R>,
+                                           rect: d::target::Rect, format: d::tex::Format)
+                                           -> Result<handle::Texture<R>, d::tex::TextureError> {
+        if format.is_compressed() || tex::is_depth_format(format) ||
+                !self.is_color_renderable(format) {
+            error!("Can't copy a frame buffer into a texture of {:?}", format);
+            return Err(d::tex::TextureError::UnsupportedFormat)
+        }
+        if format.does_convert_gamma() && !self.caps.srgb_color_supported {
+            return Err(d::tex::TextureError::UnsupportedGamma)
+        }
+        let info = d::tex::TextureInfo {
+            width: rect.w,
+            height: rect.h,
+            depth: 1,
+            levels: 1,
+            kind: d::tex::TextureKind::Texture2D,
+            format: format,
+        };
+        if rect.w == 0 || rect.h == 0 {
+            return Err(d::tex::TextureError::InvalidInfo(info))
+        }
+        let fbo = self.frame_handles.ref_frame_buffer(src);
+        unsafe {
+            self.gl.BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
+            self.gl.ReadBuffer(if fbo == 0 { gl::BACK } else { gl::COLOR_ATTACHMENT0 });
+        }
+        self.select_free_unit();
+        let name = try!(tex::make_from_framebuffer(&self.gl, &info, rect.x, rect.y));
+        self.check_errors("create_texture_from_framebuffer");
+        Ok(self.shared().manager.make_texture(name, info))
+    }
+
+    /// Read a single RGBA8 pixel of an output, typically for picking.
+    /// The coordinates have the GL origin in the bottom-left corner.
+    pub fn read_pixel<O: gfx::Output<R>>(&mut self, output: &O, x: Size, y: Size,
+                                         attachment: u32) -> [u8; 4] {
+        let mut pixel = [0u8; 4];
+        self.bind_read_target(output, attachment);
+        unsafe {
+            self.gl.ReadPixels(x as gl::types::GLint, y as gl::types::GLint, 1, 1,
+                gl::RGBA, gl::UNSIGNED_BYTE, pixel.as_mut_ptr() as *mut gl::types::GLvoid);
+        }
+        self.check_errors("read_pixel");
+        pixel
+    }
+
+    /// Read a rectangle of a color attachment of an output, for screenshots
+    /// or reference image tests. The rectangle is in GL coordinates, with
+    /// the origin in the bottom-left corner, but the returned rows are
+    /// tightly packed and ordered from the top down, like most image
+    /// formats expect.
+    pub fn read_pixels<O: gfx::Output<R>>(&mut self, output: &O, rect: d::target::Rect,
+                                          attachment: u32, format: d::tex::Format)
+                                          -> Result<Vec<u8>, d::tex::TextureError> {
+        if format.is_compressed() {
+            return Err(d::tex::TextureError::UnsupportedFormat)
+        }
+        self.bind_read_target(output, attachment);
+        let data = try!(tex::read_pixels(&self.gl, rect.x, rect.y, rect.w, rect.h, format));
+        self.check_errors("read_pixels");
+        Ok(data)
+    }
+
+    /// Read a rectangle of a color attachment of an output into `data`,
+    /// with rows laid out as GL packs them: from the bottom up, starting
+    /// every `row_length` texels (0 for the width of the rectangle) and
+    /// padded to `alignment` bytes. Fails if `data` is too short for that
+    /// layout, or with `UnsupportedFormat` if the alignment is not 1, 2, 4
+    /// or 8.
+    pub fn read_pixels_packed<O: gfx::Output<R>>(&mut self, output: &O,
+                                                 rect: d::target::Rect, attachment: u32,
+                                                 format: d::tex::Format, alignment: u8,
+                                                 row_length: u16, data: &mut [u8])
+                                                 -> Result<(), d::tex::TextureError> {
+        if format.is_compressed() {
+            return Err(d::tex::TextureError::UnsupportedFormat)
+        }
+        self.bind_read_target(output, attachment);
+        try!(tex::read_pixels_packed(&self.gl, rect.x, rect.y, rect.w, rect.h, format,
+                                     alignment, row_length, data));
+        self.check_errors("read_pixels_packed");
+        Ok(())
+    }
+
+    /// Read a single pixel of an unsigned integer attachment (`R32UI`),
+    /// typically an object identifier. See `read_pixel`.
+    pub fn read_pixel_u32<O: gfx::Output<R>>(&mut self, output: &O, x: Size, y: Size,
+                                             attachment: u32) -> u32 {
+        self.bind_read_target(output, attachment);
+        self.read_bound_u32(x, y)
+    }
+
+    fn read_bound_u32(&self, x: Size, y: Size) -> u32 {
+        let mut value = 0u32;
+        unsafe {
+            self.gl.ReadPixels(x as gl::types::GLint, y as gl::types::GLint, 1, 1,
+                gl::RED_INTEGER, gl::UNSIGNED_INT, &mut value as *mut u32 as *mut gl::types::GLvoid);
+        }
+        self.check_errors("read_pixel_u32");
+        value
+    }
+
+    /// Clear an unsigned integer (`R32UI`) color attachment of a frame
+    /// buffer to the given value, typically an invalid object identifier.
+    /// The attachment is addressed by its draw buffer index, which matches
+    /// the color attachment index unless `set_draw_buffers` remapped it.
+    pub fn clear_id_buffer(&mut self, frame_buffer: &handle::FrameBuffer<R>,
+                           attachment: u32, value: u32) {
+        let name = self.frame_handles.ref_frame_buffer(frame_buffer);
+        let values = [value, 0, 0, 0];
+        unsafe {
+            self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, name);
+            self.gl.ClearBufferuiv(gl::COLOR, attachment as gl::types::GLint, values.as_ptr());
+        }
+        self.check_errors("clear_id_buffer");
+    }
+
+    /// Read an object identifier out of an unsigned integer (`R32UI`) color
+    /// attachment of a frame buffer. Reading such an attachment as float
+    /// data fails, so this always transfers it as `RED_INTEGER`.
+    pub fn read_id(&mut self, frame_buffer: &handle::FrameBuffer<R>, attachment: u32,
+                   x: Size, y: Size) -> u32 {
+        let name = self.frame_handles.ref_frame_buffer(frame_buffer);
+        self.bind_read_frame_buffer(name, attachment);
+        self.read_bound_u32(x, y)
+    }
+
+    /// Check if the implementation can render to the given color format.
+    fn is_color_renderable(&self, format: d::tex::Format) -> bool {
+        match format {
+            d::tex::Format::Float(_, d::attrib::FloatSize::F16) =>
+                self.private_caps.half_float_color_renderable,
+            d::tex::Format::Float(_, _) | d::tex::Format::R11F_G11F_B10F =>
+                self.private_caps.float_color_renderable,
+            _ => true,
+        }
+    }
+
+    /// Attach a mip level of a texture to a frame buffer, for rendering into
+    /// a custom mip chain. Returns the dimensions of the level, which the
+    /// viewport has to match when drawing into it.
+    pub fn attach_texture_level(&mut self, frame_buffer: &handle::FrameBuffer<R>,
+                                target: d::draw::Target, texture: &handle::Texture<R>,
+                                level: u8) -> Result<(Size, Size), AttachmentError> {
+        if !self.caps.render_targets_supported {
+            return Err(AttachmentError::Unsupported)
+        }
+        let info = *texture.get_info();
+        if level >= info.levels {
+            return Err(AttachmentError::InvalidLevel(level))
+        }
+        if !self.is_color_renderable(info.format) {
+            return Err(AttachmentError::UnsupportedFormat)
+        }
+        let fbo = self.frame_handles.ref_frame_buffer(frame_buffer);
+        let name = self.frame_handles.ref_texture(texture);
+        unsafe { self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, fbo) };
+        tex::attach_level(&self.gl, ::target_to_gl(target), &info, name, level);
+        self.check_errors("attach_texture_level");
+        Ok(tex::level_size(&info, level))
+    }
+
+    /// Check if a texture was allocated with immutable storage, by
+    /// `create_texture` when `ARB_texture_storage` is supported. Only those
+    /// can be viewed with `create_level_view`, and they can't be
+    /// re-specified, only updated. Always `false` without texture storage.
+    pub fn texture_is_immutable(&mut self, texture: &handle::Texture<R>) -> bool {
+        if !self.caps.immutable_storage_supported {
+            return false
+        }
+        let name = self.frame_handles.ref_texture(texture);
+        self.select_free_unit();
+        let immutable = tex::is_immutable(&self.gl, texture.get_info().kind, name);
+        self.check_errors("texture_is_immutable");
+        immutable
+    }
+
+    /// Tell the driver the contents of a texture level are no longer
+    /// needed, such as a transient depth buffer between frames, sparing
+    /// tiled GPUs from storing and restoring them. The level is undefined
+    /// until written again. Does nothing without `ARB_invalidate_subdata`,
+    /// or if the level is out of range.
+    pub fn invalidate_texture(&mut self, texture: &handle::Texture<R>, level: u8) {
+        trace_call!(self, "invalidate_texture({:?}, {})", texture.get_info(), level);
+        if level >= texture.get_info().levels {
+            error!("Level {} is out of the {} levels of the texture, ignored",
+                   level, texture.get_info().levels);
+            return
+        }
+        if !self.private_caps.invalidate_subdata_supported {
+            debug!("Texture invalidation is not supported, ignored");
+            return
+        }
+        let name = self.frame_handles.ref_texture(texture);
+        unsafe { self.gl.InvalidateTexImage(name, level as gl::types::GLint) };
+        self.check_errors("invalidate_texture");
+    }
+
+    /// Invalidate a region of a texture level, like `invalidate_texture`.
+    /// The format of the image info is ignored.
+    pub fn invalidate_texture_region(&mut self, texture: &handle::Texture<R>,
+                                     img: &d::tex::ImageInfo) {
+        trace_call!(self, "invalidate_texture_region({:?}, {:?})", texture.get_info(), img);
+        if img.mipmap >= texture.get_info().levels {
+            error!("Level {} is out of the {} levels of the texture, ignored",
+                   img.mipmap, texture.get_info().levels);
+            return
+        }
+        if !self.private_caps.invalidate_subdata_supported {
+            debug!("Texture invalidation is not supported, ignored");
+            return
+        }
+        let name = self.frame_handles.ref_texture(texture);
+        unsafe {
+            self.gl.InvalidateTexSubImage(name, img.mipmap as gl::types::GLint,
+                img.xoffset as gl::types::GLint, img.yoffset as gl::types::GLint,
+                img.zoffset as gl::types::GLint, img.width as gl::types::GLsizei,
+                img.height as gl::types::GLsizei, img.depth as gl::types::GLsizei);
+        }
+        self.check_errors("invalidate_texture_region");
+    }
+
+    /// Upload texture data, from client memory or from the bound pixel
+    /// unpack buffer, in which case `address` is an offset into it.
+    fn update_texture_internal(&mut self, texture: &handle::Texture<R>,
+                               img: &d::tex::ImageInfo, address: *const u8, size: usize,
+                               optkind: Option<d::tex::TextureKind>)
+                               -> Result<(), d::tex::TextureError> {
+        // use the specified texture kind if set for this update, otherwise
+        // fall back on the kind that was set when the texture was created.
+        let kind = optkind.unwrap_or(texture.get_info().kind);
+        let name = self.frame_handles.ref_texture(texture);
+        if !tex::is_compression_supported(img.format, &self.compressed_formats) {
+            return Err(d::tex::TextureError::UnsupportedFormat)
+        }
+        let layers = tex::get_layer_range(kind, img);
+        if !tex::is_layer_range_valid(kind, img, texture.get_info()) {
+            error!("Update of layers {:?} is out of the {} layers of texture {}",
+                   layers, tex::get_layer_count(texture.get_info()),
+                   self.describe_object(gl::TEXTURE, name));
+            return Err(d::tex::TextureError::InvalidInfo(*texture.get_info()))
+        }
+
+        self.select_free_unit();
+        try!(tex::update_texture(&self.gl, kind, name, img, address, size));
+        self.check_errors("update_texture_raw");
+        if let Some((start, end)) = layers {
+            let count = tex::get_layer_count(texture.get_info()) as usize;
+            let mut shared = self.shared();
+            let uploaded = shared.uploaded_layers.entry(name)
+                                 .or_insert_with(|| (0 .. count).map(|_| false).collect());
+            for layer in start .. end {
+                uploaded[layer as usize] = true;
+            }
+        }
+        if self.auto_mipmap && img.mipmap == 0 && texture.get_info().levels > 1 {
+            d::Factory::generate_mipmap(self, texture);
+        }
+        if self.barrier_after_update {
+            self.texture_barrier();
+        }
+        Ok(())
+    }
+
+    /// Upload texture data, staging it in a pixel unpack buffer if one is
+    /// given. The driver then copies it into the texture asynchronously
+    /// instead of during the call, which helps with large images, for
+    /// example read from a memory-mapped file. The buffer has to be large
+    /// enough for the data, and can be reused once the upload is done.
+    ///
+    /// The data is uploaded directly, with a warning, if pixel buffers are
+    /// not supported or the buffer has immutable storage that can't be
+    /// updated.
+    pub fn update_texture_from_bytes(&mut self, texture: &handle::Texture<R>,
+                                     img: &d::tex::ImageInfo, data: &[u8],
+                                     pbo: Option<&handle::RawBuffer<R>>)
+                                     -> Result<(), d::tex::TextureError> {
+        let pbo = match pbo {
+            Some(pbo) if self.private_caps.pixel_buffer_supported => pbo,
+            Some(_) => {
+                warn!("Pixel buffers are not supported, uploading texture data directly");
+                return d::Factory::update_texture_raw(self, texture, img, data, None)
+            },
+            None => return d::Factory::update_texture_raw(self, texture, img, data, None),
+        };
+        let buffer = self.frame_handles.ref_buffer(pbo);
+        let capacity = self.buffer_size(buffer, pbo.get_info());
+        if data.len() > capacity {
+            error!("Texture data of {} bytes doesn't fit into the {} bytes of buffer {}",
+                   data.len(), capacity, self.describe_object(gl::BUFFER, buffer));
+            return Err(d::tex::TextureError::IncorrectSize(capacity))
+        }
+        if self.check_buffer_updatable(buffer).is_err() {
+            warn!("Buffer {} has immutable storage without dynamic updates, \
+                   uploading texture data directly",
+                  self.describe_object(gl::BUFFER, buffer));
+            return d::Factory::update_texture_raw(self, texture, img, data, None)
+        }
+        unsafe {
+            self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, buffer);
+            self.gl.BufferSubData(gl::PIXEL_UNPACK_BUFFER, 0,
+                                  data.len() as gl::types::GLsizeiptr,
+                                  data.as_ptr() as *const gl::types::GLvoid);
+        }
+        let result = self.update_texture_internal(texture, img, 0 as *const u8, data.len(), None);
+        unsafe { self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0) };
+        self.check_errors("update_texture_from_bytes");
+        result
+    }
+
+    /// Create a texture viewing a single mip level of a 2D texture, to be
+    /// attached at level 0 for rendering into that level. The view has the
+    /// dimensions of the level, so the attachment and viewport match it
+    /// without any offset bookkeeping. Needs `ARB_texture_view`, and the
+    /// texture has to have immutable storage.
+    pub fn create_level_view(&mut self, texture: &handle::Texture<R>, level: u8)
+                             -> Result<handle::Texture<R>, AttachmentError> {
+        if !self.private_caps.texture_view_supported {
+            return Err(AttachmentError::Unsupported)
+        }
+        let info = *texture.get_info();
+        if level >= info.levels {
+            return Err(AttachmentError::InvalidLevel(level))
+        }
+        match info.kind {
+            d::tex::TextureKind::Texture2D if self.is_color_renderable(info.format) => (),
+            _ => return Err(AttachmentError::UnsupportedFormat),
+        }
+        let name = self.frame_handles.ref_texture(texture);
+        self.select_free_unit();
+        if !tex::is_immutable(&self.gl, info.kind, name) {
+            error!("Texture {} has mutable storage, views can't be made of it", name);
+            return Err(AttachmentError::Unsupported)
+        }
+        let (view, view_info) = try!(tex::make_level_view(&self.gl, &info, name, level)
+                                        .map_err(|_| AttachmentError::UnsupportedFormat));
+        info!("\tCreated view {} of level {} of texture {}",
+              view, level, self.describe_object(gl::TEXTURE, name));
+        self.check_errors("create_level_view");
+        Ok(self.shared().manager.make_texture(view, view_info))
+    }
+
+    fn create_peel_texture(&mut self, width: u16, height: u16, format: d::tex::Format)
+                           -> Result<handle::Texture<R>, AttachmentError> {
+        let info = d::tex::TextureInfo {
+            width: width,
+            height: height,
+            depth: 1,
+            levels: 1,
+            kind: d::tex::TextureKind::Texture2D,
+            format: format,
+        };
+        d::Factory::create_texture(self, info).map_err(|e| {
+            error!("Unable to create a depth peeling target: {:?}", e);
+            AttachmentError::UnsupportedFormat
+        })
+    }
+
+    /// Allocate the frame buffers and float textures of dual depth peeling,
+    /// for order-independent transparency of up to `layers` layers.
+    pub fn create_depth_peel_targets(&mut self, width: u16, height: u16, layers: u32)
+                                     -> Result<DepthPeelTargets, AttachmentError> {
+        use gfx::device::attrib::FloatSize;
+        use gfx::device::draw::Target;
+        use gfx::device::tex::{Components, Format};
+        if !self.caps.render_targets_supported {
+            return Err(AttachmentError::Unsupported)
+        }
+        let depth_format = Format::Float(Components::RG, FloatSize::F32);
+        let color_format = Format::Float(Components::RGBA, FloatSize::F16);
+        let make_set = |factory: &mut Factory| -> Result<DepthPeelSet, AttachmentError> {
+            let set = DepthPeelSet {
+                frame_buffer: d::Factory::create_frame_buffer(factory),
+                depth: try!(factory.create_peel_texture(width, height, depth_format)),
+                front: try!(factory.create_peel_texture(width, height, color_format)),
+                back: try!(factory.create_peel_texture(width, height, color_format)),
+            };
+            for (i, texture) in [&set.depth, &set.front, &set.back].iter().enumerate() {
+                try!(factory.attach_texture_level(&set.frame_buffer, Target::Color(i as u8),
+                                                  texture, 0));
+            }
+            Ok(set)
+        };
+        let sets = [try!(make_set(self)), try!(make_set(self))];
+        let back_blend = try!(self.create_peel_texture(width, height, color_format));
+        let back_blend_buffer = d::Factory::create_frame_buffer(self);
+        try!(self.attach_texture_level(&back_blend_buffer, Target::Color(0), &back_blend, 0));
+        Ok(DepthPeelTargets {
+            sets: sets,
+            back_blend_buffer: back_blend_buffer,
+            back_blend: back_blend,
+            passes: (layers + 1) / 2,
+        })
+    }
+
+    /// Update a texture from data with swapped bytes within each component,
+    /// as delivered by some scientific and medical formats. Only integer
+    /// formats are accepted, as the byte order of packed and float formats
+    /// is not well-defined. Reversed bit orders (`UNPACK_LSB_FIRST`) are
+    /// not handled: GL only applies them to 1-bit bitmap data, which has no
+    /// texture format.
+    pub fn update_texture_reordered(&mut self, texture: &handle::Texture<R>,
+                                    img: &d::tex::ImageInfo, data: &[u8],
+                                    swap_bytes: bool)
+                                    -> Result<(), d::tex::TextureError> {
+        match img.format {
+            d::tex::Format::Integer(..) | d::tex::Format::Unsigned(..) => (),
+            format => {
+                error!("Byte reordering is not defined for {:?}", format);
+                return Err(d::tex::TextureError::UnsupportedFormat)
+            },
+        }
+        let old = tex::set_pixel_store(&self.gl, &[
+            (gl::UNPACK_SWAP_BYTES, swap_bytes as gl::types::GLint),
+        ]);
+        let result = d::Factory::update_texture_raw(self, texture, img, data, None);
+        tex::set_pixel_store(&self.gl, &old);
+        self.check_errors("update_texture_reordered");
+        result
+    }
+
+    /// Apply a set of pixel pack and unpack parameters at once. Redundant
+    /// calls are skipped, see `forget_state_cache`.
+    pub fn set_pixel_store_preset(&mut self, preset: tex::PixelStorePreset) {
+        self.sync_state_cache();
+        if self.pixel_store == Some(preset) {
+            return
+        }
+        tex::set_pixel_store(&self.gl, &tex::preset_to_pixel_store(preset));
+        self.check_errors("set_pixel_store_preset");
+        self.pixel_store = Some(preset);
+    }
+
+    /// Regenerate `count` mip levels following the `base` one, leaving the
+    /// others untouched, for textures where only a part of the chain is
+    /// stale. GL can only regenerate whole chains, so this downloads the base
+    /// level and box-filters it on the CPU, which is only worth it for small
+    /// ranges. Supports 2D textures with 8-bit normalized channels; sRGB data
+    /// is filtered without linearization.
+    pub fn generate_mipmap_range(&mut self, texture: &handle::Texture<R>, base: u8,
+                                 count: u8) -> Result<(), d::tex::TextureError> {
+        let info = *texture.get_info();
+        let channels = match (info.kind, tex::get_box_filter_channels(info.format)) {
+            (d::tex::TextureKind::Texture2D, Some(c)) => c,
+            _ => return Err(d::tex::TextureError::UnsupportedFormat),
+        };
+        if base as usize + count as usize >= info.levels as usize {
+            return Err(d::tex::TextureError::InvalidInfo(info))
+        }
+        let name = self.frame_handles.ref_texture(texture);
+        let (mut width, mut height) = tex::level_size(&info, base);
+        let mut data: Vec<u8> = (0 .. width as usize * height as usize * channels)
+                                    .map(|_| 0).collect();
+        self.select_free_unit();
+        try!(tex::read_level(&self.gl, info.kind, name, base, info.format, &mut data));
+        let old = tex::set_pixel_store(&self.gl, &[(gl::UNPACK_ALIGNMENT, 1)]);
+        let mut result = Ok(());
+        for level in base + 1 .. base + count + 1 {
+            let (next, w, h) = tex::downsample_box(&data, width, height, channels);
+            let img = d::tex::ImageInfo {
+                xoffset: 0,
+                yoffset: 0,
+                zoffset: 0,
+                width: w,
+                height: h,
+                depth: 1,
+                format: info.format,
+                mipmap: level,
+            };
+            result = d::Factory::update_texture_raw(self, texture, &img, &next, None);
+            if result.is_err() {
+                break
+            }
+            data = next;
+            width = w;
+            height = h;
+        }
+        tex::set_pixel_store(&self.gl, &old);
+        self.check_errors("generate_mipmap_range");
+        result
+    }
+
+    /// Read back a mip level of a compressed texture in its compressed form,
+    /// returning the number of bytes written into `data`. Fails with
+    /// `IncorrectSize` giving the required size if `data` is too small.
+    pub fn read_compressed_texture(&mut self, texture: &handle::Texture<R>, level: u8,
+                                   data: &mut [u8]) -> Result<usize, d::tex::TextureError> {
+        let info = *texture.get_info();
+        if !info.format.is_compressed() {
+            return Err(d::tex::TextureError::UnsupportedFormat)
+        }
+        if level >= info.levels {
+            return Err(d::tex::TextureError::InvalidInfo(info))
+        }
+        let name = self.frame_handles.ref_texture(texture);
+        self.select_free_unit();
+        let size = try!(tex::read_compressed(&self.gl, info.kind, name, level, data));
+        self.check_errors("read_compressed_texture");
+        Ok(size)
+    }
+
+    /// Disable all the vertex attributes of an array buffer, so that a
+    /// pooled one doesn't carry the layout of the previous mesh. The array
+    /// buffer is left bound.
+    pub fn reset_vertex_array(&mut self, vao: &handle::ArrayBuffer<R>) {
+        let name = self.frame_handles.ref_array_buffer(vao);
+        unsafe { self.gl.BindVertexArray(name) };
+        for i in 0 .. self.caps.max_vertex_attributes {
+            unsafe { self.gl.DisableVertexAttribArray(i as gl::types::GLuint) };
+        }
+        self.check_errors("reset_vertex_array");
+    }
+
+    pub fn get_main_frame_buffer(&self) -> handle::FrameBuffer<R> {
+        self.main_fbo.clone()
+    }
+
+    pub fn make_fake_output(&self, w: Size, h: Size) -> Output {
+        Output {
+            width: w,
+            height: h,
+            handle: self.main_fbo.clone(),
+        }
+    }
+
+    fn get_default_texture(&mut self, slot: usize, color: [u8; 4])
+                           -> Result<handle::Texture<R>, d::tex::TextureError> {
+        if let Some(ref texture) = self.default_textures[slot] {
+            return Ok(texture.clone())
+        }
+        let format = d::tex::Format::Unsigned(d::tex::Components::RGBA, 8,
+                                              d::attrib::IntSubType::Normalized);
+        let info = d::tex::TextureInfo {
+            width: 1,
+            height: 1,
+            depth: 1,
+            levels: 1,
+            kind: d::tex::TextureKind::Texture2D,
+            format: format,
+        };
+        let img = d::tex::ImageInfo {
+            xoffset: 0,
+            yoffset: 0,
+            zoffset: 0,
+            width: 1,
+            height: 1,
+            depth: 1,
+            format: format,
+            mipmap: 0,
+        };
+        let texture = try!(d::Factory::create_texture(self, info));
+        try!(d::Factory::update_texture_raw(self, &texture, &img, &color, None));
+        self.default_textures[slot] = Some(texture.clone());
+        Ok(texture)
+    }
+
+    /// Get a 1x1 opaque white texture, to bind in place of a missing one.
+    /// The texture is created on the first successful call and shared
+    /// afterwards.
+    pub fn default_texture(&mut self) -> Result<handle::Texture<R>, d::tex::TextureError> {
+        self.get_default_texture(0, [0xFF, 0xFF, 0xFF, 0xFF])
+    }
+
+    /// Get a 1x1 opaque black texture, see `default_texture`.
+    pub fn default_black_texture(&mut self)
+                                 -> Result<handle::Texture<R>, d::tex::TextureError> {
+        self.get_default_texture(1, [0, 0, 0, 0xFF])
+    }
+
+    /// Get a 1x1 flat tangent-space normal map, see `default_texture`.
+    pub fn default_normal_texture(&mut self)
+                                  -> Result<handle::Texture<R>, d::tex::TextureError> {
+        self.get_default_texture(2, [0x80, 0x80, 0xFF, 0xFF])
+    }
+
+    /// Create a depth texture along with a sampler set up for hardware PCF:
+    /// linear filtering, clamping to the edge and a `Less` comparison, so that
+    /// sampling it in a shader with a shadow sampler type returns the
+    /// filtered comparison result.
+    pub fn create_shadow_map(&mut self, width: u16, height: u16, format: d::tex::Format)
+                             -> Result<(handle::Texture<R>, handle::Sampler<R>), d::tex::TextureError> {
+        if !tex::is_depth_format(format) {
+            error!("Shadow maps need a depth format, got {:?}", format);
+            return Err(d::tex::TextureError::UnsupportedFormat)
+        }
+        let info = d::tex::TextureInfo {
+            width: width,
+            height: height,
+            depth: 1,
+            levels: 1,
+            kind: d::tex::TextureKind::Texture2D,
+            format: format,
+        };
+        let texture = try!(d::Factory::create_texture(self, info));
+        let mut sinfo = d::tex::SamplerInfo::new(d::tex::FilterMethod::Bilinear,
+                                                 d::tex::WrapMode::Clamp);
+        sinfo.comparison = d::tex::ComparisonMode::CompareRefToTexture(
+            d::state::Comparison::Less);
+        let sampler = d::Factory::create_sampler(self, sinfo);
+        Ok((texture, sampler))
+    }
+
+    /// Get the layers of an array texture that never received any data, and
+    /// would be sampled as garbage. Meant for debug assertions, for example
+    /// `debug_assert!(factory.get_missing_layers(&t).is_empty())`.
+    pub fn get_missing_layers(&mut self, texture: &handle::Texture<R>) -> Vec<u16> {
+        let count = tex::get_layer_count(texture.get_info());
+        let name = self.frame_handles.ref_texture(texture);
+        match self.shared().uploaded_layers.get(&name) {
+            Some(uploaded) => (0 .. count).filter(|&l| !uploaded[l as usize]).collect(),
+            None => match texture.get_info().kind {
+                d::tex::TextureKind::Texture1DArray |
+                d::tex::TextureKind::Texture2DArray => (0 .. count).collect(),
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    /// Keep the given texture units for external code, such as an embedded
+    /// video player using fixed units. The units are per context: the
+    /// factory stops binding textures to them, and the device moves the
+    /// texture slots of the commands past them at submission.
+    pub fn reserve_texture_units(&mut self, units: &[u32]) {
+        let mut reserved = self.reserved_units.borrow_mut();
+        for &unit in units.iter() {
+            if !reserved.contains(&unit) {
+                reserved.push(unit);
+            }
+        }
+    }
+
+    /// Give back texture units kept by `reserve_texture_units`.
+    pub fn release_texture_units(&mut self, units: &[u32]) {
+        self.reserved_units.borrow_mut().retain(|u| !units.contains(u));
+    }
+
+    /// Make the lowest texture unit that isn't reserved active, before
+    /// binding a texture in order to modify it.
+    fn select_free_unit(&self) {
+        let reserved = self.reserved_units.borrow();
+        if reserved.is_empty() {
+            return
+        }
+        let unit = (0..).find(|u| !reserved.contains(u)).unwrap();
+        unsafe { self.gl.ActiveTexture(gl::TEXTURE0 + unit) };
+    }
+
+    /// Check if samplers are emulated by setting their parameters on the
+    /// textures at bind time, when sampler objects are not supported.
+    ///
+    /// Emulated sampler state sticks to the texture: sampling one texture
+    /// through two different samplers within the same draw call gives both
+    /// the parameters of the last one bound, and sampler-specific settings
+    /// such as `set_srgb_decode` apply to every use of the texture.
+    pub fn are_samplers_emulated(&self) -> bool {
+        !self.caps.sampler_objects_supported
+    }
+
+    /// Name a GL object for debuggers such as RenderDoc, and in the log
+    /// messages of the factory. Debuggers only see it with `KHR_debug`.
+    fn set_object_label(&mut self, identifier: gl::types::GLenum, name: gl::types::GLuint,
+                        label: &str) {
+        if name == 0 {
+            return
+        }
+        if is_container(identifier) {
+            self.container_labels.insert((identifier, name), label.to_string());
+        } else {
+            self.shared().labels.insert((identifier, name), label.to_string());
+        }
+        if !self.private_caps.debug_label_supported {
+            return
+        }
+        if is_container(identifier) {
+            self.bind_container_once(identifier, name);
+        }
+        unsafe {
+            self.gl.ObjectLabel(identifier, name, label.len() as gl::types::GLsizei,
+                                label.as_ptr() as *const gl::types::GLchar);
+        }
+        self.check_errors("set_object_label");
+    }
+
+    /// Bind a container object and restore the previous binding. The names
+    /// made by `Gen*` only become objects when first bound, and `ObjectLabel`
+    /// fails on names that are not objects yet.
+    fn bind_container_once(&self, identifier: gl::types::GLenum, name: gl::types::GLuint) {
+        let mut bound = 0 as gl::types::GLint;
+        unsafe {
+            match identifier {
+                gl::FRAMEBUFFER => {
+                    self.gl.GetIntegerv(gl::DRAW_FRAMEBUFFER_BINDING, &mut bound);
+                    self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, name);
+                    self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, bound as gl::types::GLuint);
+                },
+                gl::VERTEX_ARRAY => {
+                    self.gl.GetIntegerv(gl::VERTEX_ARRAY_BINDING, &mut bound);
+                    self.gl.BindVertexArray(name);
+                    self.gl.BindVertexArray(bound as gl::types::GLuint);
+                },
+                gl::PROGRAM_PIPELINE => {
+                    self.gl.GetIntegerv(gl::PROGRAM_PIPELINE_BINDING, &mut bound);
+                    self.gl.BindProgramPipeline(name);
+                    self.gl.BindProgramPipeline(bound as gl::types::GLuint);
+                },
+                _ => (),
+            }
+        }
+    }
 
-/// A placeholder for a real `Output` implemented by your window.
-pub struct Output {
-    width: Size,
-    height: Size,
-    handle: handle::FrameBuffer<R>,
-}
+    /// Give a label to the next object created by the factory, as if set by
+    /// the `set_*_label` methods right away, so that it shows in the log
+    /// messages about its creation. The label is dropped if the creation
+    /// fails.
+    pub fn set_next_label(&mut self, label: &str) {
+        self.next_label = Some(label.to_string());
+    }
 
-impl gfx::Output<R> for Output {
-    fn get_handle(&self) -> Option<&handle::FrameBuffer<R>> {
-        Some(&self.handle)
+    /// Label a new object with the label taken from `next_label`, if any.
+    /// Buffers have to be bound before, see `bind_container_once` for the
+    /// containers.
+    fn apply_label(&mut self, identifier: gl::types::GLenum, name: gl::types::GLuint,
+                   label: Option<String>) {
+        if let Some(label) = label {
+            self.set_object_label(identifier, name, &label);
+        }
     }
 
-    fn get_size(&self) -> (Size, Size) {
-        (self.width, self.height)
+    /// Give a buffer a name shown by GL debuggers, if `KHR_debug` is
+    /// supported.
+    pub fn set_buffer_label(&mut self, buffer: &handle::RawBuffer<R>, label: &str) {
+        let name = self.frame_handles.ref_buffer(buffer);
+        self.set_object_label(gl::BUFFER, name, label);
     }
 
-    fn get_mask(&self) -> gfx::Mask {
-        gfx::COLOR | gfx::DEPTH | gfx::STENCIL
+    /// Give a texture a name shown by GL debuggers, if `KHR_debug` is
+    /// supported.
+    pub fn set_texture_label(&mut self, texture: &handle::Texture<R>, label: &str) {
+        let name = self.frame_handles.ref_texture(texture);
+        self.set_object_label(gl::TEXTURE, name, label);
     }
-}
 
-/// GL resource factory.
-pub struct Factory {
-    caps: d::Capabilities,
-    gl: Rc<gl::Gl>,
-    main_fbo: handle::FrameBuffer<R>,
-    handles: handle::Manager<R>,
-    frame_handles: handle::Manager<R>,
-}
+    /// Give a frame buffer a name shown by GL debuggers, if `KHR_debug` is
+    /// supported.
+    pub fn set_frame_buffer_label(&mut self, frame_buffer: &handle::FrameBuffer<R>,
+                                  label: &str) {
+        let name = self.frame_handles.ref_frame_buffer(frame_buffer);
+        self.set_object_label(gl::FRAMEBUFFER, name, label);
+    }
 
-/// Create a new `Factory`.
-pub fn create(caps: d::Capabilities, gl: Rc<gl::Gl>) -> Factory {
-    let mut handles = handle::Manager::new();
+    /// Give a shader a name shown by GL debuggers, if `KHR_debug` is
+    /// supported.
+    pub fn set_shader_label(&mut self, shader: &handle::Shader<R>, label: &str) {
+        let name = self.frame_handles.ref_shader(shader);
+        self.set_object_label(gl::SHADER, name, label);
+    }
 
-    Factory {
-        caps: caps,
-        gl: gl,
-        main_fbo: handles.make_frame_buffer(0),
-        handles: handles,
-        frame_handles: handle::Manager::new(),
+    /// Give a program a name shown by GL debuggers, if `KHR_debug` is
+    /// supported.
+    pub fn set_program_label(&mut self, program: &handle::Program<R>, label: &str) {
+        let name = self.frame_handles.ref_program(program);
+        self.set_object_label(gl::PROGRAM, name, label);
     }
-}
 
-impl Factory {
-    fn create_buffer_internal(&mut self) -> Buffer {
-        let mut name = 0 as Buffer;
-        unsafe {
-            self.gl.GenBuffers(1, &mut name);
+    /// Give a sampler a name shown by GL debuggers, if `KHR_debug` is
+    /// supported. Emulated samplers have no GL object to name. Samplers
+    /// created with identical infos share their object, and thus the label:
+    /// use `set_next_label` before creating a sampler to give it its own.
+    pub fn set_sampler_label(&mut self, sampler: &handle::Sampler<R>, label: &str) {
+        let name = self.frame_handles.ref_sampler(sampler);
+        self.set_object_label(gl::SAMPLER, name, label);
+    }
+
+    /// Query the video memory through the vendor extensions of NVIDIA or
+    /// AMD, for budgeting the resources to stream. Returns `None` if the
+    /// driver supports neither.
+    pub fn query_gpu_memory(&self) -> Option<GpuMemoryInfo> {
+        let info = self.private_caps.memory_info.map(|ext| match ext {
+            MemoryInfoExtension::Nvx => {
+                let mut total = 0;
+                let mut available = 0;
+                unsafe {
+                    self.gl.GetIntegerv(GPU_MEMORY_INFO_DEDICATED_VIDMEM_NVX, &mut total);
+                    self.gl.GetIntegerv(GPU_MEMORY_INFO_CURRENT_AVAILABLE_VIDMEM_NVX,
+                                        &mut available);
+                }
+                GpuMemoryInfo {
+                    total_kb: Some(total as usize),
+                    available_kb: available as usize,
+                }
+            },
+            MemoryInfoExtension::Ati => {
+                // total free, largest free block, and the same for auxiliary memory
+                let mut free = [0; 4];
+                unsafe { self.gl.GetIntegerv(TEXTURE_FREE_MEMORY_ATI, free.as_mut_ptr()) };
+                GpuMemoryInfo {
+                    total_kb: None,
+                    available_kb: free[0] as usize,
+                }
+            },
+        });
+        self.check_errors("query_gpu_memory");
+        info
+    }
+
+    /// Get the OpenGL-specific capabilities
+    pub fn get_private_caps(&self) -> &PrivateCaps {
+        &self.private_caps
+    }
+
+    /// Make sure that texture updates issued so far are visible to the
+    /// following draw calls sampling from the same texture.
+    /// Uses `TextureBarrier` when available, falling back to a texture fetch
+    /// memory barrier.
+    ///
+    /// This also allows a draw call to sample the texture it renders to,
+    /// for in-place read-modify-write: texel fetches after the barrier see
+    /// the writes of the draw calls issued before it, but never the writes
+    /// of draw calls issued after it, including the one doing the fetch.
+    /// Each texel should thus be read and written at most once between two
+    /// barriers, otherwise the result is undefined. Check
+    /// `texture_barrier_supported` in the private caps first, the memory
+    /// barrier fallback doesn't cover rendering feedback.
+    pub fn texture_barrier(&mut self) {
+        if self.private_caps.texture_barrier_supported {
+            unsafe { self.gl.TextureBarrier() };
+        } else if self.private_caps.memory_barrier_supported {
+            unsafe { self.gl.MemoryBarrier(gl::TEXTURE_FETCH_BARRIER_BIT) };
+        } else {
+            warn!("Texture barriers are not supported, ignored");
+            return
         }
-        info!("\tCreated buffer {}", name);
-        name
+        self.check_errors("texture_barrier");
     }
 
-    fn init_buffer(&mut self, buffer: Buffer, info: &d::BufferInfo) {
-        let target = match info.role {
-            d::BufferRole::Vertex => gl::ARRAY_BUFFER,
-            d::BufferRole::Index  => gl::ELEMENT_ARRAY_BUFFER,
+    /// Workaround for drivers that don't order `TexSubImage` with respect to
+    /// the following draw calls: when enabled, every texture update is
+    /// followed by a `texture_barrier`.
+    pub fn set_barrier_after_update(&mut self, enabled: bool) {
+        self.barrier_after_update = enabled;
+    }
+
+    /// Generate the mipmaps of a texture after each update of its base
+    /// level. Otherwise, `create_texture` only allocates the levels above
+    /// the base one: they stay undefined, and sample as black, until they
+    /// are uploaded or `generate_mipmap` is called.
+    pub fn set_auto_mipmap(&mut self, enabled: bool) {
+        self.auto_mipmap = enabled;
+    }
+
+    /// Check the GL error flag after every factory operation, and panic
+    /// with the name of the failing one. This is a debugging aid: querying
+    /// the error forces the driver to synchronize, which is slow. Disabling
+    /// it goes back to the default of the context, see `set_error_check`.
+    pub fn set_strict_errors(&mut self, enabled: bool) {
+        self.error_check = if enabled {
+            ErrorCheck::Panic
+        } else {
+            default_error_check(&self.private_caps)
         };
-        unsafe { self.gl.BindBuffer(target, buffer) };
-        let usage = match info.usage {
-            d::BufferUsage::Static  => gl::STATIC_DRAW,
-            d::BufferUsage::Dynamic => gl::DYNAMIC_DRAW,
-            d::BufferUsage::Stream  => gl::STREAM_DRAW,
+    }
+
+    /// Log every resource creation, update and mapping with its arguments at
+    /// the trace level, to match the operations of the factory with a GL
+    /// call trace captured by a tool such as apitrace.
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    /// Choose what to do with the GL errors raised by factory operations.
+    /// See `set_strict_errors` for the cost. Defaults to `Log` on a debug
+    /// context, `Off` otherwise.
+    pub fn set_error_check(&mut self, check: ErrorCheck) {
+        self.error_check = check;
+    }
+
+    fn check_errors(&self, operation: &str) {
+        if self.error_check == ErrorCheck::Off {
+            return
+        }
+        let report = error_report(operation, unsafe { self.gl.GetError() });
+        match (report, self.error_check) {
+            (Some(report), ErrorCheck::Panic) => panic!("{}", report),
+            (Some(report), _) => error!("{}", report),
+            (None, _) => (),
+        }
+    }
+
+    /// Let multi-sampled surfaces and textures fall back to the highest
+    /// supported sample count below the requested one, instead of failing.
+    /// The count actually allocated is reported by the `aa_mode` of the
+    /// returned handle's info. Counts above the highest supported one are
+    /// always clamped to it, with a warning.
+    pub fn set_msaa_fallback(&mut self, enabled: bool) {
+        self.msaa_fallback = enabled;
+    }
+
+    /// Resolve the sample count to allocate for a multi-sampled resource.
+    fn resolve_sample_count(&self, target: gl::types::GLenum,
+                            format: d::tex::Format, requested: u8) -> Option<u8> {
+        let counts = tex::get_sample_counts(&self.gl, target, format,
+            self.private_caps.internalformat_query_supported, self.private_caps.max_samples);
+        if counts.contains(&requested) {
+            return Some(requested)
+        }
+        match counts.first() {
+            // more than the maximum can never be allocated, always clamp
+            Some(&highest) if requested > highest => {
+                warn!("{}x multi-sampling of {:?} exceeds the maximum of {}x, clamping",
+                      requested, format, highest);
+                return Some(highest)
+            },
+            None => {
+                error!("Multi-sampling of {:?} is not supported", format);
+                return None
+            },
+            Some(_) => (),
+        }
+        if !self.msaa_fallback {
+            error!("{}x multi-sampling of {:?} is not supported", requested, format);
+            return None
+        }
+        let count = tex::pick_sample_count(&counts, requested);
+        match count {
+            Some(c) => warn!("{}x multi-sampling of {:?} is not supported, using {}x",
+                             requested, format, c),
+            None => error!("{}x multi-sampling of {:?} is not supported", requested, format),
+        }
+        count
+    }
+
+    /// Get the maximum sample count of multi-sampled surfaces of a format,
+    /// which can be lower than the global maximum for integer or floating
+    /// point formats. Without `ARB_internalformat_query` this is the global
+    /// maximum. Zero means the format can't be multi-sampled.
+    pub fn max_samples_for_format(&self, format: d::tex::Format) -> u32 {
+        if !self.private_caps.internalformat_query_supported {
+            return self.private_caps.max_samples as u32
+        }
+        // the counts come in descending order
+        let counts = tex::get_sample_counts(&self.gl, gl::RENDERBUFFER, format,
+                                            true, self.private_caps.max_samples);
+        self.check_errors("max_samples_for_format");
+        counts.first().map_or(0, |&c| c as u32)
+    }
+
+    /// Enable or disable blending on a single color attachment. Without
+    /// `ARB_draw_buffers_blend` this toggles blending for all attachments.
+    /// Redundant calls are skipped, see `forget_state_cache`. Attachments
+    /// beyond the supported draw buffers are ignored.
+    pub fn set_blend_enabled(&mut self, attachment: u32, enabled: bool) {
+        let index = attachment as usize;
+        if index >= self.blend_enabled.len() {
+            error!("Color attachment {} is out of range, only {} are supported, ignored",
+                   attachment, self.blend_enabled.len());
+            return
+        }
+        self.sync_state_cache();
+        // the bundled blend state no longer describes every attachment
+        self.pipeline_state = None;
+        if self.private_caps.draw_buffers_blend_supported {
+            if self.blend_enabled[index] != Some(enabled) {
+                state::bind_blend_enabled(&self.gl, Some(attachment), enabled);
+                self.blend_enabled[index] = Some(enabled);
+            }
+        } else {
+            if self.blend_enabled.iter().any(|&b| b != Some(enabled)) {
+                warn!("Per-attachment blending is not supported, toggling all attachments");
+                state::bind_blend_enabled(&self.gl, None, enabled);
+                for b in self.blend_enabled.iter_mut() {
+                    *b = Some(enabled);
+                }
+            }
+        }
+        self.check_errors("set_blend_enabled");
+    }
+
+    /// Clamp the depth of fragments to the depth range instead of clipping
+    /// primitives against the near and far planes, so that shadow casters
+    /// behind the near plane still write depth. Redundant calls are
+    /// skipped, see `forget_state_cache`.
+    pub fn set_depth_clamp(&mut self, enabled: bool) {
+        if !self.private_caps.depth_clamp_supported {
+            error!("Depth clamping is not supported, ignored");
+            return
+        }
+        self.sync_state_cache();
+        if self.depth_clamp == Some(enabled) {
+            return
+        }
+        unsafe {
+            if enabled {
+                self.gl.Enable(gl::DEPTH_CLAMP);
+            } else {
+                self.gl.Disable(gl::DEPTH_CLAMP);
+            }
+        }
+        self.check_errors("set_depth_clamp");
+        self.depth_clamp = Some(enabled);
+    }
+
+    /// Apply a bundle of pipeline state, only issuing the GL calls for the
+    /// parts that changed since the last applied one.
+    pub fn apply_pipeline_state(&mut self, state: &state::PipelineState) {
+        self.sync_state_cache();
+        state::bind_pipeline_state(&self.gl, self.pipeline_state.as_ref(), state);
+        self.check_errors("apply_pipeline_state");
+        let blend = state.blend.is_some();
+        for b in self.blend_enabled.iter_mut() {
+            *b = Some(blend);
+        }
+        self.pipeline_state = Some(*state);
+    }
+
+    /// Check if blending is enabled on a color attachment.
+    pub fn is_blend_enabled(&self, attachment: u32) -> bool {
+        let cached = if self.state_changed.get() {
+            None
+        } else {
+            self.blend_enabled.get(attachment as usize).and_then(|&b| b)
         };
+        match cached {
+            Some(enabled) => enabled,
+            _ => {
+                let index = if self.private_caps.draw_buffers_blend_supported {
+                    Some(attachment)
+                } else {
+                    None
+                };
+                let enabled = state::is_enabled(&self.gl, gl::BLEND, index);
+                self.check_errors("is_blend_enabled");
+                enabled
+            },
+        }
+    }
+
+    /// Check if the depth test is enabled.
+    pub fn is_depth_test_enabled(&self) -> bool {
+        match self.cached_pipeline_state() {
+            Some(ps) => ps.depth.is_some(),
+            None => {
+                let enabled = state::is_enabled(&self.gl, gl::DEPTH_TEST, None);
+                self.check_errors("is_depth_test_enabled");
+                enabled
+            },
+        }
+    }
+
+    /// Check if the stencil test is enabled.
+    pub fn is_stencil_test_enabled(&self) -> bool {
+        match self.cached_pipeline_state() {
+            Some(ps) => ps.stencil.is_some(),
+            None => {
+                let enabled = state::is_enabled(&self.gl, gl::STENCIL_TEST, None);
+                self.check_errors("is_stencil_test_enabled");
+                enabled
+            },
+        }
+    }
+
+    /// Get the faces that are currently culled.
+    pub fn current_cull_mode(&self) -> d::state::CullFace {
+        match self.cached_pipeline_state() {
+            Some(ps) => state::get_cull_face(&ps.primitive),
+            None => {
+                let mode = state::query_cull_face(&self.gl);
+                self.check_errors("current_cull_mode");
+                mode
+            },
+        }
+    }
+
+    /// Get the current winding of front faces.
+    pub fn current_front_face(&self) -> d::state::FrontFace {
+        match self.cached_pipeline_state() {
+            Some(ps) => ps.primitive.front_face,
+            None => {
+                let mode = state::query_front_face(&self.gl);
+                self.check_errors("current_front_face");
+                mode
+            },
+        }
+    }
+
+    /// Forget the cached pipeline state, blend and depth clamp toggles and
+    /// pixel store. The factory skips redundant state changes, so this has
+    /// to be called after the state was changed by other means, such as
+    /// direct GL calls. The device sharing the context of the factory makes
+    /// it forget the cache by itself when it submits commands or resets the
+    /// state.
+    pub fn forget_state_cache(&mut self) {
+        for b in self.blend_enabled.iter_mut() {
+            *b = None;
+        }
+        self.depth_clamp = None;
+        self.pipeline_state = None;
+        self.pixel_store = None;
+        self.state_changed.set(false);
+    }
+
+    /// Forget the cached state if the device changed it since.
+    fn sync_state_cache(&mut self) {
+        if self.state_changed.get() {
+            self.forget_state_cache();
+        }
+    }
+
+    /// Get the pipeline state last applied, if the device didn't change the
+    /// state since.
+    fn cached_pipeline_state(&self) -> Option<&state::PipelineState> {
+        if self.state_changed.get() {
+            None
+        } else {
+            self.pipeline_state.as_ref()
+        }
+    }
+
+    /// Force the fragment shader to run for at least the given fraction of
+    /// samples of a multi-sampled target, or go back to per-pixel shading
+    /// with `None`.
+    pub fn set_min_sample_shading(&mut self, fraction: Option<f32>) {
+        if !self.private_caps.sample_shading_supported {
+            error!("Sample shading is not supported, ignored");
+            return
+        }
+        match fraction {
+            Some(value) => unsafe {
+                self.gl.Enable(gl::SAMPLE_SHADING);
+                self.gl.MinSampleShading(value.max(0.0).min(1.0));
+            },
+            None => unsafe { self.gl.Disable(gl::SAMPLE_SHADING) },
+        }
+        self.check_errors("set_min_sample_shading");
+    }
+
+    fn check_sync(&self) -> Result<(), FenceError> {
+        if self.private_caps.sync_supported {
+            Ok(())
+        } else {
+            error!("Fences are not supported, ARB_sync is required");
+            Err(FenceError::Unsupported)
+        }
+    }
+
+    /// Insert a fence into the command stream of this context.
+    pub fn insert_fence(&mut self) -> Result<fence::Fence, FenceError> {
+        try!(self.check_sync());
+        let f = fence::make_fence(&self.gl);
+        self.check_errors("insert_fence");
+        Ok(f)
+    }
+
+    /// Block the CPU until the fence is signaled, or the timeout expires.
+    /// Returns true if the fence was signaled.
+    pub fn client_wait_fence(&mut self, f: &fence::Fence, timeout_ns: u64)
+                             -> Result<bool, FenceError> {
+        try!(self.check_sync());
+        let signaled = fence::client_wait(&self.gl, *f, timeout_ns);
+        self.check_errors("client_wait_fence");
+        Ok(signaled)
+    }
+
+    /// Make the GPU wait for a fence, typically inserted by another context
+    /// of the share group, before executing the following commands. Unlike
+    /// `client_wait_fence` this doesn't block the CPU.
+    pub fn gpu_wait_fence(&mut self, f: &fence::Fence) -> Result<(), FenceError> {
+        try!(self.check_sync());
+        fence::gpu_wait(&self.gl, *f);
+        self.check_errors("gpu_wait_fence");
+        Ok(())
+    }
+
+    /// Delete a fence. Pending waits on it are not affected.
+    pub fn delete_fence(&mut self, f: fence::Fence) {
+        fence::delete_fence(&self.gl, f);
+        self.check_errors("delete_fence");
+    }
+
+    /// Give a fence a name shown by GL debuggers, if `KHR_debug` is
+    /// supported. Sync objects are pointers rather than names, so they need
+    /// `ObjectPtrLabel`.
+    pub fn set_fence_label(&mut self, f: &fence::Fence, label: &str) {
+        if !self.private_caps.debug_label_supported {
+            return
+        }
         unsafe {
-            self.gl.BufferData(target,
-                info.size as gl::types::GLsizeiptr,
-                0 as *const gl::types::GLvoid,
-                usage
-            );
+            self.gl.ObjectPtrLabel(f.0 as *const gl::types::GLvoid,
+                                   label.len() as gl::types::GLsizei,
+                                   label.as_ptr() as *const gl::types::GLchar);
         }
+        self.check_errors("set_fence_label");
     }
 
-    pub fn get_main_frame_buffer(&self) -> handle::FrameBuffer<R> {
-        self.main_fbo.clone()
+    /// Get the number of samples of the default frame buffer, 0 if it isn't
+    /// multi-sampled. The sample count is chosen at context creation, so it
+    /// can't be changed here.
+    pub fn default_framebuffer_samples(&self) -> u32 {
+        let mut bound = 0 as gl::types::GLint;
+        let mut samples = 0 as gl::types::GLint;
+        unsafe {
+            self.gl.GetIntegerv(gl::DRAW_FRAMEBUFFER_BINDING, &mut bound);
+            self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0);
+            self.gl.GetIntegerv(gl::SAMPLES, &mut samples);
+            self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, bound as gl::types::GLuint);
+        }
+        self.check_errors("default_framebuffer_samples");
+        samples as u32
     }
 
-    pub fn make_fake_output(&self, w: Size, h: Size) -> Output {
-        Output {
-            width: w,
-            height: h,
-            handle: self.main_fbo.clone(),
+    /// Get the position of a sample within a pixel of the currently bound
+    /// draw frame buffer, in the `[0, 1]` range. Returns `None` if sample
+    /// positions can't be queried, or the index is out of range.
+    pub fn get_sample_position(&self, index: u32) -> Option<[f32; 2]> {
+        if !self.private_caps.sample_positions_supported {
+            error!("Sample position queries are not supported");
+            return None
+        }
+        let mut samples = 0 as gl::types::GLint;
+        unsafe { self.gl.GetIntegerv(gl::SAMPLES, &mut samples) };
+        if index >= samples as u32 {
+            error!("Sample index {} is out of range, the frame buffer has {} samples",
+                   index, samples);
+            return None
+        }
+        let mut position = [0.0; 2];
+        unsafe {
+            self.gl.GetMultisamplefv(gl::SAMPLE_POSITION,
+                index as gl::types::GLuint, position.as_mut_ptr());
+        }
+        self.check_errors("get_sample_position");
+        Some(position)
+    }
+
+    /// Get the metadata to store alongside program binaries retrieved
+    /// from this driver.
+    pub fn get_binary_metadata(&self) -> &BinaryMetadata {
+        &self.binary_metadata
+    }
+
+    /// Check that a program binary with the given metadata can be loaded
+    /// by the current driver.
+    pub fn validate_binary_metadata(&self, meta: &BinaryMetadata)
+                                    -> Result<(), BinaryStale> {
+        let result = ::shade::check_binary_metadata(&self.binary_metadata, meta);
+        if result.is_err() {
+            info!("\tStale program binary from {:?}", meta);
+        }
+        result
+    }
+
+    /// Get the binary of a program and its driver-specific format, to be
+    /// cached along with `get_binary_metadata` and reloaded with
+    /// `create_program_from_binary`. Returns `None` without
+    /// `ARB_get_program_binary`.
+    pub fn get_program_binary(&mut self, program: &handle::Program<R>)
+                              -> Option<(gl::types::GLenum, Vec<u8>)> {
+        if !self.private_caps.program_binary_supported {
+            return None
+        }
+        let name = self.frame_handles.ref_program(program);
+        let binary = ::shade::get_program_binary(&self.gl, name);
+        self.check_errors("get_program_binary");
+        binary
+    }
+
+    /// Create a program from a binary returned by `get_program_binary`,
+    /// along with the metadata stored with it. Binaries of another driver
+    /// fail with `Stale` without being given to GL. The driver can still
+    /// reject binaries with valid metadata, so a `LinkFailed` error also
+    /// means the program has to be linked from source again.
+    pub fn create_program_from_binary(&mut self, format: gl::types::GLenum, data: &[u8],
+                                      meta: &BinaryMetadata)
+                                      -> Result<handle::Program<R>, CreateProgramError> {
+        trace_call!(self, "create_program_from_binary({:x}, {} bytes)", format, data.len());
+        let label = self.next_label.take();
+        if !self.private_caps.program_binary_supported {
+            return Err(CreateProgramError::BinaryUnsupported)
+        }
+        if let Err(stale) = self.validate_binary_metadata(meta) {
+            return Err(CreateProgramError::Stale(stale))
+        }
+        let (prog, log) = ::shade::create_program_from_binary(&self.gl, &self.caps, format, data);
+        self.check_errors("create_program_from_binary");
+        match prog {
+            Ok((name, info)) => {
+                self.apply_label(gl::PROGRAM, name, label);
+                if self.cache_block_layouts && !info.blocks.is_empty() {
+                    let layouts = ::shade::query_block_layouts(&self.gl, &self.caps, name);
+                    self.shared().block_layouts.insert(name, layouts);
+                }
+                Ok(self.shared().manager.make_program(name, info))
+            },
+            Err(()) => {
+                info!("\tProgram binary rejected: {:?}", log);
+                Err(CreateProgramError::LinkFailed(log.unwrap_or(String::new())))
+            },
+        }
+    }
+
+    /// Select which color attachment each fragment output is written to.
+    /// Outputs mapped to `None` are discarded.
+    pub fn set_draw_buffers(&mut self, targets: &[Option<u32>])
+                            -> Result<(), state::DrawBuffersError> {
+        try!(state::check_draw_buffers(targets, self.caps.max_draw_buffers));
+        state::bind_draw_buffers(&self.gl, targets);
+        self.check_errors("set_draw_buffers");
+        Ok(())
+    }
+
+    /// Check that the fragment outputs of a program can be written to
+    /// color attachments of the given formats, indexed by attachment. Integer
+    /// attachments need integer outputs, anything else is undefined.
+    /// Returns `false` and warns about each mismatch.
+    pub fn check_output_formats(&mut self, program: &handle::Program<R>,
+                                attachments: &[Option<d::tex::Format>]) -> bool {
+        if !self.private_caps.program_interface_supported {
+            debug!("Program interface queries are not supported, skipping output check");
+            return true
+        }
+        let name = self.frame_handles.ref_program(program);
+        let mut matching = true;
+        for (out_name, loc, base) in ::shade::query_outputs(&self.gl, name) {
+            let format = match attachments.get(loc as usize) {
+                Some(&Some(format)) => format,
+                _ => continue,
+            };
+            let expected = tex::format_to_base_type(format);
+            if base != expected {
+                warn!("Output {:?} of program {} is {:?}, but attachment {} of {:?} expects {:?}",
+                      out_name, self.describe_object(gl::PROGRAM, name), base, loc, format,
+                      expected);
+                matching = false;
+            }
+        }
+        self.check_errors("check_output_formats");
+        matching
+    }
+
+    /// Check if a texture can be sampled with the given sampler, which fails
+    /// when the sampler filter needs mipmaps that were never generated.
+    pub fn is_texture_complete(&mut self, texture: &handle::Texture<R>,
+                               sampler: &handle::Sampler<R>) -> bool {
+        let name = self.frame_handles.ref_texture(texture);
+        self.select_free_unit();
+        let complete = tex::is_complete(&self.gl, texture.get_info(), name,
+                                        sampler.get_info().filtering);
+        self.check_errors("is_texture_complete");
+        if !complete {
+            warn!("Texture {} is incomplete for filter {:?}, did you forget to generate mipmaps?",
+                  name, sampler.get_info().filtering);
+        }
+        complete
+    }
+
+    /// Choose whether sampling a packed depth-stencil texture returns the
+    /// depth or the stencil component.
+    pub fn set_depth_stencil_read_mode(&mut self, texture: &handle::Texture<R>,
+                                       mode: tex::DepthStencilMode) {
+        if !self.private_caps.stencil_texturing_supported {
+            error!("Stencil texturing is not supported, ignored");
+            return
+        }
+        let info = *texture.get_info();
+        match info.format {
+            d::tex::Format::DEPTH24_STENCIL8 | d::tex::Format::DEPTH32F_STENCIL8 => (),
+            format => {
+                error!("Texture format {:?} is not depth-stencil, ignored", format);
+                return
+            },
+        }
+        let name = self.frame_handles.ref_texture(texture);
+        self.select_free_unit();
+        tex::set_depth_stencil_mode(&self.gl, info.kind, name, mode);
+        self.check_errors("set_depth_stencil_read_mode");
+    }
+
+    /// Choose where each channel sampled from a texture comes from, for
+    /// example `[Red, Red, Red, Green]` to sample a two-channel texture as
+    /// gray with alpha. Textures start with `SWIZZLE_IDENTITY`.
+    pub fn set_texture_swizzle(&mut self, texture: &handle::Texture<R>,
+                               swizzle: [tex::Swizzle; 4]) {
+        if !self.private_caps.texture_swizzle_supported {
+            error!("Texture swizzle is not supported, ignored");
+            return
+        }
+        let kind = texture.get_info().kind;
+        let name = self.frame_handles.ref_texture(texture);
+        self.select_free_unit();
+        tex::set_swizzle(&self.gl, kind, name, swizzle);
+        self.check_errors("set_texture_swizzle");
+    }
+
+    /// Get a sampler object that can be modified without affecting other
+    /// handles. Samplers created with identical infos share their object:
+    /// if another handle uses it, a new object is made from the same info,
+    /// otherwise the object of the handle is taken out of the cache so that
+    /// it isn't shared anymore.
+    fn make_sampler_unique(&mut self, sampler: &handle::Sampler<R>) -> handle::Sampler<R> {
+        let name = self.frame_handles.ref_sampler(sampler);
+        let count = self.shared().sampler_refs.get(&name).cloned().unwrap_or(1);
+        if count <= 1 {
+            self.shared().sampler_cache.retain(|&(_, s)| s != name);
+            return sampler.clone()
+        }
+        // objects shared by several handles are never modified, so the info
+        // is all there is to copy
+        let info = *sampler.get_info();
+        let sam = tex::make_sampler(&self.gl, &info, self.private_caps.max_lod_bias,
+                                    self.private_caps.max_anisotropy);
+        info!("\tCopied sampler {} to {}, as it is shared by {} handles",
+              self.describe_object(gl::SAMPLER, name), sam, count);
+        self.shared().sampler_refs.insert(sam, 1);
+        self.shared().manager.make_sampler(sam, info)
+    }
+
+    /// Choose whether sampling an sRGB texture converts it to linear space,
+    /// for sRGB data that isn't really color. The setting goes to the
+    /// sampler object, or to the texture itself when samplers are emulated,
+    /// in which case it applies to every use of the texture.
+    ///
+    /// Returns the sampler to use from now on: the given one, or a copy if
+    /// its object is shared with other samplers created with the same info,
+    /// which keep sampling as before.
+    pub fn set_srgb_decode(&mut self, texture: &handle::Texture<R>,
+                           sampler: &handle::Sampler<R>, decode: bool)
+                           -> handle::Sampler<R> {
+        if !self.private_caps.srgb_decode_control_supported {
+            error!("sRGB decode control is not supported, ignored");
+            return sampler.clone()
+        }
+        if self.caps.sampler_objects_supported {
+            let sampler = self.make_sampler_unique(sampler);
+            let name = self.frame_handles.ref_sampler(&sampler);
+            tex::set_sampler_srgb_decode(&self.gl, name, decode);
+            self.check_errors("set_srgb_decode");
+            sampler
+        } else {
+            let kind = texture.get_info().kind;
+            let name = self.frame_handles.ref_texture(texture);
+            self.select_free_unit();
+            tex::set_texture_srgb_decode(&self.gl, kind, name, decode);
+            self.check_errors("set_srgb_decode");
+            sampler.clone()
+        }
+    }
+
+    /// Make the clamped axes of a sampler read a border of the given color
+    /// outside of the texture, instead of its edge texels, such as a white
+    /// border for shadow maps. Not supported with emulated samplers.
+    ///
+    /// Returns the sampler to use from now on, like `set_srgb_decode`.
+    pub fn set_sampler_border(&mut self, sampler: &handle::Sampler<R>, color: [f32; 4])
+                              -> handle::Sampler<R> {
+        if !self.caps.sampler_objects_supported {
+            error!("Sampler borders are not supported with emulated samplers, ignored");
+            return sampler.clone()
+        }
+        let sampler = self.make_sampler_unique(sampler);
+        let name = self.frame_handles.ref_sampler(&sampler);
+        tex::set_sampler_border(&self.gl, name, sampler.get_info(), color);
+        self.check_errors("set_sampler_border");
+        sampler
+    }
+
+    /// Compute the range of mip levels that are going to be sampled when
+    /// combining the given texture and sampler.
+    pub fn effective_lod_range(&self, texture: &handle::Texture<R>,
+                               sampler: &handle::Sampler<R>) -> (f32, f32) {
+        let levels = texture.get_info().levels;
+        let range = tex::effective_lod_range((0, levels - 1),
+                                             sampler.get_info().lod_range);
+        if range.0 > range.1 {
+            warn!("Sampler LOD range {:?} doesn't overlap the {} levels of the texture, nothing will be sampled",
+                  sampler.get_info().lod_range, levels);
         }
+        range
     }
 }
 
@@ -136,19 +3027,54 @@ impl Factory {
 #[derive(Copy, Clone)]
 pub struct RawMapping {
     pub pointer: *mut libc::c_void,
+    buffer: Buffer,
     target: gl::types::GLenum,
+    access: d::MapAccess,
+    offset: usize,
+    persistent: bool,
+}
+
+impl RawMapping {
+    /// Get the offset in the buffer of the mapped region, which `pointer`
+    /// points to the start of.
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Check if the mapping stays valid while the buffer is used by the GPU,
+    /// as created by `create_persistent_buffer_raw`.
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    /// Fails during a debug build if the mapping doesn't allow the access:
+    /// reading a write-only mapping is undefined on some drivers.
+    fn check_access(&self, read: bool, write: bool) {
+        if cfg!(not(ndebug)) {
+            match self.access {
+                d::MapAccess::Readable if write =>
+                    panic!("Tried to write through a read-only buffer mapping"),
+                d::MapAccess::Writable if read =>
+                    panic!("Tried to read through a write-only buffer mapping"),
+                _ => (),
+            }
+        }
+    }
 }
 
 impl d::mapping::Raw for RawMapping {
     unsafe fn set<T>(&self, index: usize, val: T) {
+        self.check_access(false, true);
         *(self.pointer as *mut T).offset(index as isize) = val;
     }
 
     unsafe fn to_slice<T>(&self, len: usize) -> &[T] {
+        self.check_access(true, false);
         slice::from_raw_parts(self.pointer as *const T, len)
     }
 
     unsafe fn to_mut_slice<T>(&self, len: usize) -> &mut [T] {
+        self.check_access(true, true);
         slice::from_raw_parts_mut(self.pointer as *mut T, len)
     }
 }
@@ -159,19 +3085,17 @@ impl d::Factory<R> for Factory {
 
     fn create_buffer_raw(&mut self, size: usize, usage: d::BufferUsage)
                          -> handle::RawBuffer<R> {
-        let name = self.create_buffer_internal();
-        let info = d::BufferInfo {
-            role: d::BufferRole::Vertex,
-            usage: usage,
-            size: size,
-        };
-        self.init_buffer(name, &info);
-        self.handles.make_buffer(name, info)
+        // the trait gives no role for uninitialized buffers, and gfx only
+        // creates vertex buffers this way; index buffers have to go through
+        // `create_buffer_raw_with_role`
+        self.create_buffer_raw_with_role(size, usage, d::BufferRole::Vertex)
     }
 
     fn create_buffer_static_raw(&mut self, data: &[u8], role: d::BufferRole)
                                 -> handle::RawBuffer<R> {
-        let name = self.create_buffer_internal();
+        trace_call!(self, "create_buffer_static_raw({} bytes, {:?})", data.len(), role);
+        let label = self.next_label.take();
+        let name = self.create_buffer_internal(role_to_gl(role), label);
 
         let info = d::BufferInfo {
             role: role,
@@ -180,17 +3104,21 @@ impl d::Factory<R> for Factory {
         };
         self.init_buffer(name, &info);
         update_sub_buffer(&self.gl, name, data.as_ptr(), data.len(), 0, role);
-        self.handles.make_buffer(name, info)
+        self.check_errors("create_buffer_static_raw");
+        self.shared().manager.make_buffer(name, info)
     }
 
     fn create_array_buffer(&mut self) -> Result<handle::ArrayBuffer<R>, ()> {
+        let label = self.next_label.take();
         if self.caps.array_buffer_supported {
             let mut name = 0 as ::ArrayBuffer;
             unsafe {
                 self.gl.GenVertexArrays(1, &mut name);
             }
-            info!("\tCreated array buffer {}", name);
-            Ok(self.handles.make_array_buffer(name))
+            self.apply_label(gl::VERTEX_ARRAY, name, label);
+            info!("\tCreated array buffer {}", self.describe_object(gl::VERTEX_ARRAY, name));
+            self.check_errors("create_array_buffer");
+            Ok(self.containers.make_array_buffer(name))
         } else {
             error!("\tarray buffer creation unsupported, ignored");
             Err(())
@@ -199,27 +3127,28 @@ impl d::Factory<R> for Factory {
 
     fn create_shader(&mut self, stage: d::shade::Stage, code: &[u8])
                      -> Result<handle::Shader<R>, d::shade::CreateShaderError> {
+        trace_call!(self, "create_shader({:?}, {} bytes)", stage, code.len());
+        let label = self.next_label.take();
+        if stage == d::shade::Stage::Geometry && !self.private_caps.geometry_shader_supported {
+            error!("Geometry shaders are not supported");
+            return Err(d::shade::CreateShaderError::StageNotSupported(stage))
+        }
         let (name, info) = ::shade::create_shader(&self.gl, stage, code);
         info.map(|info| {
             let level = if name.is_err() { LogLevel::Error } else { LogLevel::Warn };
             log!(level, "\tShader compile log: {}", info);
         });
-        name.map(|sh| self.handles.make_shader(sh, stage))
+        self.check_errors("create_shader");
+        name.map(|sh| {
+            self.apply_label(gl::SHADER, sh, label);
+            self.shared().manager.make_shader(sh, stage)
+        })
     }
 
     fn create_program(&mut self, shaders: &[handle::Shader<R>],
                       targets: Option<&[&str]>)
                       -> Result<handle::Program<R>, ()> {
-        let objects: Vec<::Shader> = shaders.iter()
-            .map(|h| self.frame_handles.ref_shader(h))
-            .collect();
-        let (prog, log) = ::shade::create_program(&self.gl, &self.caps,
-                                                  objects.into_iter(), targets);
-        log.map(|log| {
-            let level = if prog.is_err() { LogLevel::Error } else { LogLevel::Warn };
-            log!(level, "\tProgram link log: {}", log);
-        });
-        prog.map(|(name, info)| self.handles.make_program(name, info))
+        self.try_create_program(shaders, targets).map_err(|_| ())
     }
 
     fn create_frame_buffer(&mut self) -> handle::FrameBuffer<R> {
@@ -227,124 +3156,391 @@ impl d::Factory<R> for Factory {
             panic!("No framebuffer objects, can't make a new one!");
         }
 
+        let label = self.next_label.take();
         let mut name = 0 as ::FrameBuffer;
         unsafe {
             self.gl.GenFramebuffers(1, &mut name);
         }
-        info!("\tCreated frame buffer {}", name);
-        self.handles.make_frame_buffer(name)
+        self.apply_label(gl::FRAMEBUFFER, name, label);
+        info!("\tCreated frame buffer {}", self.describe_object(gl::FRAMEBUFFER, name));
+        self.check_errors("create_frame_buffer");
+        self.containers.make_frame_buffer(name)
     }
 
     fn create_surface(&mut self, info: d::tex::SurfaceInfo) ->
                       Result<handle::Surface<R>, d::tex::SurfaceError> {
+        trace_call!(self, "create_surface({:?})", info);
+        let label = self.next_label.take();
         if info.format.does_convert_gamma() && !self.caps.srgb_color_supported {
             return Err(d::tex::SurfaceError::UnsupportedGamma)
         }
-        tex::make_surface(&self.gl, &info)
-            .map(|suf| self.handles.make_surface(suf, info))
+        if !self.is_color_renderable(info.format) {
+            error!("Floating-point surfaces of {:?} are not renderable", info.format);
+            return Err(d::tex::SurfaceError::UnsupportedFormat)
+        }
+        let mut info = info;
+        if let Some(d::tex::AaMode::Msaa(samples)) = info.aa_mode {
+            match self.resolve_sample_count(gl::RENDERBUFFER, info.format, samples) {
+                Some(s) => info.aa_mode = Some(d::tex::AaMode::Msaa(s)),
+                None => return Err(d::tex::SurfaceError::UnsupportedFormat),
+            }
+        }
+        let suf = try!(tex::make_surface(&self.gl, &info));
+        self.check_errors("create_surface");
+        self.apply_label(gl::RENDERBUFFER, suf, label);
+        Ok(self.shared().manager.make_surface(suf, info))
     }
 
     fn create_texture(&mut self, info: d::tex::TextureInfo) ->
                       Result<handle::Texture<R>, d::tex::TextureError> {
-        if info.width == 0 || info.height == 0 || info.levels == 0 {
+        trace_call!(self, "create_texture({:?})", info);
+        let label = self.next_label.take();
+        if tex::is_empty(&info) {
             return Err(d::tex::TextureError::InvalidInfo(info))
         }
         if info.format.does_convert_gamma() && !self.caps.srgb_color_supported {
             return Err(d::tex::TextureError::UnsupportedGamma)
         }
+        if !tex::is_compression_supported(info.format, &self.compressed_formats) {
+            error!("Compressed format {:?} is not supported by the driver", info.format);
+            return Err(d::tex::TextureError::UnsupportedFormat)
+        }
+        let mut info = info;
+        match info.kind {
+            d::tex::TextureKind::Texture2DMultiSample(d::tex::AaMode::Msaa(samples)) => {
+                match self.resolve_sample_count(gl::TEXTURE_2D_MULTISAMPLE, info.format, samples) {
+                    Some(s) => info.kind = d::tex::TextureKind::Texture2DMultiSample(
+                        d::tex::AaMode::Msaa(s)),
+                    None => return Err(d::tex::TextureError::UnsupportedSampling),
+                }
+            },
+            d::tex::TextureKind::Texture2DMultiSampleArray(d::tex::AaMode::Msaa(samples)) => {
+                match self.resolve_sample_count(gl::TEXTURE_2D_MULTISAMPLE_ARRAY, info.format, samples) {
+                    Some(s) => info.kind = d::tex::TextureKind::Texture2DMultiSampleArray(
+                        d::tex::AaMode::Msaa(s)),
+                    None => return Err(d::tex::TextureError::UnsupportedSampling),
+                }
+            },
+            _ => (),
+        }
 
+        self.select_free_unit();
         let name = if self.caps.immutable_storage_supported {
-            tex::make_with_storage(&self.gl, &info)
+            try!(tex::make_with_storage(&self.gl, &info))
         } else {
-            tex::make_without_storage(&self.gl, &info)
+            try!(tex::make_without_storage(&self.gl, &info))
         };
-        name.map(|tex| self.handles.make_texture(tex, info))
+        self.check_errors("create_texture");
+        self.apply_label(gl::TEXTURE, name, label);
+        if info.levels > 1 && !self.auto_mipmap {
+            warn!("Levels 1 to {} of texture {} are undefined until uploaded or generated",
+                  info.levels - 1, self.describe_object(gl::TEXTURE, name));
+        }
+        Ok(self.shared().manager.make_texture(name, info))
     }
 
     fn create_sampler(&mut self, info: d::tex::SamplerInfo)
                       -> handle::Sampler<R> {
-        let sam = if self.caps.sampler_objects_supported {
-            tex::make_sampler(&self.gl, &info)
-        } else {
-            0
+        trace_call!(self, "create_sampler({:?})", info);
+        let label = self.next_label.take();
+        tex::warn_anisotropy(info.filtering, self.private_caps.max_anisotropy);
+        let sam = {
+            let mut shared = self.shared();
+            // a label would rename the object of the other samplers
+            let cached = match label {
+                Some(_) => None,
+                None => shared.sampler_cache.iter().find(|&&(ref i, _)| *i == info)
+                                                   .map(|&(_, s)| s),
+            };
+            match cached {
+                Some(sam) => {
+                    *shared.sampler_refs.get_mut(&sam).unwrap() += 1;
+                    sam
+                },
+                None if self.caps.sampler_objects_supported => {
+                    let sam = tex::make_sampler(&self.gl, &info, self.private_caps.max_lod_bias,
+                                                self.private_caps.max_anisotropy);
+                    if label.is_none() {
+                        shared.sampler_cache.push((info, sam));
+                    }
+                    shared.sampler_refs.insert(sam, 1);
+                    sam
+                },
+                None => 0,
+            }
         };
-        self.handles.make_sampler(sam, info)
+        self.check_errors("create_sampler");
+        self.apply_label(gl::SAMPLER, sam, label);
+        self.shared().manager.make_sampler(sam, info)
     }
 
     fn update_buffer_raw(&mut self, buffer: &handle::RawBuffer<R>,
                          data: &[u8], offset_bytes: usize) {
-        debug_assert!(offset_bytes + data.len() <= buffer.get_info().size);
-        let raw_handle = self.frame_handles.ref_buffer(buffer);
-        update_sub_buffer(&self.gl, raw_handle, data.as_ptr(), data.len(),
-                          offset_bytes, buffer.get_info().role)
+        match self.try_update_buffer_raw(buffer, data, offset_bytes) {
+            Ok(()) => (),
+            Err(e) => {
+                let name = self.frame_handles.ref_buffer(buffer);
+                panic!("Unable to update buffer {}: {:?}, it was created without dynamic storage",
+                       self.describe_object(gl::BUFFER, name), e)
+            },
+        }
     }
 
     fn update_texture_raw(&mut self, texture: &handle::Texture<R>,
                           img: &d::tex::ImageInfo, data: &[u8],
                           optkind: Option<d::tex::TextureKind>)
                           -> Result<(), d::tex::TextureError> {
+        trace_call!(self, "update_texture_raw({:?}, {:?}, {} bytes)", texture.get_info(), img, data.len());
 
-        // use the specified texture kind if set for this update, otherwise
-        // fall back on the kind that was set when the texture was created.
-        let kind = optkind.unwrap_or(texture.get_info().kind);
-
-        tex::update_texture(&self.gl, kind,
-                            self.frame_handles.ref_texture(texture),
-                            img, data.as_ptr(), data.len())
+        self.update_texture_internal(texture, img, data.as_ptr(), data.len(), optkind)
     }
 
     fn generate_mipmap(&mut self, texture: &handle::Texture<R>) {
+        trace_call!(self, "generate_mipmap({:?})", texture.get_info());
+        self.select_free_unit();
         tex::generate_mipmap(&self.gl, texture.get_info().kind,
                              self.frame_handles.ref_texture(texture));
+        self.check_errors("generate_mipmap");
     }
 
     fn map_buffer_raw(&mut self, buf: &handle::RawBuffer<R>,
                       access: d::MapAccess) -> RawMapping {
+        trace_call!(self, "map_buffer_raw({:?}, {:?})", buf.get_info(), access);
         let raw_handle = self.frame_handles.ref_buffer(buf);
-        unsafe { self.gl.BindBuffer(gl::ARRAY_BUFFER, raw_handle) };
-        let ptr = unsafe { self.gl.MapBuffer(gl::ARRAY_BUFFER, match access {
+        debug_assert!(!self.shared().mapped_buffers.contains(&raw_handle),
+                      "Buffer {} is mapped already", raw_handle);
+        let target = role_to_gl(buf.get_info().role);
+        unsafe { self.gl.BindBuffer(target, raw_handle) };
+        let ptr = unsafe { self.gl.MapBuffer(target, match access {
             d::MapAccess::Readable => gl::READ_ONLY,
             d::MapAccess::Writable => gl::WRITE_ONLY,
             d::MapAccess::RW => gl::READ_WRITE
         }) } as *mut libc::c_void;
+        self.check_errors("map_buffer_raw");
+        self.shared().mapped_buffers.insert(raw_handle);
         RawMapping {
             pointer: ptr,
-            target: gl::ARRAY_BUFFER
+            buffer: raw_handle,
+            target: target,
+            access: access,
+            offset: 0,
+            persistent: false,
         }
     }
 
     fn unmap_buffer_raw(&mut self, map: RawMapping) {
-        unsafe { self.gl.UnmapBuffer(map.target) };
+        trace_call!(self, "unmap_buffer_raw()");
+        if map.persistent {
+            error!("Persistent mappings are released with their buffer, unmap ignored");
+            return
+        }
+        unsafe {
+            self.gl.BindBuffer(map.target, map.buffer);
+            self.gl.UnmapBuffer(map.target);
+        }
+        self.shared().mapped_buffers.remove(&map.buffer);
+        self.check_errors("unmap_buffer_raw");
     }
 
     fn map_buffer_readable<T: Copy>(&mut self, buf: &handle::Buffer<R, T>)
                            -> d::mapping::Readable<T, R, Factory> {
+        let len = self.mapped_len(buf);
         let map = self.map_buffer_raw(buf.raw(), d::MapAccess::Readable);
-        self.map_readable(map, buf.len())
+        self.map_readable(map, len)
     }
 
     fn map_buffer_writable<T: Copy>(&mut self, buf: &handle::Buffer<R, T>)
                                     -> d::mapping::Writable<T, R, Factory> {
+        let len = self.mapped_len(buf);
         let map = self.map_buffer_raw(buf.raw(), d::MapAccess::Writable);
-        self.map_writable(map, buf.len())
+        self.map_writable(map, len)
     }
 
     fn map_buffer_rw<T: Copy>(&mut self, buf: &handle::Buffer<R, T>)
                               -> d::mapping::RW<T, R, Factory> {
+        let len = self.mapped_len(buf);
         let map = self.map_buffer_raw(buf.raw(), d::MapAccess::RW);
-        self.map_read_write(map, buf.len())
+        self.map_read_write(map, len)
     }
 
     fn cleanup(&mut self) {
-        self.handles.clean_with(&mut self.gl,
-            |gl, v| unsafe { gl.DeleteBuffers(1, v) },
-            |gl, v| unsafe { gl.DeleteVertexArrays(1, v) },
-            |gl, v| unsafe { gl.DeleteShader(*v) },
-            |gl, v| unsafe { gl.DeleteProgram(*v) },
-            |gl, v| unsafe { gl.DeleteFramebuffers(1, v) },
-            |gl, v| unsafe { gl.DeleteRenderbuffers(1, v) },
-            |gl, v| unsafe { gl.DeleteTextures(1, v) },
-            |gl, v| unsafe { gl.DeleteSamplers(1, v) });
+        trace_call!(self, "cleanup()");
+        {
+            let mut guard = self.handles.lock().unwrap();
+            let shared = &mut *guard;
+            let mut context = Cleanup {
+                gl: &self.gl,
+                buffers: Vec::new(),
+                array_buffers: Vec::new(),
+                frame_buffers: Vec::new(),
+                surfaces: Vec::new(),
+                textures: Vec::new(),
+                samplers: Vec::new(),
+                immutable_buffers: &mut shared.immutable_buffers,
+                static_updates: &mut shared.static_updates,
+                buffer_sizes: &mut shared.buffer_sizes,
+                mapped_buffers: &mut shared.mapped_buffers,
+                uploaded_layers: &mut shared.uploaded_layers,
+                block_layouts: &mut shared.block_layouts,
+                tess_shaders: &mut shared.tess_shaders,
+                sampler_cache: &mut shared.sampler_cache,
+                sampler_refs: &mut shared.sampler_refs,
+                labels: &mut shared.labels,
+                container_labels: &mut self.container_labels,
+            };
+            // the containers of other contexts are not in the shared manager
+            shared.manager.clean_with(&mut context,
+                |c, v| {
+                    c.immutable_buffers.remove(v);
+                    c.static_updates.remove(v);
+                    c.buffer_sizes.remove(v);
+                    c.mapped_buffers.remove(v);
+                    c.labels.remove(&(gl::BUFFER, *v));
+                    c.buffers.push(*v);
+                },
+                |_, _| (),
+                |c, v| {
+                    c.tess_shaders.remove(v);
+                    c.labels.remove(&(gl::SHADER, *v));
+                    unsafe { c.gl.DeleteShader(*v) }
+                },
+                |c, v| {
+                    c.block_layouts.remove(v);
+                    c.labels.remove(&(gl::PROGRAM, *v));
+                    unsafe { c.gl.DeleteProgram(*v) }
+                },
+                |_, _| (),
+                |c, v| {
+                    c.labels.remove(&(gl::RENDERBUFFER, *v));
+                    c.surfaces.push(*v);
+                },
+                |c, v| {
+                    c.uploaded_layers.remove(v);
+                    c.labels.remove(&(gl::TEXTURE, *v));
+                    c.textures.push(*v);
+                },
+                |c, v| {
+                    if release_sampler(c.sampler_refs, c.sampler_cache, *v) {
+                        c.labels.remove(&(gl::SAMPLER, *v));
+                        c.samplers.push(*v);
+                    }
+                });
+            self.containers.clean_with(&mut context,
+                |_, _| (),
+                |c, v| {
+                    c.container_labels.remove(&(gl::VERTEX_ARRAY, *v));
+                    c.array_buffers.push(*v);
+                },
+                |_, _| (),
+                |_, _| (),
+                |c, v| {
+                    c.container_labels.remove(&(gl::FRAMEBUFFER, *v));
+                    c.frame_buffers.push(*v);
+                },
+                |_, _| (),
+                |_, _| (),
+                |_, _| ());
+            unsafe {
+                if !context.buffers.is_empty() {
+                    context.gl.DeleteBuffers(context.buffers.len() as gl::types::GLsizei,
+                                             context.buffers.as_ptr());
+                }
+                if !context.array_buffers.is_empty() {
+                    context.gl.DeleteVertexArrays(context.array_buffers.len() as gl::types::GLsizei,
+                                                  context.array_buffers.as_ptr());
+                }
+                if !context.frame_buffers.is_empty() {
+                    context.gl.DeleteFramebuffers(context.frame_buffers.len() as gl::types::GLsizei,
+                                                  context.frame_buffers.as_ptr());
+                }
+                if !context.surfaces.is_empty() {
+                    context.gl.DeleteRenderbuffers(context.surfaces.len() as gl::types::GLsizei,
+                                                   context.surfaces.as_ptr());
+                }
+                if !context.textures.is_empty() {
+                    context.gl.DeleteTextures(context.textures.len() as gl::types::GLsizei,
+                                              context.textures.as_ptr());
+                }
+                if !context.samplers.is_empty() {
+                    context.gl.DeleteSamplers(context.samplers.len() as gl::types::GLsizei,
+                                              context.samplers.as_ptr());
+                }
+            }
+        }
+        let pipelines = ::std::mem::replace(&mut *self.dropped_pipelines.borrow_mut(), Vec::new());
+        if !pipelines.is_empty() {
+            unsafe {
+                self.gl.DeleteProgramPipelines(pipelines.len() as gl::types::GLsizei,
+                                               pipelines.as_ptr());
+            }
+        }
         self.frame_handles.clear();
+        self.check_errors("cleanup");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use gfx::device as d;
+    use super::{check_buffer_range, choose_range_update, describe, error_report,
+                release_sampler, role_to_gl, BufferError, RangeUpdate};
+    use gl;
+
+    #[test]
+    fn test_buffer_range() {
+        assert_eq!(check_buffer_range(0, 64, 256, 1024), Ok(()));
+        assert_eq!(check_buffer_range(512, 512, 256, 1024), Ok(()));
+        assert_eq!(check_buffer_range(64, 64, 256, 1024),
+                   Err(BufferError::UnalignedOffset(256)));
+        assert_eq!(check_buffer_range(768, 512, 256, 1024),
+                   Err(BufferError::OutOfBounds));
+        assert_eq!(check_buffer_range(0, 0, 256, 1024),
+                   Err(BufferError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_role_to_gl() {
+        assert_eq!(role_to_gl(d::BufferRole::Vertex), gl::ARRAY_BUFFER);
+        assert_eq!(role_to_gl(d::BufferRole::Index), gl::ELEMENT_ARRAY_BUFFER);
+    }
+
+    #[test]
+    fn test_range_update() {
+        assert_eq!(choose_range_update(true, true, false), RangeUpdate::Orphan);
+        assert_eq!(choose_range_update(false, true, false), RangeUpdate::Plain);
+        assert_eq!(choose_range_update(true, false, false), RangeUpdate::Plain);
+        // promoting re-allocates the whole buffer anyway
+        assert_eq!(choose_range_update(true, true, true), RangeUpdate::Plain);
+    }
+
+    #[test]
+    fn test_error_report() {
+        assert_eq!(error_report("create_texture", gl::NO_ERROR), None);
+        assert_eq!(error_report("create_texture", gl::INVALID_VALUE),
+                   Some("Error after create_texture: InvalidValue".to_string()));
+    }
+
+    #[test]
+    fn test_release_sampler() {
+        let info = d::tex::SamplerInfo::new(d::tex::FilterMethod::Bilinear,
+                                            d::tex::WrapMode::Clamp);
+        let mut refs = HashMap::new();
+        refs.insert(3, 2);
+        let mut cache = vec![(info, 3)];
+        assert!(!release_sampler(&mut refs, &mut cache, 3));
+        assert_eq!(cache.len(), 1);
+        assert!(release_sampler(&mut refs, &mut cache, 3));
+        assert!(cache.is_empty());
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut labels = HashMap::new();
+        labels.insert((gl::BUFFER, 42), "terrain_vertices".to_string());
+        assert_eq!(describe(&labels, gl::BUFFER, 42), "42 (terrain_vertices)");
+        assert_eq!(describe(&labels, gl::TEXTURE, 42), "42");
+        assert_eq!(describe(&labels, gl::BUFFER, 7), "7");
     }
 }
@@ -0,0 +1,55 @@
+// Copyright 2015 The Gfx-rs Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::gl;
+use super::gl::types::GLuint64;
+
+
+/// A sync object signaled once the GPU reaches the point of the command
+/// stream it was inserted at. Sync objects are shared by all the contexts of
+/// a share group, so a fence can be waited on from another context.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fence(pub gl::types::GLsync);
+
+unsafe impl Send for Fence {}
+
+pub fn make_fence(gl: &gl::Gl) -> Fence {
+    let sync = unsafe { gl.FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0) };
+    Fence(sync)
+}
+
+/// Block the CPU until the fence is signaled or the timeout expires.
+/// Returns true if the fence was signaled.
+pub fn client_wait(gl: &gl::Gl, fence: Fence, timeout_ns: u64) -> bool {
+    let result = unsafe {
+        gl.ClientWaitSync(fence.0, gl::SYNC_FLUSH_COMMANDS_BIT, timeout_ns as GLuint64)
+    };
+    match result {
+        gl::ALREADY_SIGNALED | gl::CONDITION_SATISFIED => true,
+        gl::TIMEOUT_EXPIRED => false,
+        _ => {
+            error!("Waiting on fence {:?} failed", fence.0);
+            false
+        },
+    }
+}
+
+/// Make the GPU command queue wait for the fence, without blocking the CPU.
+pub fn gpu_wait(gl: &gl::Gl, fence: Fence) {
+    unsafe { gl.WaitSync(fence.0, 0, gl::TIMEOUT_IGNORED) };
+}
+
+pub fn delete_fence(gl: &gl::Gl, fence: Fence) {
+    unsafe { gl.DeleteSync(fence.0) };
+}
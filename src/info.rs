@@ -141,6 +141,12 @@ fn get_usize(gl: &gl::Gl, name: gl::types::GLenum) -> usize {
     value as usize
 }
 
+fn get_f32(gl: &gl::Gl, name: gl::types::GLenum) -> f32 {
+    let mut value = 0 as gl::types::GLfloat;
+    unsafe { gl.GetFloatv(name, &mut value) };
+    value
+}
+
 unsafe fn c_str_as_static_str(c_str: *const i8) -> &'static str {
     mem::transmute(str::from_utf8(ffi::CStr::from_ptr(c_str).to_bytes()).unwrap())
 }
@@ -219,9 +225,200 @@ fn to_shader_model(v: &Version) -> shade::ShaderModel {
     }
 }
 
+/// The profile of an OpenGL context
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Profile {
+    /// Only the non-deprecated functionality is available
+    Core,
+    /// The deprecated functionality is available as well
+    Compatibility,
+}
+
+impl Profile {
+    fn get(gl: &gl::Gl, version: &Version) -> Profile {
+        if *version < Version::new(3, 2, None, "") {
+            return Profile::Compatibility
+        }
+        let mask = get_usize(gl, gl::CONTEXT_PROFILE_MASK) as gl::types::GLenum;
+        if mask & gl::CONTEXT_CORE_PROFILE_BIT != 0 {
+            Profile::Core
+        } else {
+            Profile::Compatibility
+        }
+    }
+}
+
+/// The vendor extension reporting the free video memory, if any
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MemoryInfoExtension {
+    /// `GL_NVX_gpu_memory_info`
+    Nvx,
+    /// `GL_ATI_meminfo`
+    Ati,
+}
+
+impl MemoryInfoExtension {
+    fn get(info: &Info) -> Option<MemoryInfoExtension> {
+        MemoryInfoExtension::select(&info.extensions)
+    }
+
+    /// Pick the extension to use, NVIDIA's one being preferred since it
+    /// also reports the total memory.
+    fn select(extensions: &HashSet<&'static str>) -> Option<MemoryInfoExtension> {
+        if extensions.contains("GL_NVX_gpu_memory_info") {
+            Some(MemoryInfoExtension::Nvx)
+        } else if extensions.contains("GL_ATI_meminfo") {
+            Some(MemoryInfoExtension::Ati)
+        } else {
+            None
+        }
+    }
+}
+
+/// The context doesn't fulfill the requirements of the application
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CapsError {
+    pub required_version: (u8, u8),
+    pub required_profile: Profile,
+    pub version: Version,
+    pub profile: Profile,
+}
+
+impl fmt::Display for CapsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (major, minor) = self.required_version;
+        write!(f, "needs GL {}.{} {:?}, got {}.{} {:?}", major, minor,
+               self.required_profile, self.version.major, self.version.minor,
+               self.profile)
+    }
+}
+
+/// GL functions required by the crate that failed to load, typically
+/// because the context was only partially loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingFunctions(pub Vec<&'static str>);
+
+impl fmt::Display for MissingFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "missing GL functions: {}", self.0.connect(", "))
+    }
+}
+
+macro_rules! find_missing {
+    ($gl:expr; $($name:ident),+) => {{
+        let mut missing = Vec::new();
+        $(
+            if !$gl.$name.is_loaded() {
+                missing.push(stringify!($name));
+            }
+        )+
+        missing
+    }}
+}
+
+fn to_result(missing: Vec<&'static str>) -> Result<(), MissingFunctions> {
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(MissingFunctions(missing))
+    }
+}
+
+/// Check the functions needed to detect the driver information.
+pub fn check_base_functions(gl: &gl::Gl) -> Result<(), MissingFunctions> {
+    to_result(find_missing!(gl; GetError, GetIntegerv, GetFloatv, GetString))
+}
+
+/// Check the functions the crate uses unconditionally for the given version.
+pub fn check_functions(gl: &gl::Gl, version: &Version) -> Result<(), MissingFunctions> {
+    let mut missing = find_missing!(gl;
+        BindBuffer, BufferData, BufferSubData, DeleteBuffers, GenBuffers,
+        MapBuffer, UnmapBuffer,
+        BindTexture, DeleteTextures, GenTextures, TexImage2D, TexParameteri,
+        TexSubImage2D,
+        AttachShader, CompileShader, CreateProgram, CreateShader, DeleteProgram,
+        DeleteShader, LinkProgram, ShaderSource, UseProgram,
+        DisableVertexAttribArray, EnableVertexAttribArray, VertexAttribPointer,
+        Clear, DrawArrays, DrawElements, Viewport);
+    if *version >= Version::new(3, 0, None, "") {
+        missing.extend(find_missing!(gl;
+            GetStringi, BindVertexArray, DeleteVertexArrays, GenVertexArrays,
+            BindFramebuffer, DeleteFramebuffers, GenFramebuffers,
+            FramebufferTexture2D, BindRenderbuffer, DeleteRenderbuffers,
+            GenRenderbuffers, RenderbufferStorage, GenerateMipmap).into_iter());
+    }
+    to_result(missing)
+}
+
+fn check_requirement(version: &Version, profile: Profile,
+                     (major, minor): (u8, u8), required: Profile) -> bool {
+    // the compatibility profile is a superset of the core one
+    *version >= Version::new(major as u32, minor as u32, None, "") &&
+        (required == Profile::Core || profile == Profile::Compatibility)
+}
+
+/// OpenGL-specific capabilities that have no place in the device-agnostic
+/// `Capabilities`.
+#[derive(Copy, Clone, Debug)]
+pub struct PrivateCaps {
+    pub version: Version,
+    pub profile: Profile,
+    /// The context was created with the debug flag
+    pub debug_context:                  bool,
+    /// The context was created with robust buffer access
+    pub robust_context:                 bool,
+    /// The context was created forward-compatible, without the deprecated
+    /// functionality
+    pub forward_compatible_context:     bool,
+    pub memory_info:                    Option<MemoryInfoExtension>,
+    pub buffer_storage_supported:       bool,
+    pub compute_supported:              bool,
+    pub copy_buffer_supported:          bool,
+    pub debug_label_supported:          bool,
+    pub depth_clamp_supported:          bool,
+    pub draw_buffers_blend_supported:   bool,
+    pub fixed_restart_index_supported:  bool,
+    pub float_color_renderable:         bool,
+    pub geometry_shader_supported:      bool,
+    pub half_float_color_renderable:    bool,
+    pub internalformat_query_supported: bool,
+    pub invalidate_subdata_supported:   bool,
+    pub map_buffer_range_supported:     bool,
+    pub memory_barrier_supported:       bool,
+    pub pixel_buffer_supported:         bool,
+    pub primitive_restart_supported:    bool,
+    pub program_binary_supported:       bool,
+    pub program_interface_supported:    bool,
+    pub sample_positions_supported:     bool,
+    pub sample_shading_supported:       bool,
+    pub separate_shaders_supported:     bool,
+    pub shader_storage_supported:       bool,
+    pub srgb_decode_control_supported:  bool,
+    pub stencil_texturing_supported:    bool,
+    pub subroutine_supported:           bool,
+    pub sync_supported:                 bool,
+    pub tessellation_supported:         bool,
+    pub texture_barrier_supported:      bool,
+    pub texture_swizzle_supported:      bool,
+    pub texture_view_supported:         bool,
+
+    pub storage_buffer_alignment:       usize,
+    pub uniform_buffer_alignment:       usize,
+    pub max_lod_bias:                   f32,
+    /// Zero without `EXT_texture_filter_anisotropic`
+    pub max_anisotropy:                 f32,
+    pub max_samples:                    usize,
+    /// Zero without tessellation
+    pub max_patch_vertices:             usize,
+    /// Zero if unknown, without `ARB_explicit_uniform_location`
+    pub max_uniform_locations:          usize,
+    pub max_vertex_uniform_components:  usize,
+    pub max_fragment_uniform_components: usize,
+}
+
 /// Load the information pertaining to the driver and the corresponding device
 /// capabilities.
-pub fn get(gl: &gl::Gl) -> (Info, Capabilities) {
+pub fn get(gl: &gl::Gl) -> (Info, Capabilities, PrivateCaps) {
     let info = Info::get(gl);
     let caps = Capabilities {
         shader_model:                   to_shader_model(&info.shading_language),
@@ -242,13 +439,105 @@ pub fn get(gl: &gl::Gl) -> (Info, Capabilities) {
         uniform_block_supported:        info.is_version_or_extension_supported(3, 0, "GL_ARB_uniform_buffer_object"),
         vertex_base_supported:          info.is_version_or_extension_supported(3, 2, "GL_ARB_draw_elements_base_vertex"),
     };
-    (info, caps)
+    let uniform_block_supported = caps.uniform_block_supported;
+    let shader_storage_supported = info.is_version_or_extension_supported(4, 3, "GL_ARB_shader_storage_buffer_object");
+    let float_color_renderable = info.is_version_or_extension_supported(3, 0, "GL_ARB_color_buffer_float") ||
+                                 info.is_extension_supported("GL_EXT_color_buffer_float");
+    let tessellation_supported = info.is_version_or_extension_supported(4, 0, "GL_ARB_tessellation_shader");
+    let context_flags = if info.version >= Version::new(3, 0, None, "") {
+        get_usize(gl, gl::CONTEXT_FLAGS) as gl::types::GLenum
+    } else { 0 };
+    let private = PrivateCaps {
+        version:                        info.version,
+        profile:                        Profile::get(gl, &info.version),
+        debug_context:                  context_flags & gl::CONTEXT_FLAG_DEBUG_BIT != 0,
+        robust_context:                 context_flags & gl::CONTEXT_FLAG_ROBUST_ACCESS_BIT_ARB != 0,
+        forward_compatible_context:     context_flags & gl::CONTEXT_FLAG_FORWARD_COMPATIBLE_BIT != 0,
+        memory_info:                    MemoryInfoExtension::get(&info),
+        buffer_storage_supported:       info.is_version_or_extension_supported(4, 4, "GL_ARB_buffer_storage"),
+        compute_supported:              info.is_version_or_extension_supported(4, 3, "GL_ARB_compute_shader"),
+        copy_buffer_supported:          info.is_version_or_extension_supported(3, 1, "GL_ARB_copy_buffer"),
+        debug_label_supported:          info.is_version_or_extension_supported(4, 3, "GL_KHR_debug"),
+        depth_clamp_supported:          info.is_version_or_extension_supported(3, 2, "GL_ARB_depth_clamp"),
+        draw_buffers_blend_supported:   info.is_version_or_extension_supported(4, 0, "GL_ARB_draw_buffers_blend"),
+        fixed_restart_index_supported:  info.is_version_or_extension_supported(4, 3, "GL_ARB_ES3_compatibility"),
+        float_color_renderable:         float_color_renderable,
+        geometry_shader_supported:      info.is_version_or_extension_supported(3, 2, "GL_ARB_geometry_shader4"),
+        half_float_color_renderable:    float_color_renderable ||
+                                        info.is_extension_supported("GL_EXT_color_buffer_half_float"),
+        internalformat_query_supported: info.is_version_or_extension_supported(4, 2, "GL_ARB_internalformat_query"),
+        invalidate_subdata_supported:   info.is_version_or_extension_supported(4, 3, "GL_ARB_invalidate_subdata"),
+        map_buffer_range_supported:     info.is_version_or_extension_supported(3, 0, "GL_ARB_map_buffer_range"),
+        memory_barrier_supported:       info.is_version_or_extension_supported(4, 2, "GL_ARB_shader_image_load_store"),
+        pixel_buffer_supported:         info.is_version_or_extension_supported(2, 1, "GL_ARB_pixel_buffer_object"),
+        primitive_restart_supported:    info.version >= Version::new(3, 1, None, ""),
+        program_binary_supported:       info.is_version_or_extension_supported(4, 1, "GL_ARB_get_program_binary") &&
+                                        get_usize(gl, gl::NUM_PROGRAM_BINARY_FORMATS) > 0,
+        program_interface_supported:    info.is_version_or_extension_supported(4, 3, "GL_ARB_program_interface_query"),
+        sample_positions_supported:     info.is_version_or_extension_supported(3, 2, "GL_ARB_texture_multisample"),
+        sample_shading_supported:       info.is_version_or_extension_supported(4, 0, "GL_ARB_sample_shading"),
+        separate_shaders_supported:     info.is_version_or_extension_supported(4, 1, "GL_ARB_separate_shader_objects"),
+        shader_storage_supported:       shader_storage_supported,
+        srgb_decode_control_supported:  info.is_extension_supported("GL_EXT_texture_sRGB_decode"),
+        stencil_texturing_supported:    info.is_version_or_extension_supported(4, 3, "GL_ARB_stencil_texturing"),
+        subroutine_supported:           info.is_version_or_extension_supported(4, 0, "GL_ARB_shader_subroutine"),
+        sync_supported:                 info.is_version_or_extension_supported(3, 2, "GL_ARB_sync"),
+        tessellation_supported:         tessellation_supported,
+        texture_barrier_supported:      info.is_version_or_extension_supported(4, 5, "GL_ARB_texture_barrier") ||
+                                        info.is_extension_supported("GL_NV_texture_barrier"),
+        texture_swizzle_supported:      info.is_version_or_extension_supported(3, 3, "GL_ARB_texture_swizzle") ||
+                                        info.is_extension_supported("GL_EXT_texture_swizzle"),
+        texture_view_supported:         info.is_version_or_extension_supported(4, 3, "GL_ARB_texture_view"),
+
+        storage_buffer_alignment:       if shader_storage_supported {
+                                            get_usize(gl, gl::SHADER_STORAGE_BUFFER_OFFSET_ALIGNMENT)
+                                        } else { 1 },
+        uniform_buffer_alignment:       if uniform_block_supported {
+                                            get_usize(gl, gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT)
+                                        } else { 1 },
+        max_lod_bias:                   get_f32(gl, gl::MAX_TEXTURE_LOD_BIAS),
+        max_anisotropy:                 if info.is_extension_supported("GL_EXT_texture_filter_anisotropic") ||
+                                           info.is_extension_supported("GL_ARB_texture_filter_anisotropic") {
+                                            get_f32(gl, gl::MAX_TEXTURE_MAX_ANISOTROPY_EXT)
+                                        } else { 0.0 },
+        max_samples:                    if caps.render_targets_supported {
+                                            get_usize(gl, gl::MAX_SAMPLES)
+                                        } else { 0 },
+        max_patch_vertices:             if tessellation_supported {
+                                            get_usize(gl, gl::MAX_PATCH_VERTICES)
+                                        } else { 0 },
+        max_uniform_locations:          if info.is_version_or_extension_supported(4, 3, "GL_ARB_explicit_uniform_location") {
+                                            get_usize(gl, gl::MAX_UNIFORM_LOCATIONS)
+                                        } else { 0 },
+        max_vertex_uniform_components:  get_usize(gl, gl::MAX_VERTEX_UNIFORM_COMPONENTS),
+        max_fragment_uniform_components: get_usize(gl, gl::MAX_FRAGMENT_UNIFORM_COMPONENTS),
+    };
+    (info, caps, private)
+}
+
+impl PrivateCaps {
+    /// Check that the context is at least of the given version and profile,
+    /// so that applications can fail early with a clear message.
+    pub fn require(&self, min_version: (u8, u8), profile: Profile)
+                   -> Result<(), CapsError> {
+        if check_requirement(&self.version, self.profile, min_version, profile) {
+            Ok(())
+        } else {
+            Err(CapsError {
+                required_version: min_version,
+                required_profile: profile,
+                version: self.version,
+                profile: self.profile,
+            })
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Version;
-    use super::to_shader_model;
+    use std::collections::HashSet;
+    use super::{MemoryInfoExtension, Profile, Version};
+    use super::{check_requirement, to_shader_model};
 
     #[test]
     fn test_version_parse() {
@@ -274,4 +563,25 @@ mod tests {
         assert_eq!(to_shader_model(&Version::parse("3.00").unwrap()), ShaderModel::Version41);
         assert_eq!(to_shader_model(&Version::parse("4.30").unwrap()), ShaderModel::Version50);
     }
+
+    #[test]
+    fn test_requirement() {
+        let v21 = Version::parse("2.1").unwrap();
+        let v33 = Version::parse("3.3").unwrap();
+        assert!(check_requirement(&v33, Profile::Core, (3, 3), Profile::Core));
+        assert!(check_requirement(&v33, Profile::Compatibility, (3, 2), Profile::Core));
+        assert!(!check_requirement(&v33, Profile::Core, (3, 2), Profile::Compatibility));
+        assert!(!check_requirement(&v21, Profile::Compatibility, (3, 3), Profile::Core));
+        assert!(check_requirement(&v21, Profile::Compatibility, (2, 0), Profile::Compatibility));
+    }
+
+    #[test]
+    fn test_memory_info_extension() {
+        let mut extensions = HashSet::new();
+        assert_eq!(MemoryInfoExtension::select(&extensions), None);
+        extensions.insert("GL_ATI_meminfo");
+        assert_eq!(MemoryInfoExtension::select(&extensions), Some(MemoryInfoExtension::Ati));
+        extensions.insert("GL_NVX_gpu_memory_info");
+        assert_eq!(MemoryInfoExtension::select(&extensions), Some(MemoryInfoExtension::Nvx));
+    }
 }
@@ -25,23 +25,42 @@ extern crate libc;
 extern crate gfx_gl as gl;
 extern crate gfx;
 
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use gfx::device as d;
 use gfx::device::attrib::*;
 use gfx::device::draw::{Access, Gamma, Target};
 use gfx::device::handle;
 use gfx::device::state::{CullFace, RasterMethod, FrontFace};
 
-pub use self::draw::{Command, CommandBuffer};
-pub use self::factory::{Factory, Output};
-pub use self::info::{Info, PlatformName, Version};
+pub use self::atlas::AtlasTexture;
+pub use self::draw::{Command, CommandBuffer, CommandList};
+pub use self::factory::{AttachmentError, BlitError, BlitFilter, BlockError, BlockTarget,
+                        BufferError, DepthPeelSet, DepthPeelTargets, DispatchError,
+                        ErrorCheck, Factory, FenceError, GpuMemoryInfo, MemoryBarrier,
+                        Output, PatchError, SharedHandles, SharedState, UsageHeuristics};
+pub use self::fence::Fence;
+pub use self::info::{CapsError, Info, MemoryInfoExtension, MissingFunctions, PlatformName,
+                     PrivateCaps, Profile, Version};
+pub use self::null::{NullFactory, NullMapping};
+pub use self::shade::{BinaryMetadata, BinaryStale, BlockLayout, BlockMember,
+                      CreateProgramError, ProgramInterface, ProgramPipeline, RelinkError,
+                      SubroutineInterface, TessStage};
+pub use self::state::{DrawBuffersError, PipelineState};
+pub use self::stream::{StreamBuffer, StreamError, StreamStats, WrapPolicy};
+pub use self::tex::{DepthStencilMode, PixelStorePreset, Swizzle, SWIZZLE_IDENTITY};
 
+mod atlas;
 mod draw;
 mod factory;
+mod fence;
 mod shade;
 mod state;
+mod stream;
 mod tex;
 mod info;
+mod null;
 
 
 pub type Buffer         = gl::types::GLuint;
@@ -142,20 +161,61 @@ fn target_to_gl(target: Target) -> gl::types::GLenum {
     }
 }
 
+/// Get the index restarting primitives for an index type, which is the
+/// largest value of the type.
+fn restart_index(index_type: IntSize) -> u32 {
+    match index_type {
+        IntSize::U8  => 0xFF,
+        IntSize::U16 => 0xFFFF,
+        IntSize::U32 => 0xFFFFFFFF,
+    }
+}
+
+/// Move a texture slot past the units reserved for external code, so that
+/// the slot `n` lands on the `n`-th unit that isn't reserved. The sampler
+/// uniforms of the program are expected to follow the same mapping.
+fn remap_unit(slot: u32, reserved: &[u32]) -> u32 {
+    (0..).filter(|u| !reserved.contains(u)).nth(slot as usize).unwrap()
+}
+
+fn mask_to_gl(mask: d::target::Mask) -> gl::types::GLbitfield {
+    let mut flags = 0;
+    if mask.intersects(d::target::COLOR) {
+        flags |= gl::COLOR_BUFFER_BIT;
+    }
+    if mask.intersects(d::target::DEPTH) {
+        flags |= gl::DEPTH_BUFFER_BIT;
+    }
+    if mask.intersects(d::target::STENCIL) {
+        flags |= gl::STENCIL_BUFFER_BIT;
+    }
+    flags
+}
+
 /// An OpenGL device with GLSL shaders.
 pub struct Device {
     info: Info,
     caps: d::Capabilities,
+    private_caps: PrivateCaps,
     gl: Rc<gl::Gl>,
+    reserved_units: Rc<RefCell<Vec<u32>>>,
+    /// Tells the factory of the context that its cached state is stale
+    state_changed: Rc<Cell<bool>>,
     frame_handles: handle::Manager<Resources>,
     max_resource_count: Option<usize>,
+    primitive_restart: bool,
+    /// Restart index last set, `None` if unknown
+    restart_index: Option<u32>,
 }
 
-/// Load OpenGL symbols and detect driver information.
+/// Load OpenGL symbols and detect driver information. Fails with the list
+/// of required functions that couldn't be loaded.
 pub fn create<F: FnMut(&str) -> *const ::libc::c_void>(fn_proc: F)
-              -> (Device, Factory) {
+              -> Result<(Device, Factory), MissingFunctions> {
     let gl = Rc::new(gl::Gl::load_with(fn_proc));
-    let (info, caps) = info::get(&gl);
+    try!(info::check_base_functions(&gl));
+    let (info, caps, private_caps) = info::get(&gl);
+    try!(info::check_functions(&gl, &info.version));
 
     info!("Vendor: {:?}", info.platform_name.vendor);
     info!("Renderer: {:?}", info.platform_name.renderer);
@@ -166,15 +226,44 @@ pub fn create<F: FnMut(&str) -> *const ::libc::c_void>(fn_proc: F)
         debug!("- {}", *extension);
     }
 
-    let factory = factory::create(caps, gl.clone());
+    let handles = Arc::new(Mutex::new(SharedState::new()));
+    let reserved_units = Rc::new(RefCell::new(Vec::new()));
+    let state_changed = Rc::new(Cell::new(false));
+    let factory = factory::create(caps, private_caps, &info, gl.clone(), handles,
+                                  reserved_units.clone(), state_changed.clone());
     let device = Device {
         info: info,
         caps: caps,
+        private_caps: private_caps,
         gl: gl,
+        reserved_units: reserved_units,
+        state_changed: state_changed,
         frame_handles: handle::Manager::new(),
         max_resource_count: Some(999999),
+        primitive_restart: false,
+        restart_index: None,
     };
-    (device, factory)
+    Ok((device, factory))
+}
+
+/// Load OpenGL symbols of another context and create a factory registering
+/// its resources into the given shared manager, typically obtained with
+/// `Factory::share_handles` on the main context.
+///
+/// The context has to be in the same share group as the one of the original
+/// factory, and current on the calling thread. Container objects (frame
+/// buffers and array buffers) are not shared between contexts: they can only
+/// be used with the context that created them, and are only deleted by the
+/// `cleanup` of its factory.
+pub fn create_shared<F: FnMut(&str) -> *const ::libc::c_void>(handles: SharedHandles,
+                     fn_proc: F) -> Result<Factory, MissingFunctions> {
+    let gl = Rc::new(gl::Gl::load_with(fn_proc));
+    try!(info::check_base_functions(&gl));
+    let (info, caps, private_caps) = info::get(&gl);
+    try!(info::check_functions(&gl, &info.version));
+    info!("Shared context version: {:?}", info.version);
+    Ok(factory::create(caps, private_caps, &info, gl, handles,
+                       Rc::new(RefCell::new(Vec::new())), Rc::new(Cell::new(false))))
 }
 
 impl Device {
@@ -184,6 +273,44 @@ impl Device {
         use gfx::Device;
         self.reset_state();
         fun(&self.gl);
+        self.restart_index = None;
+    }
+
+    /// Restart primitives at the largest index of the index type of each
+    /// indexed draw call, such as `0xFFFF` for `u16` indices. Uses the fixed
+    /// restart index of GL 4.3 when available, otherwise the restart index
+    /// is set before each draw call whose index type differs from the
+    /// previous one.
+    pub fn set_primitive_restart(&mut self, enabled: bool) {
+        if !self.private_caps.primitive_restart_supported {
+            error!("Primitive restart is not supported, ignored");
+            return
+        }
+        let cap = if self.private_caps.fixed_restart_index_supported {
+            gl::PRIMITIVE_RESTART_FIXED_INDEX
+        } else {
+            gl::PRIMITIVE_RESTART
+        };
+        unsafe {
+            if enabled {
+                self.gl.Enable(cap);
+            } else {
+                self.gl.Disable(cap);
+            }
+        }
+        self.primitive_restart = enabled;
+    }
+
+    /// Set the restart index matching the index type of a draw call.
+    fn bind_restart_index(&mut self, index_type: IntSize) {
+        if !self.primitive_restart || self.private_caps.fixed_restart_index_supported {
+            return
+        }
+        let index = restart_index(index_type);
+        if self.restart_index != Some(index) {
+            unsafe { self.gl.PrimitiveRestartIndex(index) };
+            self.restart_index = Some(index);
+        }
     }
 
     /// Fails during a debug build if the implementation's error flag was set.
@@ -201,6 +328,16 @@ impl Device {
         &self.info
     }
 
+    /// Execute a recorded command list, starting from the default state.
+    /// The resources used by the list have to be kept alive by the caller.
+    pub fn replay(&mut self, list: &CommandList) {
+        use gfx::Device;
+        self.reset_state();
+        for com in list.iter() {
+            self.process(com, list.get_data());
+        }
+    }
+
     fn process(&mut self, cmd: &Command, data_buf: &d::draw::DataBuffer) {
         match *cmd {
             Command::Clear(ref data, mask) => {
@@ -344,6 +481,7 @@ impl Device {
                 shade::bind_uniform(&self.gl, loc as gl::types::GLint, uniform);
             },
             Command::BindTexture(slot, kind, texture, sampler) => {
+                let slot = remap_unit(slot as u32, &self.reserved_units.borrow());
                 let anchor = tex::bind_texture(&self.gl,
                     gl::TEXTURE0 + slot as gl::types::GLenum,
                     kind, texture);
@@ -353,7 +491,9 @@ impl Device {
                             unsafe { self.gl.BindSampler(slot as gl::types::GLenum, name) };
                         } else {
                             debug_assert_eq!(name, 0);
-                            tex::bind_sampler(&self.gl, anchor, &info);
+                            tex::bind_sampler(&self.gl, anchor, &info,
+                                              self.private_caps.max_lod_bias,
+                                              self.private_caps.max_anisotropy);
                         }
                     },
                     (_, Some(_), Some(_)) =>
@@ -428,6 +568,7 @@ impl Device {
                     IntSize::U16 => (start * 2u32, gl::UNSIGNED_SHORT),
                     IntSize::U32 => (start * 4u32, gl::UNSIGNED_INT),
                 };
+                self.bind_restart_index(index_type);
                 match instances {
                     Some((num, base_instance)) if self.caps.instance_call_supported => unsafe {
                         if (base_vertex == 0 && base_instance == 0) || !self.caps.vertex_base_supported {
@@ -510,17 +651,7 @@ impl Device {
                     s_end_y = s_rect.y;
                     s_rect.y += s_rect.h;
                 }
-                // build mask
-                let mut flags = 0;
-                if mask.intersects(d::target::COLOR) {
-                    flags |= gl::COLOR_BUFFER_BIT;
-                }
-                if mask.intersects(d::target::DEPTH) {
-                    flags |= gl::DEPTH_BUFFER_BIT;
-                }
-                if mask.intersects(d::target::STENCIL) {
-                    flags |= gl::STENCIL_BUFFER_BIT;
-                }
+                let flags = mask_to_gl(mask);
                 // build filter
                 let filter = if s_rect.w == d_rect.w && s_rect.h == d_rect.h {
                     gl::NEAREST
@@ -555,6 +686,7 @@ impl gfx::Device for Device {
     }
 
     fn reset_state(&mut self) {
+        self.state_changed.set(true);
         let data = d::draw::DataBuffer::new();
         for com in RESET_CB.iter() {
             self.process(com, &data);
@@ -580,3 +712,25 @@ impl gfx::Device for Device {
         self.frame_handles.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use gfx::device::attrib::IntSize;
+    use super::{remap_unit, restart_index};
+
+    #[test]
+    fn test_restart_index() {
+        assert_eq!(restart_index(IntSize::U8), 0xFF);
+        assert_eq!(restart_index(IntSize::U16), 0xFFFF);
+        assert_eq!(restart_index(IntSize::U32), 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_remap_unit() {
+        assert_eq!(remap_unit(0, &[]), 0);
+        assert_eq!(remap_unit(3, &[]), 3);
+        assert_eq!(remap_unit(0, &[0]), 1);
+        assert_eq!(remap_unit(1, &[0, 2]), 3);
+        assert_eq!(remap_unit(2, &[5]), 2);
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2015 The Gfx-rs Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A factory that doesn't need a GL context, for testing the code built on
+//! top of this crate.
+
+use libc;
+use std::collections::HashMap;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use gfx::device as d;
+use gfx::device::handle;
+use gfx::device::handle::Producer;
+use gfx::device::mapping::Builder;
+
+use factory::{SharedHandles, SharedState};
+use {tex, Buffer};
+use Resources as R;
+
+
+/// Mapping of a buffer of a `NullFactory`, pointing to its storage in
+/// system memory.
+#[allow(raw_pointer_derive)]
+#[derive(Copy, Clone)]
+pub struct NullMapping {
+    pub pointer: *mut libc::c_void,
+}
+
+impl d::mapping::Raw for NullMapping {
+    unsafe fn set<T>(&self, index: usize, val: T) {
+        *(self.pointer as *mut T).offset(index as isize) = val;
+    }
+
+    unsafe fn to_slice<T>(&self, len: usize) -> &[T] {
+        slice::from_raw_parts(self.pointer as *const T, len)
+    }
+
+    unsafe fn to_mut_slice<T>(&self, len: usize) -> &mut [T] {
+        slice::from_raw_parts_mut(self.pointer as *mut T, len)
+    }
+}
+
+/// Resource factory that doesn't talk to GL. Every resource gets a new
+/// dummy name, buffers are kept in system memory so that they can be
+/// updated and mapped, and everything else is a no-op. Meant for running
+/// the layers above this crate in environments without a context, like CI.
+pub struct NullFactory {
+    handles: SharedHandles,
+    frame_handles: handle::Manager<R>,
+    /// Last name handed out, shared by all the object types
+    last_name: u32,
+    /// Contents of the buffers
+    buffers: HashMap<Buffer, Vec<u8>>,
+}
+
+impl NullFactory {
+    /// Create a new null factory with its own handle manager.
+    pub fn new() -> NullFactory {
+        NullFactory::with_handles(Arc::new(Mutex::new(SharedState::new())))
+    }
+
+    /// Create a new null factory registering its resources into the given
+    /// manager.
+    pub fn with_handles(handles: SharedHandles) -> NullFactory {
+        NullFactory {
+            handles: handles,
+            frame_handles: handle::Manager::new(),
+            last_name: 0,
+            buffers: HashMap::new(),
+        }
+    }
+
+    /// Get a handle to the manager of the resources.
+    pub fn share_handles(&self) -> SharedHandles {
+        self.handles.clone()
+    }
+
+    /// Get the contents of a buffer.
+    pub fn get_buffer_data(&mut self, buf: &handle::RawBuffer<R>) -> &[u8] {
+        let name = self.frame_handles.ref_buffer(buf);
+        &self.buffers[&name]
+    }
+
+    fn next_name(&mut self) -> u32 {
+        self.last_name += 1;
+        self.last_name
+    }
+
+    fn make_buffer(&mut self, info: d::BufferInfo) -> handle::RawBuffer<R> {
+        let name = self.next_name();
+        self.buffers.insert(name, (0 .. info.size).map(|_| 0).collect());
+        self.handles.lock().unwrap().manager().make_buffer(name, info)
+    }
+}
+
+impl d::Factory<R> for NullFactory {
+    type Mapper = NullMapping;
+
+    fn create_buffer_raw(&mut self, size: usize, usage: d::BufferUsage)
+                         -> handle::RawBuffer<R> {
+        self.make_buffer(d::BufferInfo {
+            role: d::BufferRole::Vertex,
+            usage: usage,
+            size: size,
+        })
+    }
+
+    fn create_buffer_static_raw(&mut self, data: &[u8], role: d::BufferRole)
+                                -> handle::RawBuffer<R> {
+        let buf = self.make_buffer(d::BufferInfo {
+            role: role,
+            usage: d::BufferUsage::Static,
+            size: data.len(),
+        });
+        self.update_buffer_raw(&buf, data, 0);
+        buf
+    }
+
+    fn create_array_buffer(&mut self) -> Result<handle::ArrayBuffer<R>, ()> {
+        let name = self.next_name();
+        Ok(self.handles.lock().unwrap().manager().make_array_buffer(name))
+    }
+
+    fn create_shader(&mut self, stage: d::shade::Stage, _code: &[u8])
+                     -> Result<handle::Shader<R>, d::shade::CreateShaderError> {
+        let name = self.next_name();
+        Ok(self.handles.lock().unwrap().manager().make_shader(name, stage))
+    }
+
+    fn create_program(&mut self, _shaders: &[handle::Shader<R>],
+                      _targets: Option<&[&str]>)
+                      -> Result<handle::Program<R>, ()> {
+        let name = self.next_name();
+        let info = d::shade::ProgramInfo {
+            attributes: Vec::new(),
+            uniforms: Vec::new(),
+            blocks: Vec::new(),
+            textures: Vec::new(),
+        };
+        Ok(self.handles.lock().unwrap().manager().make_program(name, info))
+    }
+
+    fn create_frame_buffer(&mut self) -> handle::FrameBuffer<R> {
+        let name = self.next_name();
+        self.handles.lock().unwrap().manager().make_frame_buffer(name)
+    }
+
+    fn create_surface(&mut self, info: d::tex::SurfaceInfo) ->
+                      Result<handle::Surface<R>, d::tex::SurfaceError> {
+        let name = self.next_name();
+        Ok(self.handles.lock().unwrap().manager().make_surface(name, info))
+    }
+
+    fn create_texture(&mut self, info: d::tex::TextureInfo) ->
+                      Result<handle::Texture<R>, d::tex::TextureError> {
+        if tex::is_empty(&info) {
+            return Err(d::tex::TextureError::InvalidInfo(info))
+        }
+        let name = self.next_name();
+        Ok(self.handles.lock().unwrap().manager().make_texture(name, info))
+    }
+
+    fn create_sampler(&mut self, info: d::tex::SamplerInfo)
+                      -> handle::Sampler<R> {
+        let name = self.next_name();
+        self.handles.lock().unwrap().manager().make_sampler(name, info)
+    }
+
+    fn update_buffer_raw(&mut self, buffer: &handle::RawBuffer<R>,
+                         data: &[u8], offset_bytes: usize) {
+        let name = self.frame_handles.ref_buffer(buffer);
+        let storage = self.buffers.get_mut(&name).unwrap();
+        if offset_bytes + data.len() > storage.len() {
+            panic!("Update of {} bytes at {} overflows buffer {} of {} bytes",
+                   data.len(), offset_bytes, name, storage.len());
+        }
+        for (dst, src) in storage[offset_bytes ..].iter_mut().zip(data.iter()) {
+            *dst = *src;
+        }
+    }
+
+    fn update_texture_raw(&mut self, _texture: &handle::Texture<R>,
+                          _img: &d::tex::ImageInfo, _data: &[u8],
+                          _optkind: Option<d::tex::TextureKind>)
+                          -> Result<(), d::tex::TextureError> {
+        Ok(())
+    }
+
+    fn generate_mipmap(&mut self, _texture: &handle::Texture<R>) {}
+
+    fn map_buffer_raw(&mut self, buf: &handle::RawBuffer<R>,
+                      _access: d::MapAccess) -> NullMapping {
+        let name = self.frame_handles.ref_buffer(buf);
+        NullMapping {
+            pointer: self.buffers.get_mut(&name).unwrap().as_mut_ptr() as *mut libc::c_void,
+        }
+    }
+
+    fn unmap_buffer_raw(&mut self, _map: NullMapping) {}
+
+    fn map_buffer_readable<T: Copy>(&mut self, buf: &handle::Buffer<R, T>)
+                           -> d::mapping::Readable<T, R, NullFactory> {
+        let map = self.map_buffer_raw(buf.raw(), d::MapAccess::Readable);
+        self.map_readable(map, buf.len())
+    }
+
+    fn map_buffer_writable<T: Copy>(&mut self, buf: &handle::Buffer<R, T>)
+                                    -> d::mapping::Writable<T, R, NullFactory> {
+        let map = self.map_buffer_raw(buf.raw(), d::MapAccess::Writable);
+        self.map_writable(map, buf.len())
+    }
+
+    fn map_buffer_rw<T: Copy>(&mut self, buf: &handle::Buffer<R, T>)
+                              -> d::mapping::RW<T, R, NullFactory> {
+        let map = self.map_buffer_raw(buf.raw(), d::MapAccess::RW);
+        self.map_read_write(map, buf.len())
+    }
+
+    fn cleanup(&mut self) {
+        self.handles.lock().unwrap().manager().clean_with(&mut self.buffers,
+            |b, v| { b.remove(v); },
+            |_, _| (),
+            |_, _| (),
+            |_, _| (),
+            |_, _| (),
+            |_, _| (),
+            |_, _| (),
+            |_, _| ());
+        self.frame_handles.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gfx::device as d;
+    use gfx::device::mapping::Raw;
+    use super::NullFactory;
+
+    #[test]
+    fn test_buffer_update() {
+        let mut factory = NullFactory::new();
+        let buf = d::Factory::create_buffer_static_raw(&mut factory, &[1, 2, 3, 4],
+                                                       d::BufferRole::Index);
+        assert_eq!(buf.get_info().role, d::BufferRole::Index);
+        assert_eq!(factory.get_buffer_data(&buf), &[1, 2, 3, 4]);
+        d::Factory::update_buffer_raw(&mut factory, &buf, &[5, 6], 1);
+        assert_eq!(factory.get_buffer_data(&buf), &[1, 5, 6, 4]);
+    }
+
+    #[test]
+    fn test_buffer_mapping() {
+        let mut factory = NullFactory::new();
+        let buf = d::Factory::create_buffer_raw(&mut factory, 4, d::BufferUsage::Dynamic);
+        assert_eq!(factory.get_buffer_data(&buf), &[0, 0, 0, 0]);
+        let map = d::Factory::map_buffer_raw(&mut factory, &buf, d::MapAccess::RW);
+        unsafe {
+            map.set::<u8>(2, 7);
+            assert_eq!(map.to_slice::<u8>(4), &[0, 0, 7, 0]);
+        }
+        d::Factory::unmap_buffer_raw(&mut factory, map);
+        assert_eq!(factory.get_buffer_data(&buf), &[0, 0, 7, 0]);
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let mut factory = NullFactory::new();
+        let kept = d::Factory::create_buffer_raw(&mut factory, 4, d::BufferUsage::Static);
+        let dropped = d::Factory::create_buffer_raw(&mut factory, 8, d::BufferUsage::Static);
+        assert_eq!(factory.buffers.len(), 2);
+        drop(dropped);
+        d::Factory::cleanup(&mut factory);
+        assert_eq!(factory.buffers.len(), 1);
+        assert_eq!(factory.get_buffer_data(&kept).len(), 4);
+    }
+}
@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
 use std::iter::repeat;
 use std::ffi::CString;
+use std::rc::Rc;
 
 use gfx::device as d;
 use gfx::device::shade as s;
@@ -21,6 +23,7 @@ use gfx::device::shade::{BaseType, ContainerType, CreateShaderError,
                          IsArray, IsShadow, IsRect, IsMultiSample, MatrixFormat,
                          SamplerType, Stage, UniformValue};
 use super::gl;
+use info::Info;
 
 use self::StorageType::{
     Var,
@@ -28,13 +31,90 @@ use self::StorageType::{
     Unknown,
 };
 
-pub fn create_shader(gl: &gl::Gl, stage: s::Stage, data: &[u8])
-        -> (Result<super::Shader, s::CreateShaderError>, Option<String>) {
-    let target = match stage {
+/// Identification of the driver a program binary was retrieved from.
+/// Binaries are only valid for the exact driver they were produced by, so
+/// this needs to be stored alongside them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinaryMetadata {
+    pub vendor: String,
+    pub renderer: String,
+    pub version: String,
+}
+
+impl BinaryMetadata {
+    pub fn new(info: &Info) -> BinaryMetadata {
+        BinaryMetadata {
+            vendor: info.platform_name.vendor.to_string(),
+            renderer: info.platform_name.renderer.to_string(),
+            version: format!("{:?}", info.version),
+        }
+    }
+}
+
+/// A program binary was produced by a different driver than the current one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinaryStale {
+    /// Metadata of the current driver
+    pub current: BinaryMetadata,
+}
+
+/// Check that a program binary stored with the given metadata can be loaded
+/// by the current driver.
+pub fn check_binary_metadata(current: &BinaryMetadata, stored: &BinaryMetadata)
+                             -> Result<(), BinaryStale> {
+    if stored == current {
+        Ok(())
+    } else {
+        Err(BinaryStale {
+            current: current.clone(),
+        })
+    }
+}
+
+/// A tessellation stage. Those are missing from `Stage`, so shaders of
+/// these stages are made with `Factory::create_tess_shader`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TessStage {
+    /// Tessellation control, run once per output vertex of a patch
+    Control,
+    /// Tessellation evaluation, run once per tessellated vertex
+    Evaluation,
+}
+
+impl TessStage {
+    fn to_gl(&self) -> gl::types::GLenum {
+        match *self {
+            TessStage::Control => gl::TESS_CONTROL_SHADER,
+            TessStage::Evaluation => gl::TESS_EVALUATION_SHADER,
+        }
+    }
+}
+
+pub fn stage_to_gl(stage: s::Stage) -> gl::types::GLenum {
+    match stage {
         Stage::Vertex => gl::VERTEX_SHADER,
         Stage::Geometry => gl::GEOMETRY_SHADER,
         Stage::Fragment => gl::FRAGMENT_SHADER,
-    };
+    }
+}
+
+pub fn create_shader(gl: &gl::Gl, stage: s::Stage, data: &[u8])
+        -> (Result<super::Shader, s::CreateShaderError>, Option<String>) {
+    compile_shader(gl, stage_to_gl(stage), data)
+}
+
+pub fn create_tess_shader(gl: &gl::Gl, stage: TessStage, data: &[u8])
+        -> (Result<super::Shader, s::CreateShaderError>, Option<String>) {
+    compile_shader(gl, stage.to_gl(), data)
+}
+
+pub fn create_compute_shader(gl: &gl::Gl, data: &[u8])
+        -> (Result<super::Shader, s::CreateShaderError>, Option<String>) {
+    compile_shader(gl, gl::COMPUTE_SHADER, data)
+}
+
+fn compile_shader(gl: &gl::Gl, target: gl::types::GLenum, data: &[u8])
+        -> (Result<super::Shader, s::CreateShaderError>, Option<String>) {
     let name = unsafe { gl.CreateShader(target) };
     unsafe {
         gl.ShaderSource(name, 1,
@@ -224,6 +304,86 @@ fn query_blocks(gl: &gl::Gl, caps: &d::Capabilities, prog: super::Program) -> Ve
     }).collect()
 }
 
+/// Placement of a uniform block member, as assigned by the linker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockMember {
+    pub name: String,
+    pub offset: usize,
+    /// Distance between array elements, zero if not an array
+    pub array_stride: usize,
+    /// Distance between matrix columns (or rows), zero if not a matrix
+    pub matrix_stride: usize,
+}
+
+/// Layout of a uniform block, with its members sorted by offset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockLayout {
+    pub name: String,
+    /// Index of the block in the program, as given to `UniformBlockBinding`
+    pub index: u32,
+    pub size: usize,
+    pub members: Vec<BlockMember>,
+}
+
+impl BlockLayout {
+    /// Find a member by its full name, for example `Light.color`.
+    pub fn find_member(&self, name: &str) -> Option<&BlockMember> {
+        self.members.iter().find(|m| m.name == name)
+    }
+}
+
+/// Query the member offsets and strides of all the uniform blocks.
+pub fn query_block_layouts(gl: &gl::Gl, caps: &d::Capabilities, prog: super::Program)
+                           -> Vec<BlockLayout> {
+    let max_len = get_program_iv(gl, prog, gl::ACTIVE_UNIFORM_MAX_LENGTH);
+    let mut name = String::with_capacity(max_len as usize);
+    name.extend(repeat('\0').take(max_len as usize));
+    query_blocks(gl, caps, prog).into_iter().enumerate().map(|(i, block)| {
+        let i = i as gl::types::GLuint;
+        let mut num = 0;
+        unsafe {
+            gl.GetActiveUniformBlockiv(prog, i, gl::UNIFORM_BLOCK_ACTIVE_UNIFORMS, &mut num);
+        }
+        let mut indices: Vec<gl::types::GLint> = repeat(0).take(num as usize).collect();
+        let mut offsets = indices.clone();
+        let mut array_strides = indices.clone();
+        let mut matrix_strides = indices.clone();
+        unsafe {
+            gl.GetActiveUniformBlockiv(prog, i, gl::UNIFORM_BLOCK_ACTIVE_UNIFORM_INDICES,
+                                       indices.as_mut_ptr());
+            let ptr = indices.as_ptr() as *const gl::types::GLuint;
+            gl.GetActiveUniformsiv(prog, num, ptr, gl::UNIFORM_OFFSET, offsets.as_mut_ptr());
+            gl.GetActiveUniformsiv(prog, num, ptr, gl::UNIFORM_ARRAY_STRIDE,
+                                   array_strides.as_mut_ptr());
+            gl.GetActiveUniformsiv(prog, num, ptr, gl::UNIFORM_MATRIX_STRIDE,
+                                   matrix_strides.as_mut_ptr());
+        }
+        let mut members: Vec<_> = (0..num as usize).map(|k| {
+            let mut length = 0;
+            unsafe {
+                gl.GetActiveUniformName(prog, indices[k] as gl::types::GLuint, max_len,
+                    &mut length, (&name[..]).as_ptr() as *mut gl::types::GLchar);
+            }
+            BlockMember {
+                name: name[..length as usize].to_string(),
+                offset: offsets[k] as usize,
+                array_stride: array_strides[k] as usize,
+                matrix_stride: matrix_strides[k] as usize,
+            }
+        }).collect();
+        members.sort_by(|a, b| a.offset.cmp(&b.offset));
+        for m in members.iter() {
+            info!("\t\t\tMember '{}' at {}", m.name, m.offset);
+        }
+        BlockLayout {
+            name: block.name,
+            index: i,
+            size: block.size,
+            members: members,
+        }
+    }).collect()
+}
+
 fn query_parameters(gl: &gl::Gl, caps: &d::Capabilities, prog: super::Program)
                     -> (Vec<s::UniformVar>, Vec<s::SamplerVar>) {
     let mut uniforms = Vec::new();
@@ -282,13 +442,34 @@ fn query_parameters(gl: &gl::Gl, caps: &d::Capabilities, prog: super::Program)
     (uniforms, textures)
 }
 
+/// Count the locations and components taken by the uniforms outside of
+/// blocks, as limited by `MAX_UNIFORM_LOCATIONS` and the per-stage
+/// `MAX_*_UNIFORM_COMPONENTS`.
+pub fn count_uniform_usage(info: &s::ProgramInfo) -> (usize, usize) {
+    info.uniforms.iter().fold((0, 0), |(locations, components), u| {
+        let size = match u.container {
+            ContainerType::Single => 1,
+            ContainerType::Vector(n) => n as usize,
+            ContainerType::Matrix(_, c, r) => c as usize * r as usize,
+        };
+        (locations + u.count, components + size * u.count)
+    })
+}
+
 pub fn create_program<I: Iterator<Item = super::Shader>>(gl: &gl::Gl,
-                      caps: &d::Capabilities, shaders: I, targets: Option<&[&str]>)
+                      caps: &d::Capabilities, shaders: I, targets: Option<&[&str]>,
+                      retrievable: bool)
                       -> (Result<(::Program, s::ProgramInfo), ()>, Option<String>) {
     let name = unsafe { gl.CreateProgram() };
     for sh in shaders {
         unsafe { gl.AttachShader(name, sh) };
     }
+    if retrievable {
+        unsafe {
+            gl.ProgramParameteri(name, gl::PROGRAM_BINARY_RETRIEVABLE_HINT,
+                                 gl::TRUE as gl::types::GLint);
+        }
+    }
 
     let targets = targets.map(|targets| {
         let targets: Vec<CString> = targets.iter().map(|&s| CString::new(s).unwrap()).collect();
@@ -315,6 +496,178 @@ pub fn create_program<I: Iterator<Item = super::Shader>>(gl: &gl::Gl,
         };
     }
 
+    let (info, log) = query_link_result(gl, caps, name);
+    (info.map(|info| (name, info)), log)
+}
+
+/// Link a single shader into a program whose stage can be combined with the
+/// ones of other programs in a pipeline. Requires
+/// `GL_ARB_separate_shader_objects`.
+pub fn create_separable_program(gl: &gl::Gl, caps: &d::Capabilities, shader: super::Shader,
+                                retrievable: bool)
+                                -> (Result<(::Program, s::ProgramInfo), ()>, Option<String>) {
+    let name = unsafe { gl.CreateProgram() };
+    unsafe {
+        gl.ProgramParameteri(name, gl::PROGRAM_SEPARABLE, gl::TRUE as gl::types::GLint);
+        if retrievable {
+            gl.ProgramParameteri(name, gl::PROGRAM_BINARY_RETRIEVABLE_HINT,
+                                 gl::TRUE as gl::types::GLint);
+        }
+        gl.AttachShader(name, shader);
+        gl.LinkProgram(name);
+        gl.DetachShader(name, shader);
+    }
+    info!("\tLinked separable program {}", name);
+    let (info, log) = query_link_result(gl, caps, name);
+    (info.map(|info| (name, info)), log)
+}
+
+/// The subroutines of a program stage, and the subroutine uniforms
+/// selecting among them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubroutineInterface {
+    /// Names of the subroutines, by index
+    pub subroutines: Vec<String>,
+    /// Names and locations of the subroutine uniforms
+    pub uniforms: Vec<(String, u32)>,
+    /// Number of uniform locations, which is the number of indices to give
+    /// to `Factory::set_subroutines`
+    pub location_count: usize,
+}
+
+fn get_program_stage_iv(gl: &gl::Gl, prog: super::Program, stage: gl::types::GLenum,
+                        query: gl::types::GLenum) -> gl::types::GLint {
+    let mut iv = 0;
+    unsafe { gl.GetProgramStageiv(prog, stage, query, &mut iv) };
+    iv
+}
+
+/// Query the subroutines and subroutine uniforms of a program stage.
+/// Requires `GL_ARB_shader_subroutine`.
+pub fn query_subroutines(gl: &gl::Gl, prog: super::Program, stage: s::Stage)
+                         -> SubroutineInterface {
+    let target = stage_to_gl(stage);
+    let num = get_program_stage_iv(gl, prog, target, gl::ACTIVE_SUBROUTINES);
+    let max_len = get_program_stage_iv(gl, prog, target, gl::ACTIVE_SUBROUTINE_MAX_LENGTH);
+    let mut name = String::with_capacity(max_len as usize);
+    name.extend(repeat('\0').take(max_len as usize));
+    let subroutines = (0..num as gl::types::GLuint).map(|i| {
+        let mut length = 0;
+        unsafe {
+            gl.GetActiveSubroutineName(prog, target, i, max_len, &mut length,
+                (&name[..]).as_ptr() as *mut gl::types::GLchar);
+        }
+        name[..length as usize].to_string()
+    }).collect();
+
+    let num = get_program_stage_iv(gl, prog, target, gl::ACTIVE_SUBROUTINE_UNIFORMS);
+    let max_len = get_program_stage_iv(gl, prog, target,
+                                       gl::ACTIVE_SUBROUTINE_UNIFORM_MAX_LENGTH);
+    let mut name = String::with_capacity(max_len as usize);
+    name.extend(repeat('\0').take(max_len as usize));
+    let uniforms = (0..num as gl::types::GLuint).map(|i| {
+        let mut length = 0;
+        unsafe {
+            gl.GetActiveSubroutineUniformName(prog, target, i, max_len, &mut length,
+                (&name[..]).as_ptr() as *mut gl::types::GLchar);
+        }
+        let uniform = name[..length as usize].to_string();
+        // the name is still null-terminated in the buffer GL wrote it to
+        let location = unsafe {
+            gl.GetSubroutineUniformLocation(prog, target,
+                (&name[..]).as_ptr() as *const gl::types::GLchar)
+        };
+        info!("\t\tSubroutine uniform '{}' at {}", uniform, location);
+        (uniform, location as u32)
+    }).collect();
+
+    SubroutineInterface {
+        subroutines: subroutines,
+        uniforms: uniforms,
+        location_count: get_program_stage_iv(gl, prog, target,
+            gl::ACTIVE_SUBROUTINE_UNIFORM_LOCATIONS) as usize,
+    }
+}
+
+/// A program pipeline, running each stage with the separable program
+/// assigned to it. The pipeline is deleted by the `cleanup` of the factory
+/// that created it once dropped: as a container object, it can't be deleted
+/// from another context.
+#[derive(Debug)]
+pub struct ProgramPipeline {
+    name: gl::types::GLuint,
+    dropped: Rc<RefCell<Vec<gl::types::GLuint>>>,
+}
+
+impl ProgramPipeline {
+    pub fn new(name: gl::types::GLuint, dropped: Rc<RefCell<Vec<gl::types::GLuint>>>)
+               -> ProgramPipeline {
+        ProgramPipeline {
+            name: name,
+            dropped: dropped,
+        }
+    }
+
+    /// Get the GL name of the pipeline.
+    pub fn get_name(&self) -> gl::types::GLuint {
+        self.name
+    }
+}
+
+impl Drop for ProgramPipeline {
+    fn drop(&mut self) {
+        self.dropped.borrow_mut().push(self.name);
+    }
+}
+
+/// Get the bits of `UseProgramStages` selecting the given stages.
+pub fn stages_to_gl(stages: &[Stage]) -> gl::types::GLbitfield {
+    stages.iter().fold(0, |bits, stage| bits | match *stage {
+        Stage::Vertex => gl::VERTEX_SHADER_BIT,
+        Stage::Geometry => gl::GEOMETRY_SHADER_BIT,
+        Stage::Fragment => gl::FRAGMENT_SHADER_BIT,
+    })
+}
+
+/// Create a program from a binary retrieved by `get_program_binary`. The
+/// driver may reject it, in which case the link fails.
+pub fn create_program_from_binary(gl: &gl::Gl, caps: &d::Capabilities,
+                                  format: gl::types::GLenum, data: &[u8])
+                                  -> (Result<(::Program, s::ProgramInfo), ()>, Option<String>) {
+    let name = unsafe { gl.CreateProgram() };
+    unsafe {
+        gl.ProgramBinary(name, format, data.as_ptr() as *const gl::types::GLvoid,
+                         data.len() as gl::types::GLsizei);
+    }
+    info!("\tLoaded program {} from a binary of {} bytes", name, data.len());
+    let (info, log) = query_link_result(gl, caps, name);
+    if info.is_err() {
+        unsafe { gl.DeleteProgram(name) };
+    }
+    (info.map(|info| (name, info)), log)
+}
+
+/// Get the binary of a linked program and its format.
+pub fn get_program_binary(gl: &gl::Gl, prog: super::Program)
+                          -> Option<(gl::types::GLenum, Vec<u8>)> {
+    let length = get_program_iv(gl, prog, gl::PROGRAM_BINARY_LENGTH);
+    if length <= 0 {
+        return None
+    }
+    let mut data: Vec<u8> = repeat(0).take(length as usize).collect();
+    let mut format = 0;
+    let mut written = 0;
+    unsafe {
+        gl.GetProgramBinary(prog, length, &mut written, &mut format,
+                            data.as_mut_ptr() as *mut gl::types::GLvoid);
+    }
+    data.truncate(written as usize);
+    Some((format, data))
+}
+
+/// Get the link status and log of a program, and its info if it linked.
+fn query_link_result(gl: &gl::Gl, caps: &d::Capabilities, name: super::Program)
+                     -> (Result<s::ProgramInfo, ()>, Option<String>) {
     // get info message
     let status = get_program_iv(gl, name, gl::LINK_STATUS);
     let mut length  = get_program_iv(gl, name, gl::INFO_LOG_LENGTH);
@@ -331,20 +684,255 @@ pub fn create_program<I: Iterator<Item = super::Shader>>(gl: &gl::Gl,
         None
     };
 
-    let prog = if status != 0 {
+    let info = if status != 0 {
         let (uniforms, textures) = query_parameters(gl, caps, name);
-        let info = s::ProgramInfo {
+        Ok(s::ProgramInfo {
             attributes: query_attributes(gl, name),
             uniforms: uniforms,
             blocks: query_blocks(gl, caps, name),
             textures: textures,
-        };
-        Ok((name, info))
+        })
     } else {
         Err(())
     };
 
-    (prog, log)
+    (info, log)
+}
+
+/// An error in creating a program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CreateProgramError {
+    /// Compiling the shader of a single-stage program failed, with the
+    /// compile log
+    CompileFailed(String),
+    /// Linking failed, with the link log. For a program binary, this means
+    /// the driver rejected it.
+    LinkFailed(String),
+    /// The kind of program, such as compute or separable, is not supported
+    /// by the driver
+    Unsupported,
+    /// Program binaries are not supported by the driver
+    BinaryUnsupported,
+    /// The program binary was retrieved from another driver
+    Stale(BinaryStale),
+    /// No shader was given
+    NoShaders,
+    /// The stages can't make a program together, such as a fragment shader
+    /// without a vertex one
+    IncompatibleStages,
+}
+
+/// Check that a program can be made of shaders of the given stages.
+pub fn check_stages(stages: &[Stage]) -> Result<(), CreateProgramError> {
+    if stages.is_empty() {
+        Err(CreateProgramError::NoShaders)
+    } else if !stages.contains(&Stage::Vertex) {
+        Err(CreateProgramError::IncompatibleStages)
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the tessellation stages of a program can work together: a
+/// control stage needs an evaluation one to consume its patches.
+pub fn check_tess_stages(stages: &[TessStage]) -> Result<(), CreateProgramError> {
+    if stages.contains(&TessStage::Control) && !stages.contains(&TessStage::Evaluation) {
+        Err(CreateProgramError::IncompatibleStages)
+    } else {
+        Ok(())
+    }
+}
+
+/// An error in relinking a program in place.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelinkError {
+    /// The output location is beyond the supported draw buffers
+    InvalidLocation(u32),
+    /// Linking failed, with the link log, or the output name is not valid
+    /// and the program was left as is
+    LinkFailed(String),
+    /// The relinked program moved attributes or uniforms, so the ones
+    /// recorded in its handle are stale
+    LayoutChanged,
+}
+
+/// Check that two program infos assign the same locations.
+fn same_locations(a: &s::ProgramInfo, b: &s::ProgramInfo) -> bool {
+    a.attributes.len() == b.attributes.len() &&
+    a.uniforms.len() == b.uniforms.len() &&
+    a.attributes.iter().zip(b.attributes.iter())
+        .all(|(x, y)| x.name == y.name && x.location == y.location) &&
+    a.uniforms.iter().zip(b.uniforms.iter())
+        .all(|(x, y)| x.name == y.name && x.location == y.location)
+}
+
+/// Query the binding point of each uniform block, by null-terminated block
+/// name.
+fn query_block_bindings(gl: &gl::Gl, caps: &d::Capabilities, prog: super::Program)
+                        -> Vec<(Vec<u8>, gl::types::GLint)> {
+    if !caps.uniform_block_supported {
+        return Vec::new()
+    }
+    let num = get_program_iv(gl, prog, gl::ACTIVE_UNIFORM_BLOCKS);
+    (0..num as gl::types::GLuint).map(|i| {
+        let mut size = 0;
+        let mut binding = 0;
+        unsafe {
+            gl.GetActiveUniformBlockiv(prog, i, gl::UNIFORM_BLOCK_NAME_LENGTH, &mut size);
+            gl.GetActiveUniformBlockiv(prog, i, gl::UNIFORM_BLOCK_BINDING, &mut binding);
+        }
+        let mut name: Vec<u8> = (0..size).map(|_| 0).collect();
+        let mut length = 0;
+        unsafe {
+            gl.GetActiveUniformBlockName(prog, i, size, &mut length,
+                name.as_mut_ptr() as *mut gl::types::GLchar);
+        }
+        name.truncate(length as usize);
+        name.push(0);
+        (name, binding)
+    }).collect()
+}
+
+fn link_with_frag_data(gl: &gl::Gl, prog: super::Program, output: &CString,
+                       location: gl::types::GLuint) {
+    unsafe {
+        gl.BindFragDataLocation(prog, location,
+            output.as_bytes_with_nul().as_ptr() as *const gl::types::GLchar);
+        gl.LinkProgram(prog);
+    }
+}
+
+/// Bind a fragment output to a new location and relink the program, making
+/// sure the rest of its interface didn't move. If the relink fails or moves
+/// the interface, the program is relinked again with the output at its old
+/// location.
+///
+/// Relinking resets the values of the uniforms outside of blocks to their
+/// initializers, they have to be uploaded again. The uniform block bindings
+/// are restored.
+pub fn remap_frag_data(gl: &gl::Gl, caps: &d::Capabilities, prog: super::Program,
+                       info: &s::ProgramInfo, output: &str, location: u32)
+                       -> Result<(), RelinkError> {
+    let c_name = match CString::new(output) {
+        Ok(c) => c,
+        Err(_) => return Err(RelinkError::LinkFailed(
+            format!("Output name {:?} contains a null byte", output))),
+    };
+    let old_location = unsafe {
+        gl.GetFragDataLocation(prog,
+            c_name.as_bytes_with_nul().as_ptr() as *const gl::types::GLchar)
+    };
+    let bindings = query_block_bindings(gl, caps, prog);
+    link_with_frag_data(gl, prog, &c_name, location);
+    info!("\tRelinked program {} with output {} at {}", prog, output, location);
+    let result = match query_link_result(gl, caps, prog) {
+        (Ok(ref new_info), _) if same_locations(info, new_info) => Ok(()),
+        (Ok(_), _) => Err(RelinkError::LayoutChanged),
+        (Err(()), log) => Err(RelinkError::LinkFailed(log.unwrap_or(String::new()))),
+    };
+    if result.is_err() && old_location >= 0 {
+        link_with_frag_data(gl, prog, &c_name, old_location as gl::types::GLuint);
+        info!("\tRelinked program {} with output {} back at {}", prog, output, old_location);
+    }
+    for &(ref name, binding) in bindings.iter() {
+        unsafe {
+            let index = gl.GetUniformBlockIndex(prog, name.as_ptr() as *const gl::types::GLchar);
+            if index != gl::INVALID_INDEX {
+                gl.UniformBlockBinding(prog, index, binding as gl::types::GLuint);
+            }
+        }
+    }
+    result
+}
+
+/// Query the name, location and type of each fragment shader output.
+/// Requires `GL_ARB_program_interface_query`.
+pub fn query_outputs(gl: &gl::Gl, prog: super::Program)
+                     -> Vec<(String, gl::types::GLint, BaseType)> {
+    let mut num = 0;
+    let mut max_len = 0;
+    unsafe {
+        gl.GetProgramInterfaceiv(prog, gl::PROGRAM_OUTPUT, gl::ACTIVE_RESOURCES, &mut num);
+        gl.GetProgramInterfaceiv(prog, gl::PROGRAM_OUTPUT, gl::MAX_NAME_LENGTH, &mut max_len);
+    }
+    let mut name = String::with_capacity(max_len as usize);
+    name.extend(repeat('\0').take(max_len as usize));
+    let props = [gl::TYPE, gl::LOCATION];
+    (0..num as gl::types::GLuint).map(|i| {
+        let mut length = 0;
+        let mut values = [0 as gl::types::GLint; 2];
+        unsafe {
+            gl.GetProgramResourceName(prog, gl::PROGRAM_OUTPUT, i, max_len, &mut length,
+                (&name[..]).as_ptr() as *mut gl::types::GLchar);
+            gl.GetProgramResourceiv(prog, gl::PROGRAM_OUTPUT, i,
+                props.len() as gl::types::GLsizei, props.as_ptr(),
+                values.len() as gl::types::GLsizei, &mut 0, values.as_mut_ptr());
+        }
+        let base = match StorageType::new(values[0] as gl::types::GLenum) {
+            Var(base, _) => base,
+            _ => {
+                error!("Unrecognized output storage: {}", values[0]);
+                BaseType::F32
+            },
+        };
+        (name[..length as usize].to_string(), values[1], base)
+    }).collect()
+}
+
+/// Query the name, array size and type of each varying captured by
+/// transform feedback, in capture order.
+pub fn query_transform_feedback_varyings(gl: &gl::Gl, prog: super::Program)
+                                         -> Vec<(String, u32, gl::types::GLenum)> {
+    let num = get_program_iv(gl, prog, gl::TRANSFORM_FEEDBACK_VARYINGS);
+    let max_len = get_program_iv(gl, prog, gl::TRANSFORM_FEEDBACK_VARYING_MAX_LENGTH);
+    let mut name = String::with_capacity(max_len as usize);
+    name.extend(repeat('\0').take(max_len as usize));
+    (0..num as gl::types::GLuint).map(|i| {
+        let mut length = 0;
+        let mut size = 0;
+        let mut var_type = 0;
+        unsafe {
+            gl.GetTransformFeedbackVarying(prog, i, max_len, &mut length, &mut size,
+                &mut var_type, (&name[..]).as_ptr() as *mut gl::types::GLchar);
+        }
+        (name[..length as usize].to_string(), size as u32, var_type)
+    }).collect()
+}
+
+/// A program interface that has locations.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProgramInterface {
+    Uniform,
+    ProgramInput,
+    ProgramOutput,
+}
+
+/// Resolve the location of a named resource, which may use the array
+/// element and structure member syntax (`lights[2].color`). Uses the program
+/// interface query when supported, and the older per-interface functions
+/// otherwise.
+pub fn get_resource_location(gl: &gl::Gl, prog: super::Program, interface: ProgramInterface,
+                             name: &str, interface_query: bool) -> Option<gl::types::GLint> {
+    // no resource can be named with a null byte
+    let c_name = match CString::new(name) {
+        Ok(c) => c,
+        Err(_) => return None,
+    };
+    let ptr = c_name.as_bytes_with_nul().as_ptr() as *const gl::types::GLchar;
+    let location = unsafe {
+        match (interface, interface_query) {
+            (ProgramInterface::Uniform, true) =>
+                gl.GetProgramResourceLocation(prog, gl::UNIFORM, ptr),
+            (ProgramInterface::ProgramInput, true) =>
+                gl.GetProgramResourceLocation(prog, gl::PROGRAM_INPUT, ptr),
+            (ProgramInterface::ProgramOutput, true) =>
+                gl.GetProgramResourceLocation(prog, gl::PROGRAM_OUTPUT, ptr),
+            (ProgramInterface::Uniform, false) => gl.GetUniformLocation(prog, ptr),
+            (ProgramInterface::ProgramInput, false) => gl.GetAttribLocation(prog, ptr),
+            (ProgramInterface::ProgramOutput, false) => gl.GetFragDataLocation(prog, ptr),
+        }
+    };
+    if location < 0 { None } else { Some(location) }
 }
 
 pub fn bind_uniform(gl: &gl::Gl, loc: gl::types::GLint, uniform: UniformValue) {
@@ -365,3 +953,74 @@ pub fn bind_uniform(gl: &gl::Gl, loc: gl::types::GLint, uniform: UniformValue) {
         UniformValue::F32Matrix4(val) => unsafe{ gl.UniformMatrix4fv(loc, 1, gl::FALSE, val[0].as_ptr()) },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use gfx::device::shade as s;
+    use gfx::device::shade::{BaseType, ContainerType, MatrixFormat};
+    use gl;
+    use super::{check_binary_metadata, check_stages, check_tess_stages, count_uniform_usage,
+                stages_to_gl, BinaryMetadata, BinaryStale, CreateProgramError, TessStage};
+
+    fn uniform(container: ContainerType, count: usize) -> s::UniformVar {
+        s::UniformVar {
+            name: String::new(),
+            location: 0,
+            count: count,
+            base_type: BaseType::F32,
+            container: container,
+        }
+    }
+
+    #[test]
+    fn test_count_uniform_usage() {
+        let info = s::ProgramInfo {
+            attributes: Vec::new(),
+            uniforms: vec![
+                uniform(ContainerType::Single, 1),
+                uniform(ContainerType::Vector(3), 4),
+                uniform(ContainerType::Matrix(MatrixFormat::ColumnMajor, 4, 4), 2),
+            ],
+            blocks: Vec::new(),
+            textures: Vec::new(),
+        };
+        assert_eq!(count_uniform_usage(&info), (7, 1 + 12 + 32));
+    }
+
+    #[test]
+    fn test_check_stages() {
+        assert_eq!(check_stages(&[]), Err(CreateProgramError::NoShaders));
+        assert_eq!(check_stages(&[s::Stage::Fragment]),
+                   Err(CreateProgramError::IncompatibleStages));
+        assert_eq!(check_stages(&[s::Stage::Vertex, s::Stage::Fragment]), Ok(()));
+    }
+
+    #[test]
+    fn test_stages_to_gl() {
+        assert_eq!(stages_to_gl(&[]), 0);
+        assert_eq!(stages_to_gl(&[s::Stage::Vertex]), gl::VERTEX_SHADER_BIT);
+        assert_eq!(stages_to_gl(&[s::Stage::Vertex, s::Stage::Fragment]),
+                   gl::VERTEX_SHADER_BIT | gl::FRAGMENT_SHADER_BIT);
+    }
+
+    #[test]
+    fn test_check_tess_stages() {
+        assert_eq!(check_tess_stages(&[]), Ok(()));
+        assert_eq!(check_tess_stages(&[TessStage::Evaluation]), Ok(()));
+        assert_eq!(check_tess_stages(&[TessStage::Control, TessStage::Evaluation]), Ok(()));
+        assert_eq!(check_tess_stages(&[TessStage::Control]),
+                   Err(CreateProgramError::IncompatibleStages));
+    }
+
+    #[test]
+    fn test_check_binary_metadata() {
+        let meta = |version: &str| BinaryMetadata {
+            vendor: "Vendor".to_string(),
+            renderer: "Renderer".to_string(),
+            version: version.to_string(),
+        };
+        assert_eq!(check_binary_metadata(&meta("4.5"), &meta("4.5")), Ok(()));
+        assert_eq!(check_binary_metadata(&meta("4.5"), &meta("4.4")),
+                   Err(BinaryStale { current: meta("4.5") }));
+    }
+}
@@ -78,6 +78,45 @@ pub fn bind_draw_color_buffers(gl: &gl::Gl, num: usize) {
     )};
 }
 
+/// An error in the mapping of fragment outputs to color attachments.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DrawBuffersError {
+    /// More outputs than the implementation can write to
+    TooManyOutputs(usize),
+    /// The attachment index is out of range
+    InvalidAttachment(u32),
+    /// The attachment is written by more than one output
+    DuplicateAttachment(u32),
+}
+
+/// Check that the given output mapping is legal for `bind_draw_buffers`.
+pub fn check_draw_buffers(targets: &[Option<u32>], max: usize)
+                          -> Result<(), DrawBuffersError> {
+    if targets.len() > max {
+        return Err(DrawBuffersError::TooManyOutputs(targets.len()))
+    }
+    for (i, target) in targets.iter().enumerate() {
+        match *target {
+            Some(index) if index as usize >= max =>
+                return Err(DrawBuffersError::InvalidAttachment(index)),
+            Some(index) if targets[..i].contains(&Some(index)) =>
+                return Err(DrawBuffersError::DuplicateAttachment(index)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Route each fragment output to the given color attachment, or discard it
+/// if there is `None`.
+pub fn bind_draw_buffers(gl: &gl::Gl, targets: &[Option<u32>]) {
+    let buffers: Vec<gl::types::GLenum> = targets.iter().map(|t| match *t {
+        Some(index) => gl::COLOR_ATTACHMENT0 + index,
+        None => gl::NONE,
+    }).collect();
+    unsafe { gl.DrawBuffers(buffers.len() as i32, buffers.as_ptr()) };
+}
+
 pub fn bind_viewport(gl: &gl::Gl, rect: Rect) {
     unsafe { gl.Viewport(
         rect.x as gl::types::GLint,
@@ -213,6 +252,17 @@ pub fn bind_blend(gl: &gl::Gl, blend: Option<s::Blend>) {
     }
 }
 
+/// Toggle blending for a single color attachment, or for all of them when
+/// `attachment` is `None`.
+pub fn bind_blend_enabled(gl: &gl::Gl, attachment: Option<u32>, enabled: bool) {
+    match (attachment, enabled) {
+        (Some(i), true)  => unsafe { gl.Enablei(gl::BLEND, i) },
+        (Some(i), false) => unsafe { gl.Disablei(gl::BLEND, i) },
+        (None, true)     => unsafe { gl.Enable(gl::BLEND) },
+        (None, false)    => unsafe { gl.Disable(gl::BLEND) },
+    }
+}
+
 pub fn bind_color_mask(gl: &gl::Gl, mask: s::ColorMask) {
     unsafe { gl.ColorMask(
         if (mask & s::RED  ).is_empty() {gl::FALSE} else {gl::TRUE},
@@ -221,3 +271,105 @@ pub fn bind_color_mask(gl: &gl::Gl, mask: s::ColorMask) {
         if (mask & s::ALPHA).is_empty() {gl::FALSE} else {gl::TRUE}
     )};
 }
+
+pub fn is_enabled(gl: &gl::Gl, cap: gl::types::GLenum, index: Option<u32>) -> bool {
+    let value = match index {
+        Some(i) => unsafe { gl.IsEnabledi(cap, i) },
+        None => unsafe { gl.IsEnabled(cap) },
+    };
+    value == gl::TRUE
+}
+
+pub fn query_cull_face(gl: &gl::Gl) -> CullFace {
+    if !is_enabled(gl, gl::CULL_FACE, None) {
+        return CullFace::Nothing
+    }
+    let mut mode = 0 as gl::types::GLint;
+    unsafe { gl.GetIntegerv(gl::CULL_FACE_MODE, &mut mode) };
+    match mode as gl::types::GLenum {
+        gl::FRONT => CullFace::Front,
+        gl::BACK => CullFace::Back,
+        other => {
+            warn!("Cull face mode {:#x} has no equivalent", other);
+            CullFace::Nothing
+        },
+    }
+}
+
+pub fn query_front_face(gl: &gl::Gl) -> FrontFace {
+    let mut face = 0 as gl::types::GLint;
+    unsafe { gl.GetIntegerv(gl::FRONT_FACE, &mut face) };
+    if face as gl::types::GLenum == gl::CW {
+        FrontFace::Clockwise
+    } else {
+        FrontFace::CounterClockwise
+    }
+}
+
+/// A pre-baked bundle of the fixed-function pipeline state, applied with
+/// `Factory::apply_pipeline_state`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PipelineState {
+    pub primitive: s::Primitive,
+    pub depth: Option<s::Depth>,
+    pub stencil: Option<s::Stencil>,
+    pub blend: Option<s::Blend>,
+    pub color_mask: s::ColorMask,
+}
+
+pub fn get_cull_face(p: &s::Primitive) -> CullFace {
+    match p.method {
+        RasterMethod::Fill(cull) => cull,
+        _ => CullFace::Nothing,
+    }
+}
+
+/// Apply `new`, issuing only the GL calls for the parts that differ from
+/// `old`, which is the state known to be current, if any.
+pub fn bind_pipeline_state(gl: &gl::Gl, old: Option<&PipelineState>, new: &PipelineState) {
+    let old = match old {
+        Some(old) => old,
+        None => {
+            bind_primitive(gl, new.primitive);
+            bind_stencil(gl, new.stencil, get_cull_face(&new.primitive));
+            bind_depth(gl, new.depth);
+            bind_blend(gl, new.blend);
+            bind_color_mask(gl, new.color_mask);
+            return
+        },
+    };
+    if old.primitive != new.primitive {
+        bind_primitive(gl, new.primitive);
+    }
+    // the stencil sides that get bound depend on the culled face
+    if old.stencil != new.stencil ||
+            get_cull_face(&old.primitive) != get_cull_face(&new.primitive) {
+        bind_stencil(gl, new.stencil, get_cull_face(&new.primitive));
+    }
+    if old.depth != new.depth {
+        bind_depth(gl, new.depth);
+    }
+    if old.blend != new.blend {
+        bind_blend(gl, new.blend);
+    }
+    if old.color_mask != new.color_mask {
+        bind_color_mask(gl, new.color_mask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_draw_buffers, DrawBuffersError};
+
+    #[test]
+    fn test_check_draw_buffers() {
+        assert_eq!(check_draw_buffers(&[Some(2), None, Some(0)], 4), Ok(()));
+        assert_eq!(check_draw_buffers(&[None, None], 4), Ok(()));
+        assert_eq!(check_draw_buffers(&[Some(0), Some(4)], 4),
+                   Err(DrawBuffersError::InvalidAttachment(4)));
+        assert_eq!(check_draw_buffers(&[Some(1), None, Some(1)], 4),
+                   Err(DrawBuffersError::DuplicateAttachment(1)));
+        assert_eq!(check_draw_buffers(&[None; 5], 4),
+                   Err(DrawBuffersError::TooManyOutputs(5)));
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright 2015 The Gfx-rs Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::ptr;
+use gfx::device as d;
+use gfx::device::handle;
+use fence::Fence;
+use factory::{BufferError, FenceError, RawMapping};
+use Factory;
+use Resources as R;
+
+
+/// What to do when an allocation would overwrite data the GPU may still be
+/// reading.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapPolicy {
+    /// Wait on the CPU until the GPU is done with the data
+    Block,
+    /// Reallocate a larger buffer, leaving the old storage to the GPU
+    Grow,
+    /// Fail the allocation
+    Fail,
+}
+
+/// An error creating or using a `StreamBuffer`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StreamError {
+    /// Fences are not supported, `ARB_sync` is required
+    Unsupported,
+    /// Persistent storage can't be reallocated, so it can't `Grow`
+    PersistentGrow,
+    /// An operation on the underlying buffer failed
+    Buffer(BufferError),
+    /// The fence guarding a frame could not be inserted
+    Fence(FenceError),
+}
+
+/// Counters of the hazards met by a `StreamBuffer`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StreamStats {
+    /// Number of successful allocations
+    pub allocations: usize,
+    /// Number of times the CPU waited for the GPU
+    pub stalls: usize,
+    /// Number of times the buffer was reallocated
+    pub grows: usize,
+    /// Number of failed allocations
+    pub failures: usize,
+}
+
+/// The ranges written during a frame, along with the fence signaled once
+/// the GPU is done with them.
+struct Region {
+    ranges: Vec<(usize, usize)>,
+    fence: Fence,
+}
+
+/// Number of 1ms waits on a fence before a blocking allocation gives up.
+const MAX_STALL_WAITS: usize = 1000;
+
+fn overlaps(a: (usize, usize), b: (usize, usize)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+/// Get the offset of the next allocation in a ring of `capacity` bytes,
+/// wrapping to the start if it doesn't fit at the end. An `alignment` of 0
+/// means no alignment.
+fn next_offset(head: usize, size: usize, alignment: usize, capacity: usize)
+               -> Option<usize> {
+    if size > capacity {
+        return None
+    }
+    let alignment = ::std::cmp::max(alignment, 1);
+    let offset = (head + alignment - 1) / alignment * alignment;
+    if offset + size <= capacity {
+        Some(offset)
+    } else {
+        Some(0)
+    }
+}
+
+/// Add a range to the ones written this frame, merging it with the last
+/// one when it starts right where the last one ends.
+fn push_range(ranges: &mut Vec<(usize, usize)>, range: (usize, usize)) {
+    if let Some(last) = ranges.last_mut() {
+        if range.0 == last.1 {
+            last.1 = range.1;
+            return
+        }
+    }
+    ranges.push(range);
+}
+
+/// Merge overlapping and adjacent ranges, so that each one of the result
+/// needs a single flush. The result is sorted.
+fn coalesce(ranges: &mut Vec<(usize, usize)>) {
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for &range in ranges.iter() {
+        if let Some(last) = merged.last_mut() {
+            if range.0 <= last.1 {
+                last.1 = ::std::cmp::max(last.1, range.1);
+                continue
+            }
+        }
+        merged.push(range);
+    }
+    *ranges = merged;
+}
+
+/// A ring buffer for data streamed to the GPU every frame. Every frame's
+/// allocations are guarded by a fence inserted by `end_frame`, so that the
+/// ring never overwrites data the GPU is still reading.
+pub struct StreamBuffer {
+    buffer: handle::RawBuffer<R>,
+    capacity: usize,
+    head: usize,
+    pending: Vec<(usize, usize)>,
+    in_flight: VecDeque<Region>,
+    policy: WrapPolicy,
+    stats: StreamStats,
+    /// Persistent mapping written to by `push`, if any
+    mapping: Option<RawMapping>,
+    /// Ranges written through the mapping and not flushed yet
+    dirty: Vec<(usize, usize)>,
+}
+
+impl StreamBuffer {
+    /// Create a stream buffer of the given size. Requires fences.
+    pub fn new(factory: &mut Factory, capacity: usize, policy: WrapPolicy)
+               -> Result<StreamBuffer, StreamError> {
+        if !factory.get_private_caps().sync_supported {
+            error!("Stream buffers need fences, ARB_sync is required");
+            return Err(StreamError::Unsupported)
+        }
+        let buffer = d::Factory::create_buffer_raw(factory, capacity, d::BufferUsage::Stream);
+        Ok(StreamBuffer {
+            buffer: buffer,
+            capacity: capacity,
+            head: 0,
+            pending: Vec::new(),
+            in_flight: VecDeque::new(),
+            policy: policy,
+            stats: StreamStats::default(),
+            mapping: None,
+            dirty: Vec::new(),
+        })
+    }
+
+    /// Create a stream buffer that stays mapped, with writes made visible
+    /// by explicit flushes. The ranges pushed during a frame are coalesced
+    /// into as few flushes as possible, issued by `flush_pending` or at the
+    /// end of the frame. Falls back to a regular stream buffer without
+    /// `ARB_buffer_storage`.
+    ///
+    /// Persistent storage can't be reallocated, so the `Grow` policy is not
+    /// allowed.
+    pub fn new_persistent(factory: &mut Factory, capacity: usize, policy: WrapPolicy)
+                          -> Result<StreamBuffer, StreamError> {
+        if policy == WrapPolicy::Grow {
+            return Err(StreamError::PersistentGrow)
+        }
+        if !factory.get_private_caps().buffer_storage_supported {
+            return StreamBuffer::new(factory, capacity, policy)
+        }
+        if !factory.get_private_caps().sync_supported {
+            error!("Stream buffers need fences, ARB_sync is required");
+            return Err(StreamError::Unsupported)
+        }
+        let (buffer, mapping) = factory.create_persistent_buffer_raw(
+            capacity, d::MapAccess::Writable, false);
+        Ok(StreamBuffer {
+            buffer: buffer,
+            capacity: capacity,
+            head: 0,
+            pending: Vec::new(),
+            in_flight: VecDeque::new(),
+            policy: policy,
+            stats: StreamStats::default(),
+            mapping: Some(mapping),
+            dirty: Vec::new(),
+        })
+    }
+
+    /// Get the underlying buffer.
+    pub fn get_buffer(&self) -> &handle::RawBuffer<R> {
+        &self.buffer
+    }
+
+    /// Get the hazard counters.
+    pub fn get_stats(&self) -> StreamStats {
+        self.stats
+    }
+
+    /// Allocate `size` bytes at the given alignment, returning the offset
+    /// into the buffer, or `None` if the policy forbids waiting or growing.
+    /// The `Block` policy waits for at most a second before failing.
+    pub fn allocate(&mut self, factory: &mut Factory, size: usize, alignment: usize)
+                    -> Option<usize> {
+        let mut offset = match next_offset(self.head, size, alignment, self.capacity) {
+            Some(o) => o,
+            None if self.policy == WrapPolicy::Grow => {
+                if !self.grow(factory, size + alignment) {
+                    return None
+                }
+                next_offset(self.head, size, alignment, self.capacity).unwrap()
+            },
+            None => {
+                self.stats.failures += 1;
+                return None
+            },
+        };
+        // the ring is too small for a single frame
+        if self.pending.iter().any(|&r| overlaps(r, (offset, offset + size))) {
+            if self.policy != WrapPolicy::Grow {
+                error!("Stream buffer of {} bytes is exhausted by a single frame", self.capacity);
+                self.stats.failures += 1;
+                return None
+            }
+            if !self.grow(factory, size + alignment) {
+                return None
+            }
+            offset = next_offset(self.head, size, alignment, self.capacity).unwrap();
+        }
+        let range = (offset, offset + size);
+        while let Some(index) = self.in_flight.iter().position(|region|
+                region.ranges.iter().any(|&r| overlaps(r, range))) {
+            // fences are supported, checked at creation
+            if factory.client_wait_fence(&self.in_flight[index].fence, 0) == Ok(true) {
+                self.retire(factory, index);
+                continue
+            }
+            match self.policy {
+                WrapPolicy::Block => {
+                    self.stats.stalls += 1;
+                    let mut waits = 0;
+                    while factory.client_wait_fence(&self.in_flight[index].fence,
+                                                    1000000) != Ok(true) {
+                        waits += 1;
+                        if waits == MAX_STALL_WAITS {
+                            error!("Stream buffer timed out waiting for the GPU");
+                            self.stats.failures += 1;
+                            return None
+                        }
+                    }
+                    self.retire(factory, index);
+                },
+                WrapPolicy::Grow => {
+                    if !self.grow(factory, size + alignment) {
+                        return None
+                    }
+                    return self.allocate(factory, size, alignment)
+                },
+                WrapPolicy::Fail => {
+                    self.stats.failures += 1;
+                    return None
+                },
+            }
+        }
+        push_range(&mut self.pending, range);
+        self.head = range.1;
+        self.stats.allocations += 1;
+        Some(offset)
+    }
+
+    /// Allocate room for some data and upload it, returning its offset.
+    pub fn push(&mut self, factory: &mut Factory, data: &[u8], alignment: usize)
+                -> Option<usize> {
+        self.allocate(factory, data.len(), alignment).map(|offset| {
+            match self.mapping {
+                Some(ref map) => {
+                    unsafe {
+                        let dst = (map.pointer as *mut u8).offset(offset as isize);
+                        ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+                    }
+                    self.dirty.push((offset, offset + data.len()));
+                },
+                None => d::Factory::update_buffer_raw(factory, &self.buffer, data, offset),
+            }
+            offset
+        })
+    }
+
+    /// Flush the ranges written through the persistent mapping since the
+    /// last flush, merging the adjacent ones. Called by `end_frame`, only
+    /// needed earlier if the data is used before the frame ends.
+    pub fn flush_pending(&mut self, factory: &mut Factory) -> Result<(), StreamError> {
+        if self.dirty.is_empty() {
+            return Ok(())
+        }
+        coalesce(&mut self.dirty);
+        for &(start, end) in self.dirty.iter() {
+            if let Err(e) = factory.flush_mapped_range(&self.buffer, start, end - start) {
+                return Err(StreamError::Buffer(e))
+            }
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Guard the data allocated since the last call with a fence. Has to be
+    /// called once the commands using that data were submitted. On failure
+    /// the data of the frame stays pending, guarded by the next fence.
+    pub fn end_frame(&mut self, factory: &mut Factory) -> Result<(), StreamError> {
+        try!(self.flush_pending(factory));
+        if self.pending.is_empty() {
+            return Ok(())
+        }
+        let fence = match factory.insert_fence() {
+            Ok(f) => f,
+            Err(e) => return Err(StreamError::Fence(e)),
+        };
+        let ranges = ::std::mem::replace(&mut self.pending, Vec::new());
+        self.in_flight.push_back(Region {
+            ranges: ranges,
+            fence: fence,
+        });
+        Ok(())
+    }
+
+    /// Delete the fences still guarding data.
+    pub fn release(mut self, factory: &mut Factory) {
+        for region in self.in_flight.drain() {
+            factory.delete_fence(region.fence);
+        }
+    }
+
+    fn retire(&mut self, factory: &mut Factory, index: usize) {
+        if let Some(region) = self.in_flight.remove(index) {
+            factory.delete_fence(region.fence);
+        }
+    }
+
+    /// Reallocate the storage, keeping the data of the current frame. The
+    /// driver keeps the old storage alive for the commands in flight, so
+    /// their regions stop being hazards. Returns `false`, counting a failed
+    /// allocation, if the storage can't be reallocated.
+    fn grow(&mut self, factory: &mut Factory, min_extra: usize) -> bool {
+        let capacity = ::std::cmp::max(self.capacity * 2, self.capacity + min_extra);
+        info!("\tGrowing stream buffer from {} to {} bytes", self.capacity, capacity);
+        if let Err(e) = factory.resize_buffer_raw(&self.buffer, capacity, true) {
+            error!("Unable to grow stream buffer to {} bytes: {:?}", capacity, e);
+            self.stats.failures += 1;
+            return false
+        }
+        for region in self.in_flight.drain() {
+            factory.delete_fence(region.fence);
+        }
+        self.head = self.capacity;
+        self.capacity = capacity;
+        self.stats.grows += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coalesce, next_offset, overlaps, push_range};
+
+    #[test]
+    fn test_next_offset() {
+        assert_eq!(next_offset(0, 16, 4, 64), Some(0));
+        assert_eq!(next_offset(10, 16, 4, 64), Some(12));
+        assert_eq!(next_offset(50, 16, 4, 64), Some(0));
+        assert_eq!(next_offset(48, 16, 16, 64), Some(48));
+        assert_eq!(next_offset(0, 65, 4, 64), None);
+        assert_eq!(next_offset(10, 16, 0, 64), Some(10));
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert!(overlaps((0, 16), (8, 24)));
+        assert!(!overlaps((0, 16), (16, 24)));
+        let mut ranges = Vec::new();
+        push_range(&mut ranges, (0, 16));
+        push_range(&mut ranges, (16, 24));
+        push_range(&mut ranges, (32, 40));
+        push_range(&mut ranges, (0, 8));
+        assert_eq!(ranges, vec![(0, 24), (32, 40), (0, 8)]);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut ranges = vec![(32, 48), (0, 16), (16, 20), (40, 64), (80, 96)];
+        coalesce(&mut ranges);
+        assert_eq!(ranges, vec![(0, 20), (32, 64), (80, 96)]);
+    }
+}
@@ -12,13 +12,69 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{gl, Surface, Texture, Sampler};
+use super::{gl, Error, Surface, Texture, Sampler};
 use super::gl::types::{GLenum, GLuint, GLint, GLfloat, GLsizei, GLvoid};
 use super::state;
 use gfx::device::tex::*;
 use gfx::device::attrib::{FloatSize, IntSubType};
+use gfx::device::shade::BaseType;
 
 
+/// The component of a packed depth-stencil texture read by samplers
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DepthStencilMode {
+    Depth,
+    Stencil,
+}
+
+/// The source of a channel sampled from a texture
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Swizzle {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    Zero,
+    One,
+}
+
+/// The swizzle sampling every channel from itself.
+pub const SWIZZLE_IDENTITY: [Swizzle; 4] = [Swizzle::Red, Swizzle::Green,
+                                            Swizzle::Blue, Swizzle::Alpha];
+
+/// A coherent set of pixel pack and unpack parameters.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelStorePreset {
+    /// Rows are tightly packed, as for single-byte font data
+    Tight,
+    /// Rows are padded to a multiple of 4 bytes, the GL default
+    Aligned4,
+    /// Explicit row alignments, and row lengths in pixels (0 meaning the
+    /// width of the transfer)
+    Custom {
+        pack_alignment: u8,
+        unpack_alignment: u8,
+        pack_row_length: u32,
+        unpack_row_length: u32,
+    },
+}
+
+/// Get the pixel store parameters of a preset.
+pub fn preset_to_pixel_store(preset: PixelStorePreset) -> [(GLenum, GLint); 4] {
+    let (pack_align, unpack_align, pack_row, unpack_row) = match preset {
+        PixelStorePreset::Tight => (1, 1, 0, 0),
+        PixelStorePreset::Aligned4 => (4, 4, 0, 0),
+        PixelStorePreset::Custom { pack_alignment, unpack_alignment,
+                                   pack_row_length, unpack_row_length } =>
+            (pack_alignment as GLint, unpack_alignment as GLint,
+             pack_row_length as GLint, unpack_row_length as GLint),
+    };
+    [(gl::PACK_ALIGNMENT, pack_align),
+     (gl::UNPACK_ALIGNMENT, unpack_align),
+     (gl::PACK_ROW_LENGTH, pack_row),
+     (gl::UNPACK_ROW_LENGTH, unpack_row)]
+}
+
 /// A token produced by the `bind_texture` that allows following up
 /// with a GL-compatibility sampler settings in `bind_sampler`
 #[derive(Copy, Clone)]
@@ -235,6 +291,75 @@ fn format_to_gltype(t: Format) -> Result<GLenum, ()> {
     }
 }
 
+/// The type a fragment shader has to output in order to write to an
+/// attachment of the given format.
+pub fn format_to_base_type(t: Format) -> BaseType {
+    match t {
+        Format::Integer(_, _, IntSubType::Raw)  => BaseType::I32,
+        Format::Unsigned(_, _, IntSubType::Raw) => BaseType::U32,
+        Format::RGB10_A2UI                      => BaseType::U32,
+        _                                       => BaseType::F32,
+    }
+}
+
+/// Check if a texture would have no texels: a zero width, height, level
+/// count, or depth for the kinds that have one.
+pub fn is_empty(info: &TextureInfo) -> bool {
+    let has_depth = match info.kind {
+        TextureKind::Texture2DArray | TextureKind::Texture3D |
+        TextureKind::Texture2DMultiSampleArray(_) => true,
+        _ => false,
+    };
+    info.width == 0 || info.height == 0 || info.levels == 0 ||
+    (has_depth && info.depth == 0)
+}
+
+/// Get the byte size of an image in a compressed format, which is stored
+/// in blocks of 4x4 texels. Returns `None` for uncompressed formats.
+pub fn compressed_size(format: Format, width: u16, height: u16, depth: u16)
+                       -> Option<usize> {
+    let block = match format {
+        Format::Compressed(Compression::ETC2_RGB) |
+        Format::Compressed(Compression::ETC2_SRGB) => 8,
+        Format::Compressed(Compression::ETC2_EAC_RGBA8) => 16,
+        _ => return None,
+    };
+    let blocks = |size: u16| (size as usize + 3) / 4;
+    Some(blocks(width) * blocks(height) * ::std::cmp::max(depth, 1) as usize * block)
+}
+
+/// Get the compressed formats the driver can create textures of.
+pub fn get_compressed_formats(gl: &gl::Gl) -> Vec<GLenum> {
+    let mut num = 0;
+    unsafe { gl.GetIntegerv(gl::NUM_COMPRESSED_TEXTURE_FORMATS, &mut num) };
+    let mut formats: Vec<GLint> = (0 .. num).map(|_| 0).collect();
+    if num > 0 {
+        unsafe { gl.GetIntegerv(gl::COMPRESSED_TEXTURE_FORMATS, formats.as_mut_ptr()) };
+    }
+    formats.into_iter().map(|f| f as GLenum).collect()
+}
+
+/// Check if a compressed format is one of the supported ones. Uncompressed
+/// formats are not concerned.
+pub fn is_compression_supported(format: Format, supported: &[GLenum]) -> bool {
+    if !format.is_compressed() {
+        return true
+    }
+    match format_to_gl(format) {
+        Ok(fmt) => supported.contains(&fmt),
+        Err(_) => false,
+    }
+}
+
+/// Check if the format has a depth component.
+pub fn is_depth_format(t: Format) -> bool {
+    match t {
+        Format::DEPTH16 | Format::DEPTH24 | Format::DEPTH32F |
+        Format::DEPTH24_STENCIL8 | Format::DEPTH32F_STENCIL8 => true,
+        _ => false,
+    }
+}
+
 fn format_to_size(t: Format) -> usize {
     match t {
         Format::Float(c, FloatSize::F16) => 2 * components_to_count(c),
@@ -264,11 +389,69 @@ fn format_to_size(t: Format) -> usize {
     }
 }
 
+/// Set the given pixel store parameters, returning the previous values so
+/// that they can be restored with another call.
+pub fn set_pixel_store(gl: &gl::Gl, params: &[(GLenum, GLint)]) -> Vec<(GLenum, GLint)> {
+    params.iter().map(|&(name, value)| {
+        let mut old = 0 as GLint;
+        unsafe {
+            gl.GetIntegerv(name, &mut old);
+            gl.PixelStorei(name, value);
+        }
+        (name, old)
+    }).collect()
+}
+
 fn set_mipmap_range(gl: &gl::Gl, target: GLenum, (base, max): (u8, u8)) { unsafe {
     gl.TexParameteri(target, gl::TEXTURE_BASE_LEVEL, base as GLint);
     gl.TexParameteri(target, gl::TEXTURE_MAX_LEVEL, max as GLint);
 }}
 
+/// Intersect the mip level range of a texture with the LOD range of a
+/// sampler, giving the range of levels that can actually be sampled.
+/// The result is empty (`min > max`) when the two don't overlap.
+pub fn effective_lod_range((base, max): (u8, u8), (lod_min, lod_max): (f32, f32))
+                           -> (f32, f32) {
+    let min = (base as f32 + lod_min.max(0.0)).max(base as f32);
+    let max = (base as f32 + lod_max).min(max as f32);
+    (min, max)
+}
+
+/// Query the sample counts supported for a format on the given target
+/// (`RENDERBUFFER` or one of the multisample texture targets), highest first.
+/// Without `ARB_internalformat_query` this guesses powers of two up to
+/// `MAX_SAMPLES`.
+pub fn get_sample_counts(gl: &gl::Gl, target: GLenum, format: Format,
+                         query_supported: bool, max_samples: usize) -> Vec<u8> {
+    let fmt = match format_to_gl(format) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    if query_supported {
+        let mut num = 0 as GLint;
+        unsafe { gl.GetInternalformativ(target, fmt, gl::NUM_SAMPLE_COUNTS, 1, &mut num) };
+        let mut counts: Vec<GLint> = (0 .. num).map(|_| 0).collect();
+        if num > 0 {
+            unsafe { gl.GetInternalformativ(target, fmt, gl::SAMPLES, num, counts.as_mut_ptr()) };
+        }
+        counts.iter().map(|&c| c as u8).collect()
+    } else {
+        let mut counts = Vec::new();
+        let mut c = 2;
+        while c <= max_samples {
+            counts.insert(0, c as u8);
+            c *= 2;
+        }
+        counts
+    }
+}
+
+/// Pick the highest supported sample count not exceeding the requested one.
+/// `supported` is expected in descending order.
+pub fn pick_sample_count(supported: &[u8], requested: u8) -> Option<u8> {
+    supported.iter().cloned().find(|&c| c <= requested)
+}
+
 /// Create a render surface.
 pub fn make_surface(gl: &gl::Gl, info: &SurfaceInfo) ->
                     Result<Surface, SurfaceError> {
@@ -286,6 +469,15 @@ pub fn make_surface(gl: &gl::Gl, info: &SurfaceInfo) ->
     unsafe {
         gl.BindRenderbuffer(target, name);
     }
+    // clear the errors left by earlier calls, so that the check after the
+    // allocation only sees its own
+    loop {
+        let err = Error::from_error_code(unsafe { gl.GetError() });
+        if err == Error::NoError {
+            break
+        }
+        error!("Pending error {:?} before allocating a surface", err);
+    }
     match info.aa_mode {
         None => { unsafe {
             gl.RenderbufferStorage(
@@ -307,6 +499,22 @@ pub fn make_surface(gl: &gl::Gl, info: &SurfaceInfo) ->
         Some(_) => return Err(SurfaceError::UnsupportedFormat),
     }
 
+    // don't hand out a renderbuffer without storage, it would only fail
+    // the framebuffer completeness check later on. `SurfaceError` has no
+    // variant for it, so it's reported as an unsupported format.
+    match Error::from_error_code(unsafe { gl.GetError() }) {
+        Error::NoError => (),
+        Error::OutOfMemory => {
+            error!("Out of memory while allocating a {}x{} surface of {:?}, \
+                    reporting it as an unsupported format",
+                   info.width, info.height, info.format);
+            unsafe { gl.DeleteRenderbuffers(1, &name) };
+            return Err(SurfaceError::UnsupportedFormat)
+        },
+        err => error!("Error {:?} while allocating a {}x{} surface of {:?}",
+                      err, info.width, info.height, info.format),
+    }
+
     Ok(name)
 }
 
@@ -319,6 +527,11 @@ pub fn make_without_storage(gl: &gl::Gl, info: &TextureInfo) ->
         Ok(f) => f as GLint,
         Err(_) => return Err(TextureError::UnsupportedFormat),
     };
+    if info.format.is_compressed() {
+        try!(make_compressed_without_storage(gl, target, info, fmt as GLenum));
+        set_mipmap_range(gl, target, (0, info.levels));
+        return Ok(name)
+    }
     let pix = format_to_glpixel(info.format);
     let typ = match format_to_gltype(info.format) {
         Ok(t) => t,
@@ -426,6 +639,34 @@ pub fn make_without_storage(gl: &gl::Gl, info: &TextureInfo) ->
     Ok(name)
 }
 
+/// Allocate the base level of a bound compressed texture. `TexImage*` may
+/// reject the specific compressed formats, unlike `CompressedTexImage*`.
+fn make_compressed_without_storage(gl: &gl::Gl, target: GLenum, info: &TextureInfo,
+                                   fmt: GLenum) -> Result<(), TextureError> {
+    let size = compressed_size(info.format, info.width, info.height, 1).unwrap() as GLsizei;
+    match info.kind {
+        TextureKind::Texture2D => unsafe {
+            gl.CompressedTexImage2D(target, 0, fmt, info.width as GLsizei,
+                                    info.height as GLsizei, 0, size, ::std::ptr::null());
+        },
+        TextureKind::TextureCube(_) =>
+            for &target in [gl::TEXTURE_CUBE_MAP_POSITIVE_X, gl::TEXTURE_CUBE_MAP_NEGATIVE_X,
+                    gl::TEXTURE_CUBE_MAP_POSITIVE_Y, gl::TEXTURE_CUBE_MAP_NEGATIVE_Y,
+                    gl::TEXTURE_CUBE_MAP_POSITIVE_Z, gl::TEXTURE_CUBE_MAP_NEGATIVE_Z].iter() {
+                unsafe { gl.CompressedTexImage2D(target, 0, fmt, info.width as GLsizei,
+                                                 info.height as GLsizei, 0, size,
+                                                 ::std::ptr::null()) };
+            },
+        TextureKind::Texture2DArray => unsafe {
+            gl.CompressedTexImage3D(target, 0, fmt, info.width as GLsizei,
+                                    info.height as GLsizei, info.depth as GLsizei, 0,
+                                    size * info.depth as GLsizei, ::std::ptr::null());
+        },
+        _ => return Err(TextureError::UnsupportedFormat),
+    }
+    Ok(())
+}
+
 /// Create a texture, assuming TexStorage is available.
 pub fn make_with_storage(gl: &gl::Gl, info: &TextureInfo) ->
                          Result<Texture, TextureError> {
@@ -550,14 +791,15 @@ pub fn bind_texture(gl: &gl::Gl, slot: GLenum, kind: TextureKind,
 
 /// Bind a sampler using a given binding anchor.
 /// Used for GL compatibility profile only. The core profile has sampler objects
-pub fn bind_sampler(gl: &gl::Gl, anchor: BindAnchor, info: &SamplerInfo) { unsafe {
+pub fn bind_sampler(gl: &gl::Gl, anchor: BindAnchor, info: &SamplerInfo,
+                    max_lod_bias: f32, max_anisotropy: f32) { unsafe {
     let BindAnchor(target) = anchor;
     let (min, mag) = filter_to_gl(info.filtering);
 
-    match info.filtering {
-        FilterMethod::Anisotropic(fac) =>
-            gl.TexParameterf(target, gl::TEXTURE_MAX_ANISOTROPY_EXT, fac as GLfloat),
-        _ => ()
+    if let FilterMethod::Anisotropic(fac) = info.filtering {
+        if let Some(fac) = clamp_anisotropy(fac, max_anisotropy) {
+            gl.TexParameterf(target, gl::TEXTURE_MAX_ANISOTROPY_EXT, fac);
+        }
     }
 
     gl.TexParameteri(target, gl::TEXTURE_MIN_FILTER, min as GLint);
@@ -568,47 +810,51 @@ pub fn bind_sampler(gl: &gl::Gl, anchor: BindAnchor, info: &SamplerInfo) { unsaf
     gl.TexParameteri(target, gl::TEXTURE_WRAP_T, wrap_to_gl(t) as GLint);
     gl.TexParameteri(target, gl::TEXTURE_WRAP_R, wrap_to_gl(r) as GLint);
 
-    gl.TexParameterf(target, gl::TEXTURE_LOD_BIAS, info.lod_bias);
+    gl.TexParameterf(target, gl::TEXTURE_LOD_BIAS, clamp_lod_bias(info.lod_bias, max_lod_bias));
 
     let (min, max) = info.lod_range;
     gl.TexParameterf(target, gl::TEXTURE_MIN_LOD, min);
     gl.TexParameterf(target, gl::TEXTURE_MAX_LOD, max);
 
-    match info.comparison {
-        ComparisonMode::NoComparison => gl.TexParameteri(target, gl::TEXTURE_COMPARE_MODE, gl::NONE as GLint),
-        ComparisonMode::CompareRefToTexture(cmp) => {
-            gl.TexParameteri(target, gl::TEXTURE_COMPARE_MODE, gl::COMPARE_REF_TO_TEXTURE as GLint);
-            gl.TexParameteri(target, gl::TEXTURE_COMPARE_FUNC, state::map_comparison(cmp) as GLint);
-        }
+    let (mode, func) = comparison_to_gl(info.comparison);
+    gl.TexParameteri(target, gl::TEXTURE_COMPARE_MODE, mode as GLint);
+    if let Some(func) = func {
+        gl.TexParameteri(target, gl::TEXTURE_COMPARE_FUNC, func as GLint);
     }
 }}
 
 pub fn update_texture(gl: &gl::Gl, kind: TextureKind, name: Texture,
                       img: &ImageInfo, address: *const u8, size: usize)
                       -> Result<(), TextureError> {
-    if !img.format.is_compressed() {
-        // TODO: can we compute the expected size for compressed formats?
-        let expected_size = img.width as usize * img.height as usize *
-                            img.depth as usize * format_to_size(img.format);
-        if size != expected_size {
-            return Err(TextureError::IncorrectSize(expected_size));
-        }
+    let expected_size = match compressed_size(img.format, img.width, img.height, img.depth) {
+        Some(s) => s,
+        None => img.width as usize * img.height as usize *
+                img.depth as usize * format_to_size(img.format),
+    };
+    if size != expected_size {
+        return Err(TextureError::IncorrectSize(expected_size));
     }
 
     let data = address as *const GLvoid;
+    let target = bind_kind_to_gl(kind);
+
+    if img.format.is_compressed() {
+        let fmt = match format_to_gl(img.format) {
+            Ok(f) => f,
+            Err(_) => return Err(TextureError::UnsupportedFormat),
+        };
+        unsafe { gl.BindTexture(target, name) };
+        return compressed_update(gl, kind, target, img, data, fmt, size as GLint);
+    }
+
     let pix = format_to_glpixel(img.format);
     let typ = match format_to_gltype(img.format) {
         Ok(t) => t,
         Err(_) => return Err(TextureError::UnsupportedFormat),
     };
-    let target = bind_kind_to_gl(kind);
 
     unsafe { gl.BindTexture(target, name) };
 
-    if img.format.is_compressed() {
-        return compressed_update(gl, kind, target, img, data, typ, size as GLint);
-    }
-
     unsafe {
         match kind {
             TextureKind::Texture1D => {
@@ -651,6 +897,13 @@ pub fn update_texture(gl: &gl::Gl, kind: TextureKind, name: Texture,
                 );
             },
             TextureKind::Texture2DArray | TextureKind::Texture3D => {
+                // the data is tightly packed, make sure GL doesn't expect
+                // any padding between rows and slices
+                let old = set_pixel_store(gl, &[
+                    (gl::UNPACK_ALIGNMENT, 1),
+                    (gl::UNPACK_ROW_LENGTH, img.width as GLint),
+                    (gl::UNPACK_IMAGE_HEIGHT, img.height as GLint),
+                ]);
                 gl.TexSubImage3D(
                     target,
                     img.mipmap as GLint,
@@ -664,6 +917,7 @@ pub fn update_texture(gl: &gl::Gl, kind: TextureKind, name: Texture,
                     typ,
                     data
                 );
+                set_pixel_store(gl, &old);
             },
             TextureKind::Texture2DMultiSample(_) | TextureKind::Texture2DMultiSampleArray(_) =>
                 return Err(TextureError::UnsupportedSampling),
@@ -674,7 +928,7 @@ pub fn update_texture(gl: &gl::Gl, kind: TextureKind, name: Texture,
 }
 
 pub fn compressed_update(gl: &gl::Gl, kind: TextureKind, target: GLenum, img: &ImageInfo,
-                         data: *const GLvoid, typ: GLenum, size: GLint)
+                         data: *const GLvoid, fmt: GLenum, size: GLint)
                          -> Result<(), TextureError> {
     unsafe {
         match kind {
@@ -684,7 +938,7 @@ pub fn compressed_update(gl: &gl::Gl, kind: TextureKind, target: GLenum, img: &I
                     img.mipmap as GLint,
                     img.xoffset as GLint,
                     img.width as GLint,
-                    typ,
+                    fmt,
                     size as GLint,
                     data
                 );
@@ -697,7 +951,7 @@ pub fn compressed_update(gl: &gl::Gl, kind: TextureKind, target: GLenum, img: &I
                     img.yoffset as GLint,
                     img.width as GLint,
                     img.height as GLint,
-                    typ,
+                    fmt,
                     size as GLint,
                     data
                 );
@@ -712,7 +966,7 @@ pub fn compressed_update(gl: &gl::Gl, kind: TextureKind, target: GLenum, img: &I
                     img.yoffset as GLint,
                     img.width as GLint,
                     img.height as GLint,
-                    typ,
+                    fmt,
                     size as GLint,
                     data
                 );
@@ -727,7 +981,7 @@ pub fn compressed_update(gl: &gl::Gl, kind: TextureKind, target: GLenum, img: &I
                     img.width as GLint,
                     img.height as GLint,
                     img.depth as GLint,
-                    typ,
+                    fmt,
                     size as GLint,
                     data
                 );
@@ -751,6 +1005,82 @@ fn make_texture(gl: &gl::Gl, info: &TextureInfo) -> (Texture, GLuint) {
     (name, k)
 }
 
+/// Create a 2D texture filled with a rectangle of the bound read frame
+/// buffer, starting at (`x`, `y`).
+pub fn make_from_framebuffer(gl: &gl::Gl, info: &TextureInfo, x: u16, y: u16)
+                             -> Result<Texture, TextureError> {
+    let fmt = match format_to_gl(info.format) {
+        Ok(f) => f,
+        Err(_) => return Err(TextureError::UnsupportedFormat),
+    };
+    let (name, target) = make_texture(gl, info);
+    unsafe {
+        gl.CopyTexImage2D(target, 0, fmt, x as GLint, y as GLint,
+                          info.width as GLsizei, info.height as GLsizei, 0);
+    }
+    set_mipmap_range(gl, target, (0, 0));
+    Ok(name)
+}
+
+/// Read back a level of a compressed texture, returning the number of
+/// bytes written. For cube maps, `kind` selects the face.
+pub fn read_compressed(gl: &gl::Gl, kind: TextureKind, name: Texture, level: u8,
+                       data: &mut [u8]) -> Result<usize, TextureError> {
+    let target = create_kind_to_gl(kind);
+    let mut size = 0 as GLint;
+    unsafe {
+        gl.BindTexture(bind_kind_to_gl(kind), name);
+        gl.GetTexLevelParameteriv(target, level as GLint,
+                                  gl::TEXTURE_COMPRESSED_IMAGE_SIZE, &mut size);
+    }
+    let size = size as usize;
+    if data.len() < size {
+        return Err(TextureError::IncorrectSize(size))
+    }
+    unsafe {
+        gl.GetCompressedTexImage(target, level as GLint, data.as_mut_ptr() as *mut GLvoid);
+    }
+    Ok(size)
+}
+
+/// Clamp a sampler LOD bias to the range supported by the implementation,
+/// which would otherwise do so silently.
+fn clamp_lod_bias(bias: f32, max: f32) -> f32 {
+    if bias > max || bias < -max {
+        warn!("LOD bias {} is out of the supported [-{}, {}] range, clamped",
+              bias, max, max);
+        bias.max(-max).min(max)
+    } else {
+        bias
+    }
+}
+
+/// Clamp an anisotropy factor to the maximum supported, which is zero
+/// without `EXT_texture_filter_anisotropic`. Returns `None` if anisotropic
+/// filtering is not supported. Silent, as it runs at every bind of the
+/// emulated samplers, see `warn_anisotropy`.
+fn clamp_anisotropy(factor: u8, max: f32) -> Option<GLfloat> {
+    if max < 1.0 {
+        None
+    } else if factor as f32 > max {
+        Some(max)
+    } else {
+        Some(factor as GLfloat)
+    }
+}
+
+/// Warn about the anisotropy of a sampler that will be clamped or ignored,
+/// once at creation.
+pub fn warn_anisotropy(filtering: FilterMethod, max: f32) {
+    if let FilterMethod::Anisotropic(factor) = filtering {
+        if max < 1.0 {
+            warn!("Anisotropic filtering is not supported, falling back to trilinear");
+        } else if factor as f32 > max {
+            warn!("{}x anisotropic filtering exceeds the maximum of {}x, clamped", factor, max);
+        }
+    }
+}
+
 fn wrap_to_gl(w: WrapMode) -> GLenum {
     match w {
         WrapMode::Tile   => gl::REPEAT,
@@ -759,6 +1089,26 @@ fn wrap_to_gl(w: WrapMode) -> GLenum {
     }
 }
 
+/// Get the wrap mode of an axis once the sampler has a border, which
+/// replaces clamping to the edge.
+fn border_wrap_to_gl(w: WrapMode) -> GLenum {
+    match w {
+        WrapMode::Clamp => gl::CLAMP_TO_BORDER,
+        w => wrap_to_gl(w),
+    }
+}
+
+/// Get the `TEXTURE_COMPARE_MODE` of a comparison mode, along with the
+/// `TEXTURE_COMPARE_FUNC` if it compares. Any depth texture can be the
+/// target of a comparison, shadow samplers need nothing else.
+fn comparison_to_gl(mode: ComparisonMode) -> (GLenum, Option<GLenum>) {
+    match mode {
+        ComparisonMode::NoComparison => (gl::NONE, None),
+        ComparisonMode::CompareRefToTexture(cmp) =>
+            (gl::COMPARE_REF_TO_TEXTURE, Some(state::map_comparison(cmp))),
+    }
+}
+
 fn filter_to_gl(f: FilterMethod) -> (GLenum, GLenum) {
     match f {
         FilterMethod::Scale => (gl::NEAREST, gl::NEAREST),
@@ -769,16 +1119,17 @@ fn filter_to_gl(f: FilterMethod) -> (GLenum, GLenum) {
     }
 }
 
-pub fn make_sampler(gl: &gl::Gl, info: &SamplerInfo) -> Sampler { unsafe {
+pub fn make_sampler(gl: &gl::Gl, info: &SamplerInfo, max_lod_bias: f32,
+                    max_anisotropy: f32) -> Sampler { unsafe {
     let mut name = 0 as Sampler;
     gl.GenSamplers(1, &mut name);
 
     let (min, mag) = filter_to_gl(info.filtering);
 
-    match info.filtering {
-        FilterMethod::Anisotropic(fac) =>
-            gl.SamplerParameterf(name, gl::TEXTURE_MAX_ANISOTROPY_EXT, fac as GLfloat),
-        _ => ()
+    if let FilterMethod::Anisotropic(fac) = info.filtering {
+        if let Some(fac) = clamp_anisotropy(fac, max_anisotropy) {
+            gl.SamplerParameterf(name, gl::TEXTURE_MAX_ANISOTROPY_EXT, fac);
+        }
     }
 
     gl.SamplerParameteri(name, gl::TEXTURE_MIN_FILTER, min as GLint);
@@ -789,23 +1140,233 @@ pub fn make_sampler(gl: &gl::Gl, info: &SamplerInfo) -> Sampler { unsafe {
     gl.SamplerParameteri(name, gl::TEXTURE_WRAP_T, wrap_to_gl(t) as GLint);
     gl.SamplerParameteri(name, gl::TEXTURE_WRAP_R, wrap_to_gl(r) as GLint);
 
-    gl.SamplerParameterf(name, gl::TEXTURE_LOD_BIAS, info.lod_bias);
+    gl.SamplerParameterf(name, gl::TEXTURE_LOD_BIAS, clamp_lod_bias(info.lod_bias, max_lod_bias));
 
     let (min, max) = info.lod_range;
     gl.SamplerParameterf(name, gl::TEXTURE_MIN_LOD, min);
     gl.SamplerParameterf(name, gl::TEXTURE_MAX_LOD, max);
 
-    match info.comparison {
-        ComparisonMode::NoComparison => gl.SamplerParameteri(name, gl::TEXTURE_COMPARE_MODE, gl::NONE as GLint),
-        ComparisonMode::CompareRefToTexture(cmp) => {
-            gl.SamplerParameteri(name, gl::TEXTURE_COMPARE_MODE, gl::COMPARE_REF_TO_TEXTURE as GLint);
-            gl.SamplerParameteri(name, gl::TEXTURE_COMPARE_FUNC, state::map_comparison(cmp) as GLint);
-        }
+    let (mode, func) = comparison_to_gl(info.comparison);
+    gl.SamplerParameteri(name, gl::TEXTURE_COMPARE_MODE, mode as GLint);
+    if let Some(func) = func {
+        gl.SamplerParameteri(name, gl::TEXTURE_COMPARE_FUNC, func as GLint);
     }
 
     name
 }}
 
+/// Get the range of array layers touched by an update, or `None` if the
+/// texture kind has no layers.
+pub fn get_layer_range(kind: TextureKind, img: &ImageInfo) -> Option<(u16, u16)> {
+    match kind {
+        TextureKind::Texture1DArray => Some((img.yoffset, img.yoffset + img.height)),
+        TextureKind::Texture2DArray => Some((img.zoffset, img.zoffset + img.depth)),
+        _ => None,
+    }
+}
+
+/// Get the number of array layers of a texture.
+pub fn get_layer_count(info: &TextureInfo) -> u16 {
+    match info.kind {
+        TextureKind::Texture1DArray => info.height,
+        TextureKind::Texture2DArray => info.depth,
+        _ => 1,
+    }
+}
+
+/// Check that the array layers touched by an update are within the layers
+/// of the texture. Kinds without layers always pass.
+pub fn is_layer_range_valid(kind: TextureKind, img: &ImageInfo, info: &TextureInfo) -> bool {
+    match get_layer_range(kind, img) {
+        Some((_, end)) => end <= get_layer_count(info),
+        None => true,
+    }
+}
+
+/// Get the dimensions of a mip level of a texture.
+pub fn level_size(info: &TextureInfo, level: u8) -> (u16, u16) {
+    use std::cmp::max;
+    (max(1, info.width >> level as usize), max(1, info.height >> level as usize))
+}
+
+/// Check if a texture was allocated with immutable storage.
+pub fn is_immutable(gl: &gl::Gl, kind: TextureKind, name: Texture) -> bool {
+    let target = bind_kind_to_gl(kind);
+    let mut immutable = 0;
+    unsafe {
+        gl.BindTexture(target, name);
+        gl.GetTexParameteriv(target, gl::TEXTURE_IMMUTABLE_FORMAT, &mut immutable);
+    }
+    immutable != 0
+}
+
+/// Create a view of a single mip level of a 2D texture with immutable
+/// storage, returning it along with its info.
+pub fn make_level_view(gl: &gl::Gl, info: &TextureInfo, name: Texture, level: u8)
+                       -> Result<(Texture, TextureInfo), TextureError> {
+    let fmt = match format_to_gl(info.format) {
+        Ok(f) => f,
+        Err(_) => return Err(TextureError::UnsupportedFormat),
+    };
+    let (width, height) = level_size(info, level);
+    let view_info = TextureInfo {
+        width: width,
+        height: height,
+        depth: 1,
+        levels: 1,
+        kind: info.kind,
+        format: info.format,
+    };
+    let mut view = 0;
+    unsafe {
+        // the name must not be bound before becoming a view
+        gl.GenTextures(1, &mut view);
+        gl.TextureView(view, gl::TEXTURE_2D, name, fmt, level as GLuint, 1, 0, 1);
+    }
+    Ok((view, view_info))
+}
+
+/// Attach a mip level of a texture to the currently bound draw frame buffer.
+pub fn attach_level(gl: &gl::Gl, attachment: GLenum, info: &TextureInfo,
+                    name: Texture, level: u8) {
+    match info.kind {
+        TextureKind::Texture2D | TextureKind::TextureCube(_) |
+        TextureKind::Texture2DMultiSample(_) => unsafe {
+            gl.FramebufferTexture2D(gl::DRAW_FRAMEBUFFER, attachment,
+                create_kind_to_gl(info.kind), name, level as GLint);
+        },
+        _ => unsafe {
+            gl.FramebufferTexture(gl::DRAW_FRAMEBUFFER, attachment,
+                name, level as GLint);
+        },
+    }
+}
+
+/// Choose which component of a depth-stencil texture gets sampled.
+pub fn set_depth_stencil_mode(gl: &gl::Gl, kind: TextureKind, name: Texture,
+                              mode: DepthStencilMode) {
+    let target = bind_kind_to_gl(kind);
+    let value = match mode {
+        DepthStencilMode::Depth   => gl::DEPTH_COMPONENT,
+        DepthStencilMode::Stencil => gl::STENCIL_INDEX,
+    };
+    unsafe {
+        gl.BindTexture(target, name);
+        gl.TexParameteri(target, gl::DEPTH_STENCIL_TEXTURE_MODE, value as GLint);
+    }
+}
+
+fn swizzle_to_gl(swizzle: Swizzle) -> GLint {
+    (match swizzle {
+        Swizzle::Red   => gl::RED,
+        Swizzle::Green => gl::GREEN,
+        Swizzle::Blue  => gl::BLUE,
+        Swizzle::Alpha => gl::ALPHA,
+        Swizzle::Zero  => gl::ZERO,
+        Swizzle::One   => gl::ONE,
+    }) as GLint
+}
+
+/// Choose where each channel sampled from a texture comes from.
+pub fn set_swizzle(gl: &gl::Gl, kind: TextureKind, name: Texture, swizzle: [Swizzle; 4]) {
+    let target = bind_kind_to_gl(kind);
+    let values = [swizzle_to_gl(swizzle[0]), swizzle_to_gl(swizzle[1]),
+                  swizzle_to_gl(swizzle[2]), swizzle_to_gl(swizzle[3])];
+    unsafe {
+        gl.BindTexture(target, name);
+        gl.TexParameteriv(target, gl::TEXTURE_SWIZZLE_RGBA, values.as_ptr());
+    }
+}
+
+// EXT_texture_sRGB_decode tokens
+const TEXTURE_SRGB_DECODE_EXT: GLenum = 0x8A48;
+const DECODE_EXT: GLenum = 0x8A49;
+const SKIP_DECODE_EXT: GLenum = 0x8A4A;
+
+fn srgb_decode_to_gl(decode: bool) -> GLint {
+    (if decode { DECODE_EXT } else { SKIP_DECODE_EXT }) as GLint
+}
+
+/// Choose whether an sRGB texture is linearized when sampled through the
+/// given sampler object.
+pub fn set_sampler_srgb_decode(gl: &gl::Gl, name: Sampler, decode: bool) {
+    unsafe {
+        gl.SamplerParameteri(name, TEXTURE_SRGB_DECODE_EXT, srgb_decode_to_gl(decode));
+    }
+}
+
+/// Make the clamped axes of a sampler object clamp to a border of the given
+/// color, instead of the texels at the edge.
+pub fn set_sampler_border(gl: &gl::Gl, name: Sampler, info: &SamplerInfo, color: [f32; 4]) {
+    let (s, t, r) = info.wrap_mode;
+    unsafe {
+        gl.SamplerParameteri(name, gl::TEXTURE_WRAP_S, border_wrap_to_gl(s) as GLint);
+        gl.SamplerParameteri(name, gl::TEXTURE_WRAP_T, border_wrap_to_gl(t) as GLint);
+        gl.SamplerParameteri(name, gl::TEXTURE_WRAP_R, border_wrap_to_gl(r) as GLint);
+        gl.SamplerParameterfv(name, gl::TEXTURE_BORDER_COLOR, color.as_ptr());
+    }
+}
+
+/// Choose whether an sRGB texture is linearized when sampled without a
+/// sampler object.
+pub fn set_texture_srgb_decode(gl: &gl::Gl, kind: TextureKind, name: Texture,
+                               decode: bool) {
+    let target = bind_kind_to_gl(kind);
+    unsafe {
+        gl.BindTexture(target, name);
+        gl.TexParameteri(target, TEXTURE_SRGB_DECODE_EXT, srgb_decode_to_gl(decode));
+    }
+}
+
+/// Check if the mip chain of a texture is complete with respect to the
+/// given filter: mipmapped filters need every level from the base to the
+/// max one, with consistent sizes.
+pub fn is_complete(gl: &gl::Gl, info: &TextureInfo, name: Texture,
+                   filter: FilterMethod) -> bool {
+    if info.kind.get_aa_mode().is_some() {
+        return true
+    }
+    let target = bind_kind_to_gl(info.kind);
+    let level_target = create_kind_to_gl(info.kind);
+    let (mut base, mut max) = (0, 0);
+    unsafe {
+        gl.BindTexture(target, name);
+        gl.GetTexParameteriv(target, gl::TEXTURE_BASE_LEVEL, &mut base);
+        gl.GetTexParameteriv(target, gl::TEXTURE_MAX_LEVEL, &mut max);
+    }
+    let max = match filter {
+        FilterMethod::Mipmap | FilterMethod::Trilinear |
+        FilterMethod::Anisotropic(_) => max,
+        FilterMethod::Scale | FilterMethod::Bilinear => base,
+    };
+    let (mut width, mut height) = (0, 0);
+    for level in base..(max + 1) {
+        let (mut w, mut h) = (0, 0);
+        unsafe {
+            gl.GetTexLevelParameteriv(level_target, level, gl::TEXTURE_WIDTH, &mut w);
+            gl.GetTexLevelParameteriv(level_target, level, gl::TEXTURE_HEIGHT, &mut h);
+        }
+        if level == base {
+            if w == 0 {
+                return false
+            }
+            width = w;
+            height = h;
+        } else {
+            if width == 1 && height == 1 {
+                // the chain ends here regardless of the max level
+                return true
+            }
+            width = ::std::cmp::max(1, width >> 1);
+            height = ::std::cmp::max(1, height >> 1);
+            if w != width || h != height {
+                return false
+            }
+        }
+    }
+    true
+}
+
 pub fn generate_mipmap(gl: &gl::Gl, kind: TextureKind, name: Texture) { unsafe {
     //can't fail here, but we need to check for integer formats too
     debug_assert!(kind.get_aa_mode().is_none());
@@ -813,3 +1374,336 @@ pub fn generate_mipmap(gl: &gl::Gl, kind: TextureKind, name: Texture) { unsafe {
     gl.BindTexture(target, name);
     gl.GenerateMipmap(target);
 }}
+
+/// Get the number of 8-bit channels of the formats that can be filtered by
+/// `downsample_box`.
+pub fn get_box_filter_channels(t: Format) -> Option<usize> {
+    match t {
+        Format::Unsigned(c, 8, IntSubType::Normalized) => Some(components_to_count(c)),
+        Format::SRGB8 => Some(3),
+        Format::SRGB8_A8 | Format::BGRA8 => Some(4),
+        _ => None,
+    }
+}
+
+/// Halve an image of 8-bit channels with a box filter. The last row or
+/// column of odd dimensions is repeated.
+pub fn downsample_box(src: &[u8], width: u16, height: u16, channels: usize)
+                      -> (Vec<u8>, u16, u16) {
+    use std::cmp::{max, min};
+    let (w, h) = (width as usize, height as usize);
+    let (dw, dh) = (max(1, w / 2), max(1, h / 2));
+    let mut dst = Vec::with_capacity(dw * dh * channels);
+    for y in 0 .. dh {
+        let (y0, y1) = (min(2 * y, h - 1), min(2 * y + 1, h - 1));
+        for x in 0 .. dw {
+            let (x0, x1) = (min(2 * x, w - 1), min(2 * x + 1, w - 1));
+            for c in 0 .. channels {
+                let texel = |x: usize, y: usize| src[(y * w + x) * channels + c] as u32;
+                let sum = texel(x0, y0) + texel(x1, y0) + texel(x0, y1) + texel(x1, y1);
+                dst.push(((sum + 2) / 4) as u8);
+            }
+        }
+    }
+    (dst, dw as u16, dh as u16)
+}
+
+/// Read a level of a 2D texture with tightly packed rows.
+pub fn read_level(gl: &gl::Gl, kind: TextureKind, name: Texture, level: u8,
+                  format: Format, data: &mut [u8]) -> Result<(), TextureError> {
+    let typ = match format_to_gltype(format) {
+        Ok(t) => t,
+        Err(_) => return Err(TextureError::UnsupportedFormat),
+    };
+    let old = set_pixel_store(gl, &[(gl::PACK_ALIGNMENT, 1), (gl::PACK_ROW_LENGTH, 0)]);
+    unsafe {
+        gl.BindTexture(bind_kind_to_gl(kind), name);
+        gl.GetTexImage(create_kind_to_gl(kind), level as GLint, format_to_glpixel(format),
+                       typ, data.as_mut_ptr() as *mut GLvoid);
+    }
+    set_pixel_store(gl, &old);
+    Ok(())
+}
+
+/// Reverse the order of the rows of an image, converting between the
+/// bottom-up rows of GL and top-down ones.
+fn flip_rows(data: &mut [u8], row_size: usize) {
+    let rows = data.len() / row_size;
+    for i in 0 .. rows / 2 {
+        for k in 0 .. row_size {
+            data.swap(i * row_size + k, (rows - 1 - i) * row_size + k);
+        }
+    }
+}
+
+/// Get the distance in bytes between the starts of two rows of pixels
+/// packed with the given alignment and row length, 0 meaning the width.
+pub fn row_stride(width: u16, texel_size: usize, alignment: usize, row_length: usize)
+                  -> usize {
+    let texels = if row_length == 0 { width as usize } else { row_length };
+    (texels * texel_size + alignment - 1) / alignment * alignment
+}
+
+/// Get the number of bytes written by reading an image packed with the
+/// given alignment and row length: the last row isn't padded.
+pub fn packed_size(width: u16, height: u16, texel_size: usize, alignment: usize,
+                   row_length: usize) -> usize {
+    if width == 0 || height == 0 {
+        return 0
+    }
+    row_stride(width, texel_size, alignment, row_length) * (height as usize - 1) +
+    width as usize * texel_size
+}
+
+/// Read a rectangle of the bound read frame buffer into `data`, with rows
+/// packed as given, ordered from the bottom up. An alignment other than 1,
+/// 2, 4 or 8 is reported as an unsupported format.
+pub fn read_pixels_packed(gl: &gl::Gl, x: u16, y: u16, width: u16, height: u16,
+                          format: Format, alignment: u8, row_length: u16, data: &mut [u8])
+                          -> Result<(), TextureError> {
+    match alignment {
+        1 | 2 | 4 | 8 => (),
+        _ => {
+            error!("Invalid pack alignment {}, must be 1, 2, 4 or 8", alignment);
+            return Err(TextureError::UnsupportedFormat)
+        },
+    }
+    let typ = match format_to_gltype(format) {
+        Ok(t) => t,
+        Err(_) => return Err(TextureError::UnsupportedFormat),
+    };
+    let size = packed_size(width, height, format_to_size(format), alignment as usize,
+                           row_length as usize);
+    if data.len() < size {
+        return Err(TextureError::IncorrectSize(size))
+    }
+    let old = set_pixel_store(gl, &[(gl::PACK_ALIGNMENT, alignment as GLint),
+                                    (gl::PACK_ROW_LENGTH, row_length as GLint)]);
+    unsafe {
+        gl.ReadPixels(x as GLint, y as GLint, width as GLsizei, height as GLsizei,
+                      format_to_glpixel(format), typ, data.as_mut_ptr() as *mut GLvoid);
+    }
+    set_pixel_store(gl, &old);
+    Ok(())
+}
+
+/// Read a rectangle of the bound read frame buffer, with tightly packed
+/// rows ordered from the top of the rectangle down.
+pub fn read_pixels(gl: &gl::Gl, x: u16, y: u16, width: u16, height: u16, format: Format)
+                   -> Result<Vec<u8>, TextureError> {
+    let row_size = width as usize * format_to_size(format);
+    let mut data: Vec<u8> = (0 .. row_size * height as usize).map(|_| 0).collect();
+    try!(read_pixels_packed(gl, x, y, width, height, format, 1, 0, &mut data));
+    if row_size > 0 {
+        flip_rows(&mut data, row_size);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{border_wrap_to_gl, clamp_anisotropy, clamp_lod_bias, comparison_to_gl,
+                compressed_size, downsample_box, effective_lod_range, flip_rows,
+                format_to_base_type, get_layer_range, is_depth_format, is_empty,
+                is_layer_range_valid, packed_size, pick_sample_count, preset_to_pixel_store,
+                row_stride, swizzle_to_gl, PixelStorePreset, Swizzle};
+    use gl;
+    use gfx::device::attrib::{FloatSize, IntSubType};
+    use gfx::device::shade::BaseType;
+    use gfx::device::state::Comparison;
+    use gfx::device::tex::{ComparisonMode, Components, Compression, Format, ImageInfo,
+                           TextureInfo, TextureKind, WrapMode};
+
+    #[test]
+    fn test_effective_lod_range() {
+        assert_eq!(effective_lod_range((0, 9), (0.0, 1000.0)), (0.0, 9.0));
+        assert_eq!(effective_lod_range((2, 5), (0.0, 1.5)), (2.0, 3.5));
+        assert_eq!(effective_lod_range((0, 4), (-2.0, 2.0)), (0.0, 2.0));
+        let (min, max) = effective_lod_range((4, 6), (3.0, 1000.0));
+        assert!(min > max);
+    }
+
+    #[test]
+    fn test_pick_sample_count() {
+        let counts = [8, 4, 2];
+        assert_eq!(pick_sample_count(&counts, 8), Some(8));
+        assert_eq!(pick_sample_count(&counts, 16), Some(8));
+        assert_eq!(pick_sample_count(&counts, 6), Some(4));
+        assert_eq!(pick_sample_count(&counts, 1), None);
+        assert_eq!(pick_sample_count(&[], 4), None);
+    }
+
+    #[test]
+    fn test_is_depth_format() {
+        assert!(is_depth_format(Format::DEPTH24));
+        assert!(is_depth_format(Format::DEPTH32F_STENCIL8));
+        assert!(!is_depth_format(Format::Float(Components::R, FloatSize::F32)));
+        assert!(!is_depth_format(Format::SRGB8_A8));
+    }
+
+    #[test]
+    fn test_get_layer_range() {
+        let img = ImageInfo {
+            xoffset: 0,
+            yoffset: 2,
+            zoffset: 3,
+            width: 4,
+            height: 4,
+            depth: 2,
+            format: Format::SRGB8_A8,
+            mipmap: 0,
+        };
+        assert_eq!(get_layer_range(TextureKind::Texture2DArray, &img), Some((3, 5)));
+        assert_eq!(get_layer_range(TextureKind::Texture1DArray, &img), Some((2, 6)));
+        assert_eq!(get_layer_range(TextureKind::Texture3D, &img), None);
+    }
+
+    #[test]
+    fn test_is_layer_range_valid() {
+        let info = TextureInfo {
+            width: 64,
+            height: 64,
+            depth: 4,
+            levels: 1,
+            kind: TextureKind::Texture2DArray,
+            format: Format::SRGB8_A8,
+        };
+        let layer = |zoffset, depth| ImageInfo {
+            xoffset: 0,
+            yoffset: 0,
+            zoffset: zoffset,
+            width: 64,
+            height: 64,
+            depth: depth,
+            format: Format::SRGB8_A8,
+            mipmap: 0,
+        };
+        assert_eq!(get_layer_range(info.kind, &layer(2, 1)), Some((2, 3)));
+        assert!(is_layer_range_valid(info.kind, &layer(2, 1), &info));
+        assert!(is_layer_range_valid(info.kind, &layer(0, 4), &info));
+        assert!(!is_layer_range_valid(info.kind, &layer(4, 1), &info));
+        assert!(!is_layer_range_valid(info.kind, &layer(3, 2), &info));
+        assert!(is_layer_range_valid(TextureKind::Texture2D, &layer(4, 1), &info));
+    }
+
+    #[test]
+    fn test_comparison_to_gl() {
+        assert_eq!(comparison_to_gl(ComparisonMode::NoComparison), (gl::NONE, None));
+        assert_eq!(comparison_to_gl(ComparisonMode::CompareRefToTexture(Comparison::Less)),
+                   (gl::COMPARE_REF_TO_TEXTURE, Some(gl::LESS)));
+        assert_eq!(comparison_to_gl(ComparisonMode::CompareRefToTexture(Comparison::GreaterEqual)),
+                   (gl::COMPARE_REF_TO_TEXTURE, Some(gl::GEQUAL)));
+    }
+
+    #[test]
+    fn test_border_wrap_to_gl() {
+        assert_eq!(border_wrap_to_gl(WrapMode::Clamp), gl::CLAMP_TO_BORDER);
+        assert_eq!(border_wrap_to_gl(WrapMode::Tile), gl::REPEAT);
+        assert_eq!(border_wrap_to_gl(WrapMode::Mirror), gl::MIRRORED_REPEAT);
+    }
+
+    #[test]
+    fn test_swizzle_to_gl() {
+        assert_eq!(swizzle_to_gl(Swizzle::Red), gl::RED as i32);
+        assert_eq!(swizzle_to_gl(Swizzle::Alpha), gl::ALPHA as i32);
+        assert_eq!(swizzle_to_gl(Swizzle::Zero), gl::ZERO as i32);
+        assert_eq!(swizzle_to_gl(Swizzle::One), gl::ONE as i32);
+    }
+
+    #[test]
+    fn test_clamp_anisotropy() {
+        assert_eq!(clamp_anisotropy(4, 16.0), Some(4.0));
+        assert_eq!(clamp_anisotropy(16, 8.0), Some(8.0));
+        assert_eq!(clamp_anisotropy(16, 0.0), None);
+    }
+
+    #[test]
+    fn test_clamp_lod_bias() {
+        assert_eq!(clamp_lod_bias(1.5, 16.0), 1.5);
+        assert_eq!(clamp_lod_bias(20.0, 16.0), 16.0);
+        assert_eq!(clamp_lod_bias(-20.0, 16.0), -16.0);
+    }
+
+    #[test]
+    fn test_pixel_store_preset() {
+        assert_eq!(preset_to_pixel_store(PixelStorePreset::Tight)[1], (gl::UNPACK_ALIGNMENT, 1));
+        assert_eq!(preset_to_pixel_store(PixelStorePreset::Aligned4)[0], (gl::PACK_ALIGNMENT, 4));
+        let custom = PixelStorePreset::Custom {
+            pack_alignment: 8,
+            unpack_alignment: 2,
+            pack_row_length: 0,
+            unpack_row_length: 640,
+        };
+        assert_eq!(preset_to_pixel_store(custom),
+                   [(gl::PACK_ALIGNMENT, 8), (gl::UNPACK_ALIGNMENT, 2),
+                    (gl::PACK_ROW_LENGTH, 0), (gl::UNPACK_ROW_LENGTH, 640)]);
+    }
+
+    #[test]
+    fn test_downsample_box() {
+        let src = [0, 10, 20, 30,
+                   40, 50, 60, 70];
+        assert_eq!(downsample_box(&src, 4, 2, 1), (vec![25, 45], 2, 1));
+        let src = [0, 100, 8, 4, 200, 16, 12, 255, 32];
+        assert_eq!(downsample_box(&src, 3, 1, 3), (vec![2, 150, 12], 1, 1));
+        assert_eq!(downsample_box(&[7, 9], 1, 1, 2), (vec![7, 9], 1, 1));
+    }
+
+    #[test]
+    fn test_format_to_base_type() {
+        assert_eq!(format_to_base_type(Format::Float(Components::RGBA, FloatSize::F16)), BaseType::F32);
+        assert_eq!(format_to_base_type(Format::Unsigned(Components::RGBA, 8, IntSubType::Normalized)), BaseType::F32);
+        assert_eq!(format_to_base_type(Format::Unsigned(Components::R, 32, IntSubType::Raw)), BaseType::U32);
+        assert_eq!(format_to_base_type(Format::Integer(Components::RG, 16, IntSubType::Raw)), BaseType::I32);
+        assert_eq!(format_to_base_type(Format::RGB10_A2UI), BaseType::U32);
+    }
+
+    #[test]
+    fn test_compressed_size() {
+        let rgb = Format::Compressed(Compression::ETC2_RGB);
+        let rgba = Format::Compressed(Compression::ETC2_EAC_RGBA8);
+        assert_eq!(compressed_size(rgb, 64, 64, 1), Some(16 * 16 * 8));
+        assert_eq!(compressed_size(rgb, 5, 5, 0), Some(2 * 2 * 8));
+        assert_eq!(compressed_size(rgba, 4, 4, 3), Some(3 * 16));
+        assert_eq!(compressed_size(Format::RGBA8, 4, 4, 1), None);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut info = TextureInfo {
+            width: 32,
+            height: 32,
+            depth: 32,
+            levels: 1,
+            kind: TextureKind::Texture3D,
+            format: Format::RGBA8,
+        };
+        assert!(!is_empty(&info));
+        info.depth = 0;
+        assert!(is_empty(&info));
+        info.kind = TextureKind::Texture2D;
+        assert!(!is_empty(&info));
+        info.levels = 0;
+        assert!(is_empty(&info));
+    }
+
+    #[test]
+    fn test_flip_rows() {
+        let mut data = [1, 1, 2, 2, 3, 3];
+        flip_rows(&mut data, 2);
+        assert_eq!(data, [3, 3, 2, 2, 1, 1]);
+        let mut data = [1, 2, 3, 4];
+        flip_rows(&mut data, 1);
+        assert_eq!(data, [4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn test_packed_size() {
+        assert_eq!(row_stride(3, 3, 4, 0), 12);
+        assert_eq!(row_stride(3, 3, 1, 0), 9);
+        assert_eq!(row_stride(3, 3, 4, 5), 16);
+        assert_eq!(packed_size(3, 2, 3, 4, 0), 12 + 9);
+        assert_eq!(packed_size(3, 2, 3, 1, 0), 18);
+        assert_eq!(packed_size(0, 2, 3, 4, 0), 0);
+    }
+}